@@ -0,0 +1,110 @@
+//! `#[derive(Trace)]` for cgc
+//! every field is traced, unless it's annotated with `#[trace(ignore)]`.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, GenericParam};
+
+#[proc_macro_derive(Trace, attributes(trace))]
+pub fn derive_trace(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    match expand(&mut input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &mut DeriveInput) -> syn::Result<TokenStream2> {
+    for param in input.generics.params.iter_mut() {
+        if let GenericParam::Type(ty) = param {
+            ty.bounds.push(parse_quote!(::cgc::trace::Trace));
+        }
+    }
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, visits) = destruct(&data.fields)?;
+            quote! {
+                let Self #pattern = self;
+                #(#visits)*
+            }
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            for variant in data.variants.iter() {
+                let name = &variant.ident;
+                let (pattern, visits) = destruct(&variant.fields)?;
+                arms.push(quote! {
+                    Self::#name #pattern => { #(#visits)* }
+                });
+            }
+            if arms.is_empty() {
+                // `self` is a reference, matching it is not exhaustive for an empty enum
+                quote!(match *self {})
+            } else {
+                quote! {
+                    match self {
+                        #(#arms)*
+                    }
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new_spanned(
+                data.union_token,
+                "Trace could not be derived for unions",
+            ))
+        }
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::cgc::trace::Trace for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn trace(&self, tracer: &mut dyn ::cgc::trace::Tracer) {
+                #body
+            }
+        }
+    })
+}
+
+/// returns the pattern binding every field, and the statements tracing the bound fields
+fn destruct(fields: &Fields) -> syn::Result<(TokenStream2, Vec<TokenStream2>)> {
+    let mut bindings = Vec::new();
+    let mut visits = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let binding = format_ident!("__field_{}", i);
+        if !is_ignored(field)? {
+            visits.push(quote! {
+                ::cgc::trace::Trace::trace(#binding, tracer);
+            });
+        }
+        bindings.push(match &field.ident {
+            Some(ident) => quote!(#ident: #binding),
+            None => quote!(#binding),
+        });
+    }
+    let pattern = match fields {
+        Fields::Named(_) => quote!({ #(#bindings),* }),
+        Fields::Unnamed(_) => quote!(( #(#bindings),* )),
+        Fields::Unit => quote!(),
+    };
+    Ok((pattern, visits))
+}
+
+fn is_ignored(field: &syn::Field) -> syn::Result<bool> {
+    let mut ignored = false;
+    for attr in field.attrs.iter() {
+        if !attr.path().is_ident("trace") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("ignore") {
+                ignored = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `#[trace(ignore)]`"))
+            }
+        })?;
+    }
+    Ok(ignored)
+}
@@ -0,0 +1,67 @@
+//! allocation throughput of 1 and 8 mutator threads,
+//! for small objects bumped from the thread local chunks,
+//! and for objects too large for them, which take the slow path of every allocation.
+//! run it with `cargo run --release --example alloc_throughput`
+use std::time::Instant;
+
+use cgc::frame::GCFrame;
+use cgc::state::{GCConfig, State};
+use cgc::trace::{Trace, Tracer};
+
+struct Object<const N: usize>([u64; N]);
+
+impl<const N: usize> Trace for Object<N> {
+    fn trace(&self, _: &mut dyn Tracer) {}
+}
+
+const PER_THREAD: usize = 100_000;
+const BATCH: usize = 5000;
+
+/// allocated objects per second
+fn throughput<const N: usize>(threads: usize, per_thread: usize) -> f64 {
+    // never collected while allocating, so only the allocation is measured
+    let config = GCConfig::builder()
+        .minor_gc_trigger_size(1 << 34)
+        .minor_heap_size_limit(1 << 35)
+        .build()
+        .unwrap();
+    let state: &'static State = Box::leak(Box::new(State::new(config)));
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            std::thread::spawn(move || {
+                let _mutator = state.register_mutator();
+                let frame: &'static GCFrame = Box::leak(Box::new(GCFrame::new(state)));
+                for _ in 0..per_thread / BATCH {
+                    let boxes: Vec<_> = (0..BATCH).map(|_| frame.alloc(Object([0; N]))).collect();
+                    drop(boxes);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+    state.collect();
+    (threads * per_thread) as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    println!(
+        "on {} cpus",
+        std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+    );
+    for threads in [1, 8] {
+        println!(
+            "{} threads, 16 bytes:    {:>12.0} objects/s",
+            threads,
+            throughput::<2>(threads, PER_THREAD)
+        );
+        println!(
+            "{} threads, 16 kbytes:   {:>12.0} objects/s",
+            threads,
+            throughput::<2048>(threads, PER_THREAD / 10)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! time a major gc of a wide object graph, marked by 1 and by 4 gc threads.
+//! run it with `cargo run --release --example parallel_mark`
+use std::time::{Duration, Instant};
+
+use cgc::frame::GCFrame;
+use cgc::gc_box::GCRef;
+use cgc::state::{GCConfig, State};
+use cgc::trace::{Trace, Tracer};
+
+struct Node(Vec<GCRef<Node>>);
+
+impl Trace for Node {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        self.0.trace(tracer);
+    }
+}
+
+const MIDS: usize = 1000;
+const LEAVES: usize = 200;
+const ROUNDS: usize = 5;
+
+/// the fastest of `ROUNDS` major gcs, every object of the graph survives them
+fn mark(threads: usize) -> Duration {
+    let config = GCConfig::builder()
+        .thread_pool_size(threads)
+        .minor_gc_trigger_size(1 << 34)
+        .minor_heap_size_limit(1 << 35)
+        .build()
+        .unwrap();
+    let state: &'static State = Box::leak(Box::new(State::new(config)));
+    let frame: &'static GCFrame = Box::leak(Box::new(GCFrame::new(state)));
+    // root -> mids -> leaves, only the root is rooted
+    let mids: Vec<_> = (0..MIDS)
+        .map(|_| {
+            let leaves: Vec<_> = (0..LEAVES)
+                .map(|_| frame.alloc(Node(Vec::new())).as_ref())
+                .collect();
+            frame.alloc(Node(leaves)).as_ref()
+        })
+        .collect();
+    let _root = frame.alloc(Node(mids));
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            state.collect();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let objects = 1 + MIDS + MIDS * LEAVES;
+    let single = mark(1);
+    let parallel = mark(4);
+    println!(
+        "major gc of {} live objects, on {} cpus",
+        objects,
+        std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+    );
+    println!("1 thread:  {:?}", single);
+    println!("4 threads: {:?}", parallel);
+    println!(
+        "speedup:   {:.2}x",
+        single.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
@@ -0,0 +1,41 @@
+//! time the sweep of 1M dead small objects, freed one by one and with `GCConfig::batch_sweep`.
+//! run it with `cargo run --release --example sweep`
+use std::time::{Duration, Instant};
+
+use cgc::frame::GCFrame;
+use cgc::state::{GCConfig, State};
+use cgc::trace::{Trace, Tracer};
+
+struct Leaf(#[allow(dead_code)] u64);
+
+impl Trace for Leaf {
+    fn trace(&self, _: &mut dyn Tracer) {}
+}
+
+const OBJECTS: u64 = 1_000_000;
+
+/// a major gc of nothing but dead objects, so it's mostly the sweep
+fn sweep(batch: bool) -> Duration {
+    let config = GCConfig::builder()
+        .batch_sweep(batch)
+        .minor_gc_trigger_size(1 << 34)
+        .minor_heap_size_limit(1 << 35)
+        .build()
+        .unwrap();
+    let state: &'static State = Box::leak(Box::new(State::new(config)));
+    let frame: &'static GCFrame = Box::leak(Box::new(GCFrame::new(state)));
+    for i in 0..OBJECTS {
+        drop(frame.alloc(Leaf(i)));
+    }
+    let start = Instant::now();
+    state.collect();
+    let elapsed = start.elapsed();
+    assert_eq!(state.stats().total_size, 0);
+    elapsed
+}
+
+fn main() {
+    println!("sweep of {} dead objects", OBJECTS);
+    println!("one by one: {:?}", sweep(false));
+    println!("batched:    {:?}", sweep(true));
+}
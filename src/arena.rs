@@ -0,0 +1,294 @@
+//! gc references branded with a generative lifetime, like `gc-arena`.
+//! every `Gc<'gc, T>` is only usable inside the `State::mutate` closure creating its `'gc`,
+//! and no collection could free or move an object while a closure is running,
+//! so holding a reference across a collection point is a compile error instead of UB.
+//! objects are kept after the closure only if they are reachable from a root, like a `GCRoot` or a `GCBox`.
+//!
+//! the objects are read through `Gc::borrow`, which holds a shared borrow checked by `Mutation::write`,
+//! so a `GcMut` never aliases a `GcRead` of the same object.
+//!
+//! it's an additional layer over the handles of `gc_box`, mixing both is fine.
+use core::cell::Cell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::Ordering;
+
+use super::frame::GCFrame;
+use super::gc_box::{BorrowError, GCBox, GCCell, GCHeader, GCMut, GCRef, GCRoot};
+use super::state::State;
+#[cfg(feature = "std")]
+use super::state::MUTATING;
+use super::trace::Trace;
+
+/// invariant over `'gc`, so a brand could never be mixed with another one
+type Invariant<'gc> = PhantomData<Cell<&'gc ()>>;
+
+/// the context of one `State::mutate` closure, every allocation and mutation goes through it
+pub struct Mutation<'gc> {
+    state: &'static State,
+    frame: &'static GCFrame,
+    brand: Invariant<'gc>,
+}
+
+/// a reference which is valid until the end of the closure of `'gc`
+pub struct Gc<'gc, T: Trace> {
+    cell: GCCell<T>,
+    brand: Invariant<'gc>,
+}
+
+/// a mutable borrow of the object through the write barrier, see `GCMut`
+pub struct GcMut<'gc, T: Trace> {
+    barrier: GCMut<T>,
+    brand: Invariant<'gc>,
+}
+
+/// a shared borrow of the object, it could not be written until every one is dropped
+pub struct GcRead<'gc, T: Trace> {
+    cell: GCCell<T>,
+    brand: Invariant<'gc>,
+}
+
+impl State {
+    /// run `f` with the collections held off, the only place to use `Gc` references.
+    /// minor gc and the sweep of major gc wait until `f` returns, on every thread,
+    /// while a running major gc keeps marking, the objects allocated or written meanwhile are recorded as usual.
+    /// the collection triggered by the allocations in `f` runs after it returns.
+    ///
+    /// closures should be short, since they pause the collection of every thread,
+    /// and the closures of different threads are serialized, each one holds `start_minor_gc_flag`
+    /// and waits for the one running.
+    /// the gc triggered by allocations and the OOM handler are deferred until `f` returns,
+    /// even through `GCFrame::alloc`. it must not be nested, and `f` must not call `collect`
+    /// or any other gc of the state, they would wait for `f` forever, with `std` both panic.
+    pub fn mutate<R>(&'static self, f: impl for<'gc> FnOnce(&Mutation<'gc>) -> R) -> R {
+        #[cfg(feature = "std")]
+        if self.mutating_on_current_thread() {
+            panic!("[FALTAL ERROR] `State::mutate` is nested");
+        }
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.mutating.store(true, Ordering::Release);
+        #[cfg(feature = "std")]
+        MUTATING.with(|states| states.borrow_mut().push(self as *const State as usize));
+        let result = {
+            let mutation = Mutation {
+                state: self,
+                frame: self.push_frame(),
+                brand: PhantomData,
+            };
+            f(&mutation)
+        };
+        self.poll_minor_heap();
+        result
+    }
+}
+
+impl<'gc> Mutation<'gc> {
+    /// allocate an object, it's collected after the closure unless it's reachable from a root by then
+    pub fn alloc<T: Trace>(&self, value: T) -> Gc<'gc, T> {
+        Gc::new(self.frame.allocate_gc_cell(value, false))
+    }
+
+    /// the object of a box, it's kept until the closure returns even if the box is dropped inside
+    pub fn get<T: Trace>(&self, gc_box: &GCBox<T>) -> Gc<'gc, T> {
+        self.load(&gc_box.as_ref())
+    }
+
+    /// the object of a root, like `get`
+    pub fn get_root<T: Trace>(&self, root: &GCRoot<T>) -> Gc<'gc, T> {
+        self.load(&root.to_ref())
+    }
+
+    /// read a reference stored in a gc object
+    pub fn load<T: Trace>(&self, reference: &GCRef<T>) -> Gc<'gc, T> {
+        Gc::new(reference.cell().resolve())
+    }
+
+    /// root the object, so it outlives the closure
+    pub fn root<T: Trace>(&self, gc: Gc<'gc, T>) -> GCRoot<T> {
+        GCRoot::new(&gc.as_ref())
+    }
+
+    /// borrow the object mutably, panics if it's already borrowed in any way, see `try_write`
+    pub fn write<T: Trace>(&self, gc: Gc<'gc, T>) -> GcMut<'gc, T> {
+        self.try_write(gc)
+            .unwrap_or_else(|err| panic!("[FALTAL ERROR] {}", err))
+    }
+
+    /// returns `Err(BorrowError)` if the object is mutably borrowed already, or a `GcRead` of it is alive,
+    /// the mutation is recorded by the write barrier after the returned one is dropped
+    pub fn try_write<T: Trace>(&self, gc: Gc<'gc, T>) -> Result<GcMut<'gc, T>, BorrowError> {
+        Ok(GcMut {
+            barrier: GCMut::try_new(self.state, gc.cell)?,
+            brand: PhantomData,
+        })
+    }
+}
+
+impl Drop for Mutation<'_> {
+    fn drop(&mut self) {
+        // the objects of the closure are never rooted by the frame, so nothing is unrooted here
+        unsafe { self.state.pop_frame(self.frame) };
+        #[cfg(feature = "std")]
+        MUTATING.with(|states| {
+            let mut states = states.borrow_mut();
+            let state = self.state as *const State as usize;
+            if let Some(i) = states.iter().position(|mutating| *mutating == state) {
+                states.swap_remove(i);
+            }
+        });
+        self.state.mutating.store(false, Ordering::Release);
+        self.state
+            .start_minor_gc_flag
+            .store(false, Ordering::Release);
+    }
+}
+
+impl<'gc, T: Trace> Gc<'gc, T> {
+    fn new(cell: GCCell<T>) -> Self {
+        Self {
+            cell,
+            brand: PhantomData,
+        }
+    }
+    pub fn header(self) -> *mut GCHeader {
+        self.cell.header
+    }
+    /// a reference to store into other gc objects, through `Mutation::write`
+    pub fn as_ref(self) -> GCRef<T> {
+        GCRef::new(self.cell)
+    }
+    /// whether both reference the same object, see `GCRef::ptr_eq`
+    pub fn ptr_eq(a: Self, b: Self) -> bool {
+        a.cell.header == b.cell.header
+    }
+    /// borrow the object shared, panics if it's mutably borrowed, see `try_borrow`
+    pub fn borrow(self) -> GcRead<'gc, T> {
+        self.try_borrow()
+            .unwrap_or_else(|err| panic!("[FALTAL ERROR] {}", err))
+    }
+    /// returns `Err(BorrowError)` if a `GcMut` or a `GCMut` of the object is alive
+    pub fn try_borrow(self) -> Result<GcRead<'gc, T>, BorrowError> {
+        if !unsafe { (*self.cell.header).borrow_shared() } {
+            return Err(BorrowError {
+                header: self.cell.header as usize,
+            });
+        }
+        Ok(GcRead {
+            cell: self.cell,
+            brand: PhantomData,
+        })
+    }
+}
+
+impl<T: Trace> Clone for Gc<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Trace> Copy for Gc<'_, T> {}
+
+impl<T: Trace + fmt::Debug> fmt::Debug for Gc<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.borrow(), f)
+    }
+}
+
+impl<T: Trace> Deref for GcRead<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // nothing is moved or freed during the closure
+        unsafe { &*(self.cell.data as *const T) }
+    }
+}
+
+impl<T: Trace> Drop for GcRead<'_, T> {
+    fn drop(&mut self) {
+        unsafe { (*self.cell.header).release_shared() };
+    }
+}
+
+impl<T: Trace> Deref for GcMut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.barrier
+    }
+}
+
+impl<T: Trace> DerefMut for GcMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.barrier
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::sync::atomic::Ordering;
+
+    use crate::state::GCConfig;
+    use crate::test_util::{env, env_with, Leaf, Node};
+
+    #[test]
+    fn frame_alloc_inside_mutate_is_deferred() {
+        let config = GCConfig::builder()
+            .minor_gc_trigger_size(1024)
+            .minor_heap_size_limit(1024)
+            .build()
+            .unwrap();
+        let (state, frame) = env_with(config);
+        let kept = state.mutate(|mc| {
+            let kept = mc.alloc(Node(0, vec![]));
+            // far above the trigger and the limit, which would collect while the closure holds minor gc
+            for i in 0..256 {
+                frame.alloc(Leaf(i));
+            }
+            assert!(state.gc_deferred());
+            assert_eq!(state.minor_gc_count.load(Ordering::Acquire), 0);
+            mc.root(kept)
+        });
+        assert!(!state.gc_deferred());
+        assert!(state.minor_gc_count.load(Ordering::Acquire) > 0);
+        assert_eq!(state.mutate(|mc| mc.get_root(&kept).borrow().0), 0);
+    }
+
+    #[test]
+    fn shared_borrows_exclude_writes() {
+        let (state, _) = env();
+        state.mutate(|mc| {
+            let node = mc.alloc(Node(1, vec![]));
+            let read = node.borrow();
+            let again = node.borrow();
+            assert!(mc.try_write(node).is_err());
+            drop(read);
+            assert!(mc.try_write(node).is_err());
+            drop(again);
+            let mut write = mc.write(node);
+            write.0 = 2;
+            assert!(node.try_borrow().is_err());
+            drop(write);
+            assert_eq!(node.borrow().0, 2);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "nested")]
+    fn nested_mutate_panics() {
+        let (state, _) = env();
+        state.mutate(|_| state.mutate(|_| ()));
+    }
+
+    #[test]
+    #[should_panic(expected = "inside `State::mutate`")]
+    fn collect_inside_mutate_panics() {
+        let (state, _) = env();
+        state.mutate(|_| state.collect());
+    }
+}
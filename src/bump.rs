@@ -0,0 +1,224 @@
+//! thread local bump allocation.
+//! every thread carves small cells from its own chunk, and only registers them into their frames
+//! when the chunk is full, so the allocating threads don't fight for the frame registry.
+//! without `std` there is no thread local storage, so every cell takes the slow path.
+use core::alloc::Layout;
+#[cfg(feature = "std")]
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::gc_box::GCHeader;
+use super::state::Allocator;
+use super::sync::Mutex;
+
+/// size of every chunk
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+/// cells larger than this are allocated by the slow path
+#[cfg(feature = "std")]
+pub(crate) const MAX_BUMP_SIZE: usize = CHUNK_SIZE / 8;
+/// cells aligned more than this are allocated by the slow path
+const CHUNK_ALIGN: usize = 16;
+
+/// a piece of memory shared by many cells,
+/// it's freed after every cell in it and the owning thread released it.
+pub(crate) struct Chunk {
+    allocator: &'static dyn Allocator,
+    start: *mut u8,
+    /// cells alive in this chunk, plus 1 while a thread is still bumping in it
+    refs: AtomicUsize,
+    /// cells not registered into their frames yet
+    pending: Mutex<Vec<*mut GCHeader>>,
+}
+
+impl Chunk {
+    #[cfg(feature = "std")]
+    fn new(allocator: &'static dyn Allocator) -> *const Chunk {
+        let start = allocator.alloc(Self::layout());
+        if start.is_null() {
+            return core::ptr::null();
+        }
+        Box::into_raw(Box::new(Chunk {
+            allocator,
+            start,
+            refs: AtomicUsize::new(1),
+            pending: Mutex::new(Vec::new()),
+        }))
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(CHUNK_SIZE, CHUNK_ALIGN).unwrap()
+    }
+
+    /// register every pending cell into its frame at once, freed cells are skipped
+    #[cfg(feature = "std")]
+    pub(crate) fn flush(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        for header in pending.drain(..) {
+            let header = unsafe { &*header };
+            if !header.is_freed() {
+                header.frame().register(header as *const _ as *mut _);
+            }
+        }
+    }
+
+    /// free a cell in this chunk, and release the chunk
+    /// # Safety
+    /// `header` should be a live cell in `this`
+    pub(crate) unsafe fn free_cell(this: *const Chunk, header: *mut GCHeader) {
+        {
+            // a concurrent flush either registered the cell already, or will skip it
+            let _pending = (*this).pending.lock().unwrap();
+            (*header).set_freed();
+        }
+        (*header).frame().unregister(header);
+        Self::release(this);
+    }
+
+    /// free many cells in this chunk like `free_cell`, the chunk is released once for all of them
+    /// # Safety
+    /// `headers` should be distinct live cells in `this`
+    pub(crate) unsafe fn free_cells(this: *const Chunk, headers: &[*mut GCHeader]) {
+        {
+            let _pending = (*this).pending.lock().unwrap();
+            for header in headers {
+                (**header).set_freed();
+            }
+        }
+        for header in headers {
+            (**header).frame().unregister(*header);
+        }
+        Self::release_many(this, headers.len());
+    }
+
+    /// release one reference, the chunk is freed with the last one
+    /// # Safety
+    /// every cell and the owning thread should release the chunk exactly once
+    pub(crate) unsafe fn release(this: *const Chunk) {
+        Self::release_many(this, 1);
+    }
+
+    /// release `count` references at once
+    /// # Safety
+    /// see `release`
+    unsafe fn release_many(this: *const Chunk, count: usize) {
+        if (*this).refs.fetch_sub(count, Ordering::AcqRel) == count {
+            let chunk = Box::from_raw(this as *mut Chunk);
+            chunk.allocator.dealloc(chunk.start, Self::layout());
+        }
+    }
+}
+
+/// the chunk bumped by current thread
+#[cfg(feature = "std")]
+struct LocalBuffer {
+    chunk: *const Chunk,
+    cursor: usize,
+    end: usize,
+}
+
+#[cfg(feature = "std")]
+impl LocalBuffer {
+    /// register the pending cells, and give up the current chunk
+    fn retire(&mut self) {
+        if !self.chunk.is_null() {
+            unsafe {
+                (*self.chunk).flush();
+                Chunk::release(self.chunk);
+            }
+            self.chunk = core::ptr::null();
+        }
+        self.cursor = 0;
+        self.end = 0;
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for LocalBuffer {
+    fn drop(&mut self) {
+        self.retire();
+    }
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static BUFFER: RefCell<LocalBuffer> = const {
+        RefCell::new(LocalBuffer {
+            chunk: core::ptr::null(),
+            cursor: 0,
+            end: 0,
+        })
+    };
+}
+
+/// carve a cell from the chunk of current thread, the cell should be registered by `pend` later.
+/// returns `None` if the layout doesn't fit in a chunk or a new chunk could not be allocated,
+/// then the caller should take the slow path.
+/// a chunk only holds cells of one allocator, the current chunk is retired when it changes.
+#[cfg(feature = "std")]
+pub(crate) fn allocate(
+    allocator: &'static dyn Allocator,
+    layout: Layout,
+) -> Option<(*mut u8, *const Chunk)> {
+    if layout.size() > MAX_BUMP_SIZE || layout.align() > CHUNK_ALIGN {
+        return None;
+    }
+    BUFFER
+        .try_with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            let mut start = align_up(buffer.cursor, layout.align());
+            if buffer.chunk.is_null()
+                || !core::ptr::addr_eq(unsafe { (*buffer.chunk).allocator }, allocator)
+                || start + layout.size() > buffer.end
+            {
+                buffer.retire();
+                let chunk = Chunk::new(allocator);
+                if chunk.is_null() {
+                    return None;
+                }
+                let base = unsafe { (*chunk).start as usize };
+                buffer.chunk = chunk;
+                buffer.end = base + CHUNK_SIZE;
+                start = base;
+            }
+            buffer.cursor = start + layout.size();
+            let chunk = buffer.chunk;
+            unsafe { (*chunk).refs.fetch_add(1, Ordering::AcqRel) };
+            Some((start as *mut u8, chunk))
+        })
+        .ok()
+        .flatten()
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn allocate(
+    _allocator: &'static dyn Allocator,
+    _layout: Layout,
+) -> Option<(*mut u8, *const Chunk)> {
+    None
+}
+
+/// defer the registration of an initialized cell until its chunk is retired
+/// # Safety
+/// `header` should be carved from `chunk`
+pub(crate) unsafe fn pend(chunk: *const Chunk, header: *mut GCHeader) {
+    (*chunk).pending.lock().unwrap().push(header);
+}
+
+/// register the pending cells in the chunk of current thread
+pub(crate) fn flush() {
+    #[cfg(feature = "std")]
+    let _ = BUFFER.try_with(|buffer| {
+        let buffer = buffer.borrow();
+        if !buffer.chunk.is_null() {
+            unsafe { (*buffer.chunk).flush() };
+        }
+    });
+}
+
+#[cfg(feature = "std")]
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -1,44 +1,557 @@
-use std::{
-    alloc::{alloc, Layout},
-    intrinsics::size_of,
-};
-
-use dashmap::DashSet as Set;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::any::TypeId;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
 use crate::gc_box::GCCellLayout;
 
 use super::{
-    gc_box::{GCCell, GCHeader},
+    bump,
+    gc_array::GCArray,
+    gc_box::{GCBox, GCCell, GCHeader, GCRef, Generation},
+    pinned_region::PinnedRegion,
     state::State,
+    sync::Set,
     trace::Trace,
+    type_registry::TypeRegistry,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// the system allocator returned null
+    SystemOom { size: usize },
+    /// a newly allocated header is already registered in the frame,
+    /// which means a freed object was never unregistered. it's a bug of cgc.
+    DuplicateHeader { address: usize },
+    /// the rest of the pinned region is too small for the object
+    RegionFull { size: usize },
+}
+
+impl core::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AllocError::SystemOom { size } => {
+                write!(f, "the system allocator failed to allocate {} bytes", size)
+            }
+            AllocError::DuplicateHeader { address } => write!(
+                f,
+                "header {:#x} is already registered, a freed object was never unregistered",
+                address
+            ),
+            AllocError::RegionFull { size } => {
+                write!(f, "the pinned region has no room for {} bytes", size)
+            }
+        }
+    }
+}
+
+impl core::error::Error for AllocError {}
+
 pub struct GCFrame {
     state: &'static State,
+    /// the frame this one is pushed from, null for the outermost ones
+    parent: AtomicPtr<GCFrame>,
     registed_gc_objects: Set<*mut GCHeader>,
-    escaped_gc_objects: *mut GCHeader,
+    /// objects outliving this frame, they are handed to the parent when this frame is popped
+    escaped_gc_objects: Set<*mut GCHeader>,
 }
 
 impl GCFrame {
-    pub(crate) fn allocate_gc_cell<T: Trace>(&self, value: T) -> GCCell<T> {
+    pub fn new(state: &'static State) -> GCFrame {
+        GCFrame {
+            state,
+            parent: AtomicPtr::new(core::ptr::null_mut()),
+            registed_gc_objects: Set::new(),
+            escaped_gc_objects: Set::new(),
+        }
+    }
+
+    /// start a nested frame, the objects escaped from it are handed to this one when it's popped
+    pub fn push_frame(&'static self) -> &'static GCFrame {
+        let frame = self.state.push_frame();
+        frame
+            .parent
+            .store(self as *const _ as *mut _, Ordering::Release);
+        frame
+    }
+
+    pub fn parent(&self) -> Option<&'static GCFrame> {
+        unsafe { self.parent.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// the object allocated in this frame is stored somewhere outliving this frame,
+    /// or returned to the outer scope, so it stays rooted after this frame is popped.
+    pub fn mark_escaped(&self, header: *mut GCHeader) {
+        self.escaped_gc_objects.insert(header);
+    }
+
+    pub fn state(&self) -> &'static State {
+        self.state
+    }
+
+    /// allocate a rooted object, it's kept alive until the returned box is dropped.
+    /// when `minor_heap_size` exceeds `minor_gc_trigger_size` after allocating,
+    /// a minor gc is triggered before returning.
+    /// when a heap exceeds its limit, the `OomHandler` of state is called.
+    pub fn alloc<T: Trace>(&'static self, value: T) -> GCBox<T> {
+        let gc_box = GCBox::new(self, value);
+        self.state.poll_minor_heap();
+        gc_box
+    }
+
+    /// same as `alloc`, but returns why the object could not be allocated instead of panicking
+    pub fn try_alloc<T: Trace>(&'static self, value: T) -> Result<GCBox<T>, AllocError> {
+        let gc_box = GCBox::try_new(self, value)?;
+        self.state.poll_minor_heap();
+        Ok(gc_box)
+    }
+
+    /// allocate a rooted object whose gc references may not be valid yet, like a null pointer
+    /// stored in a field before it's assigned, it's never traced until `GCBox::finish_init` is called.
+    /// the object itself is kept alive by the box as usual.
+    /// # Safety
+    /// until `finish_init`, every valid gc object referenced by it should be rooted by something else,
+    /// like a `GCBox` or a `GCRoot`, since the collector neither keeps alive nor redirects them.
+    pub unsafe fn alloc_constructing<T: Trace>(&'static self, value: T) -> GCBox<T> {
+        let gc_box = GCBox::new_constructing(self, value);
+        self.state.poll_minor_heap();
+        gc_box
+    }
+
+    /// allocate rooted objects referencing each other, like the nodes of a doubly linked list.
+    /// `init` gets every object mutably, and a reference to each of them in the same order,
+    /// so the cyclic references could be stored without going through `GCMut`.
+    ///
+    /// it's safe without the write barrier: the objects are young,
+    /// so no remembered set could miss them, the old ones are remembered after `init` returns,
+    /// and during major gc they are allocated black, and rescanned if the marking is still running.
+    /// every value is complete before it's allocated, so a gc running on other threads
+    /// only sees valid references, and gc is only triggered by this thread after `init` returns.
+    pub fn alloc_cyclic<T: Trace>(
+        &'static self,
+        values: Vec<T>,
+        init: impl FnOnce(&mut [&mut T], &[GCRef<T>]),
+    ) -> Vec<GCBox<T>> {
+        let boxes = GCBox::new_cyclic(self, values, init);
+        self.state.poll_minor_heap();
+        boxes
+    }
+
+    /// allocate a rooted object straight into `generation`, as a hint of its lifetime,
+    /// like a compiled function allocated into major heap, which is never copied by minor gc.
+    /// `Generation::Imm` falls back to major heap unless `enable_imm_gen` is set,
+    /// and `Generation::Minor` falls back to major heap if minor generation is disabled.
+    /// it triggers gc and handles OOM just like `alloc`.
+    pub fn alloc_in<T: Trace>(&'static self, value: T, generation: Generation) -> GCBox<T> {
+        self.try_alloc_in(value, generation)
+            .unwrap_or_else(|err| panic!("[FALTAL ERROR] failed to allocate gc cell: {}", err))
+    }
+
+    /// same as `alloc_in`, but returns why the object could not be allocated instead of panicking
+    pub fn try_alloc_in<T: Trace>(
+        &'static self,
+        value: T,
+        generation: Generation,
+    ) -> Result<GCBox<T>, AllocError> {
+        let gc_box = GCBox::try_new_in(self, value, generation)?;
+        self.state.poll_minor_heap();
+        Ok(gc_box)
+    }
+
+    /// allocate a rooted array of `len` elements behind a single header,
+    /// the element at `i` is initialized with `init(i)`.
+    /// it triggers minor gc and handles oom just like `alloc`.
+    pub fn allocate_array<T: Trace>(
+        &'static self,
+        len: usize,
+        init: impl Fn(usize) -> T,
+    ) -> GCArray<T> {
+        self.try_allocate_array(len, init)
+            .unwrap_or_else(|err| panic!("[FALTAL ERROR] failed to allocate gc array: {}", err))
+    }
+
+    /// same as `allocate_array`, but returns why the array could not be allocated instead of panicking
+    pub fn try_allocate_array<T: Trace>(
+        &'static self,
+        len: usize,
+        init: impl Fn(usize) -> T,
+    ) -> Result<GCArray<T>, AllocError> {
+        let array = GCArray::try_new(self, len, init)?;
+        self.state.poll_minor_heap();
+        Ok(array)
+    }
+
+    /// reserve a contiguous region of `size` bytes for objects which must stay put,
+    /// see `PinnedRegion`. panics if it could not be allocated
+    pub fn alloc_pinned_region(&'static self, size: usize) -> PinnedRegion {
+        self.try_alloc_pinned_region(size).unwrap_or_else(|err| {
+            panic!("[FALTAL ERROR] failed to allocate pinned region: {}", err)
+        })
+    }
+
+    /// same as `alloc_pinned_region`, but returns the error instead of panicking
+    pub fn try_alloc_pinned_region(
+        &'static self,
+        size: usize,
+    ) -> Result<PinnedRegion, AllocError> {
+        PinnedRegion::try_new(self, size)
+    }
+
+    pub(crate) fn allocate_gc_cell<T: Trace>(&'static self, value: T, rooted: bool) -> GCCell<T> {
+        self.try_allocate_gc_cell(value, rooted)
+            .unwrap_or_else(|err| panic!("[FALTAL ERROR] failed to allocate gc cell: {}", err))
+    }
+
+    pub(crate) fn try_allocate_gc_cell<T: Trace>(
+        &'static self,
+        value: T,
+        rooted: bool,
+    ) -> Result<GCCell<T>, AllocError> {
+        self.try_allocate_gc_cell_in(value, self.default_generation_of::<T>(), false, rooted)
+    }
+
+    /// `constructing` for the cells of `alloc_constructing`,
+    /// it's set before anyone could trace the cell.
+    /// `rooted` for the cells of `GCBox`, see `try_allocate_raw`
+    pub(crate) fn try_allocate_gc_cell_in<T: Trace>(
+        &'static self,
+        value: T,
+        generation: Generation,
+        constructing: bool,
+        rooted: bool,
+    ) -> Result<GCCell<T>, AllocError> {
+        let meta = self.state.type_registry.get(TypeId::of::<T>());
         unsafe {
             let layout = Layout::new::<GCCellLayout<T>>();
+            let header = self.try_allocate_raw_in(layout, generation, rooted, |header| {
+                (*header).init::<T>(self);
+                (*header).set_constructing(constructing);
+                if let Some(finalizer) = meta.as_ref().and_then(TypeRegistry::finalizer::<T>) {
+                    (*header).set_finalizer(finalizer);
+                }
+                let cell = header as *mut GCCellLayout<T>;
+                core::ptr::addr_of_mut!((*cell).data).write(value);
+            })?;
+            if meta.is_some_and(|meta| meta.pinned) {
+                (*header).set_pinned(true);
+                self.state.pinned_objects.insert(header);
+            }
+            Ok(GCCell::from_header(header))
+        }
+    }
+
+    /// minor generation, or major one if minor generation is disabled
+    pub(crate) fn default_generation(&self) -> Generation {
+        if self.state.config.disable_minor_gen {
+            Generation::Major
+        } else {
+            Generation::Minor
+        }
+    }
+
+    /// the generation registered for `T` by `State::register_type`, or the default one
+    pub(crate) fn default_generation_of<T: Trace>(&self) -> Generation {
+        self.state
+            .type_registry
+            .get(TypeId::of::<T>())
+            .and_then(|meta| meta.generation)
+            .unwrap_or_else(|| self.default_generation())
+    }
+
+    /// allocate a gc cell tracked as a minor object, or a major one if minor generation is disabled,
+    /// `init` should initialize both the header and the data before anyone could see the cell.
+    /// a `rooted` cell is added to the roots before it's published to its generation,
+    /// so a gc running on another thread never frees it between the two.
+    pub(crate) unsafe fn try_allocate_raw(
+        &'static self,
+        layout: Layout,
+        rooted: bool,
+        init: impl FnOnce(*mut GCHeader),
+    ) -> Result<*mut GCHeader, AllocError> {
+        self.try_allocate_raw_in(layout, self.default_generation(), rooted, init)
+    }
+
+    /// same as `try_allocate_raw`, but tracked in `generation`, see `alloc_in` for the fallbacks
+    pub(crate) unsafe fn try_allocate_raw_in(
+        &'static self,
+        layout: Layout,
+        generation: Generation,
+        rooted: bool,
+        init: impl FnOnce(*mut GCHeader),
+    ) -> Result<*mut GCHeader, AllocError> {
+        let generation = match generation {
+            Generation::Imm if !self.state.config.enable_imm_gen => Generation::Major,
+            Generation::Minor => self.default_generation(),
+            generation => generation,
+        };
+        // fast path: bump in the chunk of current thread, and register it with the whole chunk
+        let allocator = &*self.state.allocator;
+        let (header_ptr, chunk) = match bump::allocate(allocator, layout) {
+            Some((ptr, chunk)) => (ptr as *mut GCHeader, chunk),
+            None => (allocator.alloc(layout) as *mut GCHeader, core::ptr::null()),
+        };
+        if header_ptr.is_null() {
+            return Err(AllocError::SystemOom { size: layout.size() });
+        }
+        if chunk.is_null() && !self.registed_gc_objects.insert(header_ptr) {
+            allocator.dealloc(header_ptr as *mut u8, layout);
+            return Err(AllocError::DuplicateHeader {
+                address: header_ptr as usize,
+            });
+        }
+        let heap_size = match generation {
+            Generation::Minor => &self.state.minor_heap_size,
+            Generation::Major => &self.state.major_heap_size,
+            Generation::Imm => &self.state.imm_size,
+        };
+        heap_size.fetch_add(layout.size(), Ordering::Acquire);
+        self.state
+            .total_size
+            .fetch_add(layout.size(), Ordering::Acquire);
+        self.state
+            .total_allocated_bytes
+            .fetch_add(layout.size(), Ordering::Relaxed);
+        self.state
+            .total_allocated_objects
+            .fetch_add(1, Ordering::Relaxed);
+        init(header_ptr);
+        if let Some(hook) = &self.state.alloc_hook {
+            hook((*header_ptr).type_id(), layout.size());
+        }
+        (*header_ptr).set_chunk(chunk);
+        if !chunk.is_null() {
+            bump::pend(chunk, header_ptr);
+        }
+        (*header_ptr).set_generation(generation);
+        let large_object_size = self.state.config.large_object_size;
+        if large_object_size != 0 && layout.size() >= large_object_size {
+            self.state.large_object_set.insert(header_ptr);
             self.state
-                .minor_heap_size
-                .fetch_add(layout.size(), std::sync::atomic::Ordering::Acquire);
-            let header_ptr = alloc(layout) as *mut GCHeader;
-            let header = &mut *header_ptr;
-            header.init::<T>();
-            let data = header_ptr.add(1) as *mut T;
-            *data = value;
-            if !self.registed_gc_objects.insert(header_ptr) {
-                panic!("[FALTAL ERROR] failed to allocate gc cell");
-            }
-            GCCell {
-                header,
-                data,
-                phantom: std::marker::PhantomData,
+                .large_object_heap_size
+                .fetch_add(layout.size(), Ordering::AcqRel);
+        }
+        if rooted {
+            self.state.minor_heap_roots.insert(header_ptr);
+        }
+        // an old object may point to younger ones right away,
+        // and it's marked for the running major gc before the sweep could see it
+        match generation {
+            Generation::Minor => {
+                self.state.allocate_black(header_ptr);
+                self.state.minor_heap_gen.insert(header_ptr);
+            }
+            Generation::Major => {
+                if self.state.points_into_minor(header_ptr) {
+                    self.state.remembered_set.insert(header_ptr);
+                }
+                self.state.allocate_black(header_ptr);
+                self.state.major_heap_gen.insert(header_ptr);
+            }
+            Generation::Imm => {
+                if self.state.points_into_minor(header_ptr) {
+                    self.state.remembered_set.insert(header_ptr);
+                }
+                if self.state.points_out_of_imm(header_ptr) {
+                    self.state.imm_remembered_set.insert(header_ptr);
+                }
+                self.state.imm_gen.insert(header_ptr);
+                // imm objects are never marked or swept
+                return Ok(header_ptr);
             }
         }
+        Ok(header_ptr)
+    }
+
+    /// the object is allocated
+    pub(crate) fn register(&self, header: *mut GCHeader) {
+        if !self.registed_gc_objects.insert(header) {
+            panic!("[FALTAL ERROR] failed to allocate gc cell");
+        }
+    }
+
+    /// forget every registered object, hand the escaped ones to the parent frame,
+    /// and returns the ones not escaped.
+    /// objects allocated by current thread in the pending bump chunk are registered first.
+    pub(crate) fn clear(&self) -> Vec<*mut GCHeader> {
+        bump::flush();
+        let parent = self.parent();
+        let mut headers = Vec::new();
+        for header in self.registed_gc_objects.iter() {
+            let header = *header;
+            if !self.escaped_gc_objects.contains(&header) {
+                headers.push(header);
+            } else if let Some(parent) = parent {
+                unsafe { (*header).set_frame(parent) };
+                parent.register(header);
+            }
+        }
+        self.registed_gc_objects.clear();
+        self.escaped_gc_objects.clear();
+        self.parent.store(core::ptr::null_mut(), Ordering::Release);
+        headers
+    }
+
+    /// the object is freed
+    pub(crate) fn unregister(&self, header: *mut GCHeader) {
+        self.registed_gc_objects.remove(&header);
+        self.escaped_gc_objects.remove(&header);
+    }
+
+    /// the object is moved
+    pub(crate) fn reregister(&self, from: *mut GCHeader, to: *mut GCHeader) {
+        self.registed_gc_objects.remove(&from);
+        self.registed_gc_objects.insert(to);
+        if self.escaped_gc_objects.remove(&from).is_some() {
+            self.escaped_gc_objects.insert(to);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use alloc::vec;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::gc_box::{GCHeader, GCRef, Generation};
+    use crate::state::GCConfig;
+    use crate::test_util::{env, env_with, full_gc, get, is_tracked, Leaf, Node};
+    use crate::trace::{Trace, Tracer};
+
+    #[test]
+    fn allocation_racing_minor_gc_is_never_freed() {
+        static RUNNING: AtomicBool = AtomicBool::new(true);
+        let (state, frame) = env();
+        let collector = std::thread::spawn(move || {
+            while RUNNING.load(Ordering::SeqCst) {
+                state.minor_heap_gen_gc();
+            }
+        });
+        for i in 0..20_000 {
+            let leaf = frame.alloc(Leaf(i));
+            assert!(is_tracked(state, leaf.header()));
+            assert_eq!(get(&leaf).0, i);
+        }
+        RUNNING.store(false, Ordering::SeqCst);
+        collector.join().unwrap();
+    }
+
+    #[test]
+    fn escaped_objects_are_handed_to_the_parent_frame() {
+        let (state, _) = env();
+        let outer = state.push_frame();
+        let inner = outer.push_frame();
+        let kept = inner.alloc(Leaf(1));
+        inner.mark_escaped(kept.header());
+        let gone = inner.alloc(Leaf(2));
+        let gone_header = gone.header();
+        drop(gone);
+        unsafe { state.pop_frame(inner) };
+        assert!(outer.registed_gc_objects.contains(&kept.header()));
+        assert!(core::ptr::eq(unsafe { (*kept.header()).frame() }, outer));
+        full_gc(state);
+        assert!(is_tracked(state, kept.header()));
+        assert!(!is_tracked(state, gone_header));
+        assert_eq!(get(&kept).0, 1);
+        drop(kept);
+        unsafe { state.pop_frame(outer) };
+        full_gc(state);
+        assert_eq!(state.total_size.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn alloc_in_allocates_into_the_given_generation() {
+        let (state, frame) = env();
+        let young = frame.alloc(Node(1, vec![]));
+        let old = frame.alloc_in(Node(2, vec![young.as_ref()]), Generation::Major);
+        let header = old.header();
+        assert_eq!(unsafe { (*header).generation() }, Generation::Major);
+        assert!(state.major_heap_gen.contains(&header));
+        assert!(state.remembered_set.contains(&header));
+        drop(young);
+        for _ in 0..5 {
+            state.minor_heap_gen_gc();
+        }
+        // never copied by minor gc
+        assert_eq!(old.header(), header);
+        assert_eq!(get(&old).1[0].0, 1);
+        // imm falls back to major without `enable_imm_gen`
+        let imm = frame.alloc_in(Leaf(3), Generation::Imm);
+        assert_eq!(unsafe { (*imm.header()).generation() }, Generation::Major);
+
+        let config = GCConfig::builder().enable_imm_gen(true).build().unwrap();
+        let (state, frame) = env_with(config);
+        let imm = frame.alloc_in(Leaf(4), Generation::Imm);
+        assert!(state.imm_gen.contains(&imm.header()));
+        let size = unsafe { (*imm.header()).size() };
+        assert_eq!(state.imm_size.load(Ordering::Acquire), size);
+        full_gc(state);
+        assert!(state.imm_gen.contains(&imm.header()));
+    }
+
+    #[test]
+    fn large_objects_are_never_copied() {
+        struct Big([u64; 256]);
+
+        impl Trace for Big {
+            fn trace(&self, _: &mut dyn Tracer) {}
+        }
+
+        struct Holder(GCRef<Big>);
+
+        impl Trace for Holder {
+            fn trace(&self, tracer: &mut dyn Tracer) {
+                self.0.trace(tracer);
+            }
+        }
+
+        let config = GCConfig::builder().large_object_size(1024).build().unwrap();
+        let (state, frame) = env_with(config);
+        let big = frame.alloc(Big([7; 256]));
+        let holder = frame.alloc(Holder(big.as_ref()));
+        let header = big.header();
+        let small = frame.alloc(Leaf(1));
+        assert!(state.large_object_set.contains(&header));
+        assert!(!state.large_object_set.contains(&small.header()));
+        let size = unsafe { (*header).size() };
+        assert_eq!(state.large_object_heap_size.load(Ordering::Acquire), size);
+        drop(big);
+        for _ in 0..6 {
+            state.minor_heap_gen_gc();
+            assert_eq!(get(&holder).0.header(), header);
+        }
+        assert_eq!(get(&holder).0 .0[255], 7);
+        drop(holder);
+        full_gc(state);
+        assert!(state.large_object_set.is_empty());
+        assert_eq!(state.large_object_heap_size.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn constructing_objects_are_not_traced_until_finished() {
+        struct Partial(*mut GCHeader);
+
+        impl Trace for Partial {
+            fn trace(&self, tracer: &mut dyn Tracer) {
+                tracer.visit(self.0);
+            }
+        }
+
+        let (state, frame) = env();
+        // tracing the dangling pointer would crash the gc
+        let mut partial = unsafe { frame.alloc_constructing(Partial(core::ptr::dangling_mut())) };
+        for _ in 0..3 {
+            state.minor_heap_gen_gc();
+            state.collect();
+        }
+        let child = frame.alloc(Leaf(5));
+        let child_header = child.header();
+        partial.as_mut().0 = child_header;
+        partial.finish_init();
+        drop(child);
+        full_gc(state);
+        assert!(is_tracked(state, child_header));
+        drop(partial);
+        full_gc(state);
+        assert!(!is_tracked(state, child_header));
     }
 }
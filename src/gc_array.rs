@@ -0,0 +1,209 @@
+use alloc::alloc::handle_alloc_error;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::any::TypeId;
+use core::fmt;
+use core::ops::Index;
+
+use super::frame::{AllocError, GCFrame};
+use super::gc_box::{finalize_noop, CellFns, GCHeader};
+use super::trace::{Trace, Tracer};
+use super::type_registry::trace_offsets;
+
+/// a rooted array of gc object, the elements are stored right after a single header.
+/// it's kept alive until the array is dropped, just like `GCBox`.
+pub struct GCArray<T: Trace> {
+    frame: &'static GCFrame,
+    header: *mut GCHeader,
+    data: *mut T,
+    len: usize,
+}
+
+impl<T: Trace> GCArray<T> {
+    pub(crate) fn try_new(
+        frame: &'static GCFrame,
+        len: usize,
+        init: impl Fn(usize) -> T,
+    ) -> Result<Self, AllocError> {
+        // build elements first, so a panicking `init` never leaves a half initialized cell
+        let mut elements: Vec<T> = (0..len).map(init).collect();
+        let layout = ArrayLayout::new::<T>(len);
+        debug_assert_eq!(layout.data, array_fns::<T>().data_offset);
+        let header = unsafe {
+            frame.try_allocate_raw(layout.layout, true, |header| {
+                (*header).init_erased(frame, array_fns::<T>());
+                (header as *mut u8)
+                    .add(layout.len)
+                    .cast::<usize>()
+                    .write(len);
+                let data = GCHeader::data(header).cast::<T>();
+                core::ptr::copy_nonoverlapping(elements.as_ptr(), data, len);
+                elements.set_len(0);
+            })?
+        };
+        Ok(Self {
+            frame,
+            header,
+            data: unsafe { GCHeader::data(header).cast::<T>() },
+            len,
+        })
+    }
+
+    /// rooted objects are never moved, so the header is stable while the array is alive
+    pub fn header(&self) -> *mut GCHeader {
+        self.header
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.data, self.len) }
+    }
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.as_slice().get(index)
+    }
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+    /// replace an element through the write barrier, and returns the old one.
+    /// panics if `index` is out of bounds.
+    pub fn set(&mut self, index: usize, value: T) -> T {
+        assert!(
+            index < self.len,
+            "index {} out of bounds {}",
+            index,
+            self.len
+        );
+        let old = unsafe { core::ptr::replace(self.data.add(index), value) };
+        self.frame.state().write_barrier(self.header);
+        old
+    }
+}
+
+impl<T: Trace> Drop for GCArray<T> {
+    fn drop(&mut self) {
+        let state = self.frame.state();
+        if state.minor_heap_roots.remove(&self.header).is_none() {
+            state.major_heap_roots.remove(&self.header);
+        }
+    }
+}
+
+impl<T: Trace> Index<usize> for GCArray<T> {
+    type Output = T;
+    fn index(&self, index: usize) -> &T {
+        &self.as_slice()[index]
+    }
+}
+
+impl<'a, T: Trace> IntoIterator for &'a GCArray<T> {
+    type Item = &'a T;
+    type IntoIter = core::slice::Iter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: Trace + fmt::Debug> fmt::Debug for GCArray<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// header, then the length, then the elements
+struct ArrayLayout {
+    layout: Layout,
+    /// offset of the length
+    len: usize,
+    /// offset of the first element
+    data: usize,
+}
+
+impl ArrayLayout {
+    fn new<T>(len: usize) -> Self {
+        let (layout, len_offset) = Layout::new::<GCHeader>()
+            .extend(Layout::new::<usize>())
+            .unwrap();
+        let (layout, data_offset) = Layout::array::<T>(len)
+            .and_then(|elements| layout.extend(elements))
+            .expect("[FALTAL ERROR] gc array is too large");
+        Self {
+            layout: layout.pad_to_align(),
+            len: len_offset,
+            data: data_offset,
+        }
+    }
+
+    /// layout of an allocated array
+    unsafe fn of<T>(header: *const GCHeader) -> (Self, usize) {
+        let len_offset = ArrayLayout::new::<T>(0).len;
+        let len = *(header as *const u8).add(len_offset).cast::<usize>();
+        (ArrayLayout::new::<T>(len), len)
+    }
+}
+
+/// the elements start at the same offset for every length, the layout is read from the length
+const fn array_fns<T: Trace>() -> &'static CellFns {
+    const {
+        &CellFns {
+            type_id: TypeId::of::<[T]>,
+            type_name: core::any::type_name::<[T]>,
+            trace: trace_array::<T>,
+            drop: drop_array::<T>,
+            evacuate: evacuate_array::<T>,
+            finalize: finalize_noop,
+            layout: array_layout::<T>,
+            data_offset: core::mem::offset_of!(ArrayCellLayout<T>, data),
+        }
+    }
+}
+
+/// `ArrayLayout` of an empty array, for the offsets known at compile time
+#[repr(C)]
+struct ArrayCellLayout<T> {
+    header: GCHeader,
+    len: usize,
+    data: [T; 0],
+}
+
+unsafe fn array_layout<T>(header: *const GCHeader) -> Layout {
+    ArrayLayout::of::<T>(header).0.layout
+}
+
+unsafe fn elements<'a, T>(header: *mut GCHeader) -> &'a mut [T] {
+    let len = ArrayLayout::of::<T>(header).1;
+    core::slice::from_raw_parts_mut(GCHeader::data(header).cast::<T>(), len)
+}
+
+unsafe fn trace_array<T: Trace>(header: *mut GCHeader, tracer: &mut dyn Tracer) {
+    let offsets = (*header)
+        .frame()
+        .state()
+        .type_registry
+        .offsets(TypeId::of::<T>());
+    for element in elements::<T>(header).iter() {
+        element.trace(tracer);
+        if let Some(offsets) = offsets {
+            trace_offsets(offsets, element as *const T as *const u8, tracer);
+        }
+    }
+}
+
+unsafe fn drop_array<T: Trace>(header: *mut GCHeader) {
+    core::ptr::drop_in_place(elements::<T>(header));
+}
+
+unsafe fn evacuate_array<T: Trace>(header: *mut GCHeader) -> *mut GCHeader {
+    let layout = ArrayLayout::of::<T>(header).0.layout;
+    let copy = (*header).frame().state().allocator.alloc(layout) as *mut GCHeader;
+    if copy.is_null() {
+        handle_alloc_error(layout);
+    }
+    core::ptr::copy_nonoverlapping(header as *const u8, copy as *mut u8, layout.size());
+    (*copy).set_forward(core::ptr::null_mut());
+    (*header).set_forward(copy);
+    copy
+}
@@ -1,77 +1,653 @@
-use std::any::TypeId;
-use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize};
+use alloc::alloc::handle_alloc_error;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::any::TypeId;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
 
-use super::frame::GCFrame;
-use super::trace::Trace;
+use super::bump::Chunk;
+use super::frame::{AllocError, GCFrame};
+use super::state::State;
+use super::sync::HashMap;
+use super::trace::{Finalize, Trace, Tracer};
+use super::type_registry::trace_offsets;
 
+/// an object rooted by the frame allocating it, it stays on the thread of the frame,
+/// use `GCRoot` to hand the object to another thread
 pub struct GCBox<T: Trace> {
     frame: &'static GCFrame,
     value: GCCell<T>,
+    /// the box is a clone, rooted by a count in `manual_roots` since the root sets hold an object once
+    counted_root: bool,
 }
 impl<T: Trace> GCBox<T> {
-    fn new(frame: &'static GCFrame, value: T) -> Self {
+    pub(crate) fn new(frame: &'static GCFrame, value: T) -> Self {
+        let value = frame.allocate_gc_cell(value, true);
+        Self::rooted(frame, value)
+    }
+
+    pub(crate) fn try_new(frame: &'static GCFrame, value: T) -> Result<Self, AllocError> {
+        let value = frame.try_allocate_gc_cell(value, true)?;
+        Ok(Self::rooted(frame, value))
+    }
+
+    pub(crate) fn try_new_in(
+        frame: &'static GCFrame,
+        value: T,
+        generation: Generation,
+    ) -> Result<Self, AllocError> {
+        let value = frame.try_allocate_gc_cell_in(value, generation, false, true)?;
+        Ok(Self::rooted(frame, value))
+    }
+
+    pub(crate) fn new_constructing(frame: &'static GCFrame, value: T) -> Self {
+        let value = frame
+            .try_allocate_gc_cell_in(value, frame.default_generation_of::<T>(), true, true)
+            .unwrap_or_else(|err| panic!("[FALTAL ERROR] failed to allocate gc cell: {}", err));
+        Self::rooted(frame, value)
+    }
+
+    /// see `GCFrame::alloc_cyclic`
+    pub(crate) fn new_cyclic(
+        frame: &'static GCFrame,
+        values: Vec<T>,
+        init: impl FnOnce(&mut [&mut T], &[GCRef<T>]),
+    ) -> Vec<Self> {
+        let boxes: Vec<Self> = values
+            .into_iter()
+            .map(|value| Self::new(frame, value))
+            .collect();
+        let refs: Vec<_> = boxes.iter().map(|gc_box| gc_box.as_ref()).collect();
+        // every box is a distinct rooted object, so they are never moved or aliased
+        let mut values: Vec<&mut T> = boxes
+            .iter()
+            .map(|gc_box| unsafe { &mut *(gc_box.value.data as *mut T) })
+            .collect();
+        init(&mut values, &refs);
+        // promoted by minor gc of other threads while initializing, or allocated into major heap
+        let state = frame.state();
+        for gc_box in &boxes {
+            let header = gc_box.header();
+            if unsafe { (*header).generation() } != Generation::Minor
+                && state.points_into_minor(header)
+            {
+                state.remembered_set.insert(header);
+            }
+        }
+        boxes
+    }
+
+    /// the box of a cell rooted by its allocation
+    fn rooted(frame: &'static GCFrame, value: GCCell<T>) -> Self {
         Self {
             frame,
-            value: frame.allocate_gc_cell(value),
+            value,
+            counted_root: false,
         }
     }
+
+    /// rooted objects are never moved, so the header is stable while the box is alive
+    pub fn header(&self) -> *mut GCHeader {
+        self.value.header
+    }
+
+    /// run `Finalize::finalize` before the object is freed by gc
+    pub fn register_finalizer(&self)
+    where
+        T: Finalize,
+    {
+        unsafe { (*self.header()).set_finalizer(finalizing_fns::<T>()) };
+    }
+
+    /// a read only reference, it never goes through the write barrier like `as_mut`,
+    /// so reading through it never adds the object to the rescan list of major gc.
+    /// like every `GCRef`, it doesn't keep the object alive after the box is dropped.
+    pub fn as_ref(&self) -> GCRef<T> {
+        GCRef::new(self.value)
+    }
+
+    /// whether both root the same object, like `Rc::ptr_eq`, the contents are not compared
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.header() == b.header()
+    }
+
+    /// a weak reference which doesn't keep the object alive
+    pub fn downgrade(&self) -> GCWeak<T> {
+        GCWeak::new(self.frame.state(), self.header())
+    }
+
+    /// pin the object, it's never moved or freed until `unpin` is called,
+    /// even after every box of it is dropped.
+    /// useful when a raw pointer to the object is handed to C,
+    /// beware leaking a pin roots the object permanently.
+    pub fn pin(&self) {
+        unsafe { (*self.header()).set_pinned(true) };
+        self.frame.state().pinned_objects.insert(self.header());
+    }
+    /// the object is moved and collected as usual again
+    pub fn unpin(&self) {
+        self.frame.state().pinned_objects.remove(&self.header());
+        unsafe { (*self.header()).set_pinned(false) };
+    }
+    pub fn is_pinned(&self) -> bool {
+        unsafe { (*self.header()).is_pinned() }
+    }
+
+    /// the object allocated by `GCFrame::alloc_constructing` is fully initialized,
+    /// it's traced from now on, and recorded by the write barrier like a mutated one
+    pub fn finish_init(&self) {
+        let header = self.header();
+        unsafe { (*header).set_constructing(false) };
+        self.frame.state().write_barrier(header);
+    }
 }
 
-impl<T: Trace> AsMut<GCMut<T>> for GCBox<T> {
-    fn as_mut(&mut self) -> &mut GCMut<T> {
-        todo!()
+impl<T: Trace> Drop for GCBox<T> {
+    fn drop(&mut self) {
+        let state = self.frame.state();
+        if self.counted_root {
+            state.remove_root(self.value.header);
+        } else if state.minor_heap_roots.remove(&self.value.header).is_none() {
+            state.major_heap_roots.remove(&self.value.header);
+        }
+    }
+}
+
+impl<T: Trace> Clone for GCBox<T> {
+    /// another root of the same object, the object is kept alive until every clone is dropped
+    fn clone(&self) -> Self {
+        self.frame.state().add_root(self.value.header);
+        Self {
+            frame: self.frame,
+            value: self.value,
+            counted_root: true,
+        }
+    }
+}
+
+impl<T: Trace + fmt::Debug> fmt::Debug for GCBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // roots are never moved, so the cell always points to the object
+        fmt::Debug::fmt(unsafe { &*(self.value.data as *const T) }, f)
+    }
+}
+
+impl<T: Trace> GCBox<T> {
+    /// borrow the object mutably through the write barrier,
+    /// the mutation is recorded and the borrow released when the returned guard is dropped,
+    /// so `box.as_mut().field = value;` is recorded right after the statement.
+    /// it's not `AsMut<GCMut<T>>`, which could only lend a `GCMut` kept in the box,
+    /// so the barrier would not run until the next `as_mut` or the drop of the box.
+    /// panics if the object is mutably borrowed through another box, see `try_borrow_mut`
+    pub fn as_mut(&mut self) -> GCBoxMut<'_, T> {
+        self.try_borrow_mut()
+            .unwrap_or_else(|err| panic!("[FALTAL ERROR] {}", err))
+    }
+
+    /// `as_mut`, but returns `Err(BorrowError)` if another box of the object holds a guard now,
+    /// like `RefCell::try_borrow_mut`, so two `&mut` to the object never exist at the same time.
+    pub fn try_borrow_mut(&mut self) -> Result<GCBoxMut<'_, T>, BorrowError> {
+        Ok(GCBoxMut {
+            barrier: GCMut::try_new(self.frame.state(), self.value)?,
+            gc_box: core::marker::PhantomData,
+        })
     }
 }
 
+/// a mutable borrow of a rooted object from `GCBox::as_mut`, see `GCMut`
+pub struct GCBoxMut<'a, T: Trace> {
+    barrier: GCMut<T>,
+    /// the box keeps the object rooted while it's borrowed
+    gc_box: core::marker::PhantomData<&'a mut GCBox<T>>,
+}
+
+impl<T: Trace> GCBoxMut<'_, T> {
+    /// record the mutation now, same as dropping it
+    pub fn commit(self) {}
+
+    /// forget the mutation, see `GCMut::abort`
+    /// # Safety
+    /// same as `GCMut::abort`
+    pub unsafe fn abort(self) {
+        self.barrier.abort();
+    }
+}
+
+impl<T: Trace> Deref for GCBoxMut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.barrier
+    }
+}
+
+impl<T: Trace> DerefMut for GCBoxMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.barrier
+    }
+}
+
+/// the object is already mutably borrowed, or shared borrowed by a `GcRead` of `arena` while writing it.
+/// the header is an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowError {
+    pub header: usize,
+}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "object {:#x} is already borrowed", self.header)
+    }
+}
+
+impl core::error::Error for BorrowError {}
+
+/// a reference which doesn't keep the object alive,
+/// the collector could redirect it when the object is moved.
 pub struct GCRef<T: Trace> {
-    value: GCCell<T>,
+    pub(crate) value: UnsafeCell<GCCell<T>>,
+}
+
+impl<T: Trace> GCRef<T> {
+    pub(crate) fn new(cell: GCCell<T>) -> Self {
+        Self {
+            value: UnsafeCell::new(cell),
+        }
+    }
+    pub(crate) fn cell(&self) -> GCCell<T> {
+        unsafe { *self.value.get() }
+    }
+    /// the header could change after a gc, unless the object is rooted or pinned
+    pub fn header(&self) -> *mut GCHeader {
+        self.cell().header
+    }
+    /// forget the type, so references of different types could be stored together
+    pub fn erase(&self) -> AnyGCRef {
+        AnyGCRef::new(self.cell().header)
+    }
+    /// returns `None` if the object is not a `U`
+    pub fn downcast<U: Trace>(&self) -> Option<GCRef<U>> {
+        self.erase().downcast()
+    }
+    /// whether both reference the same object, like `Rc::ptr_eq`, the contents are not compared.
+    /// a reference to an object being evacuated equals one to its moved copy
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.cell().resolve().header == b.cell().resolve().header
+    }
+}
+
+/// another reference to the same object, the object is not copied.
+/// the object is kept alive as long as any traced clone reaches it.
+impl<T: Trace> Clone for GCRef<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.cell())
+    }
 }
 
 impl<T: Trace> Deref for GCRef<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        unsafe { &*(self.value.data as *mut T) }
+        unsafe { &*(self.cell().resolve().data as *mut T) }
+    }
+}
+
+impl<T: Trace + fmt::Debug> fmt::Debug for GCRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// a `GCRef` whose type is only known at runtime
+pub struct AnyGCRef {
+    pub(crate) header: UnsafeCell<*mut GCHeader>,
+}
+
+impl AnyGCRef {
+    pub(crate) fn new(header: *mut GCHeader) -> Self {
+        Self {
+            header: UnsafeCell::new(header),
+        }
+    }
+    /// the header could change after a gc, unless the object is rooted or pinned
+    pub fn header(&self) -> *mut GCHeader {
+        unsafe { *self.header.get() }
+    }
+    /// whether the object is a `U`
+    pub fn is<U: Trace>(&self) -> bool {
+        unsafe { (*self.header()).type_id() == TypeId::of::<U>() }
+    }
+    /// returns `None` if the object is not a `U`
+    pub fn downcast<U: Trace>(&self) -> Option<GCRef<U>> {
+        if !self.is::<U>() {
+            return None;
+        }
+        Some(GCRef::new(unsafe { GCCell::from_header(self.header()) }))
+    }
+}
+
+impl Clone for AnyGCRef {
+    fn clone(&self) -> Self {
+        Self::new(self.header())
+    }
+}
+
+impl fmt::Debug for AnyGCRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("AnyGCRef")
+            .field(unsafe { &*self.header() })
+            .finish()
+    }
+}
+
+impl GCSlot for *mut GCHeader {
+    fn header(&self) -> *mut GCHeader {
+        *self
+    }
+    unsafe fn redirect(&mut self, header: *mut GCHeader) {
+        *self = header;
+    }
+}
+
+/// a reference which doesn't keep the object alive, and knows when it's collected.
+/// the collector clears it after the object is found dead, and redirects it after the object is moved.
+pub struct GCWeak<T: Trace> {
+    /// null after the object is collected
+    slot: Arc<AtomicPtr<GCHeader>>,
+    phantom: core::marker::PhantomData<T>,
+}
+
+impl<T: Trace> GCWeak<T> {
+    pub(crate) fn new(state: &'static State, header: *mut GCHeader) -> Self {
+        let slot = Arc::new(AtomicPtr::new(header));
+        state.weak_slots.lock().unwrap().push(Arc::downgrade(&slot));
+        Self {
+            slot,
+            phantom: core::marker::PhantomData,
+        }
+    }
+    /// returns `None` if the object is collected
+    pub fn upgrade(&self) -> Option<GCRef<T>> {
+        let header = self.slot.load(Ordering::Acquire);
+        if header.is_null() {
+            None
+        } else {
+            Some(GCRef::new(unsafe { GCCell::from_header(header) }))
+        }
+    }
+}
+
+impl<T: Trace> Clone for GCWeak<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            phantom: core::marker::PhantomData,
+        }
+    }
+}
+
+/// the key and value of an ephemeron, null after the key is collected
+pub(crate) struct EphemeronSlot {
+    pub(crate) key: AtomicPtr<GCHeader>,
+    pub(crate) value: AtomicPtr<GCHeader>,
+}
+
+/// a key to value pair, where the value is only kept alive while the key is.
+/// neither is kept alive by the ephemeron itself: the collector traces the value only after finding the key live,
+/// so a value referencing its own key doesn't keep the pair alive, unlike storing both in an object.
+/// the pair is cleared after the key is found dead, and redirected after either is moved like `GCWeak`.
+/// it's the entry of side tables keyed by gc objects, which shouldn't keep the keys alive.
+///
+/// while a clone of it is alive, the value stays reachable through it even if it's stored in a dead object,
+/// until the object is dropped.
+pub struct Ephemeron<K: Trace, V: Trace> {
+    slot: Arc<EphemeronSlot>,
+    phantom: core::marker::PhantomData<(K, V)>,
+}
+
+impl<K: Trace, V: Trace> Ephemeron<K, V> {
+    pub fn new(key: &GCBox<K>, value: &GCBox<V>) -> Self {
+        let slot = Arc::new(EphemeronSlot {
+            key: AtomicPtr::new(key.header()),
+            value: AtomicPtr::new(value.header()),
+        });
+        key.frame
+            .state()
+            .ephemeron_slots
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&slot));
+        Self {
+            slot,
+            phantom: core::marker::PhantomData,
+        }
+    }
+    /// returns `None` if the key is collected
+    pub fn key(&self) -> Option<GCRef<K>> {
+        self.get().map(|(key, _)| key)
+    }
+    /// returns `None` if the key is collected
+    pub fn value(&self) -> Option<GCRef<V>> {
+        self.get().map(|(_, value)| value)
+    }
+    /// returns `None` if the key is collected
+    pub fn get(&self) -> Option<(GCRef<K>, GCRef<V>)> {
+        let key = self.slot.key.load(Ordering::Acquire);
+        let value = self.slot.value.load(Ordering::Acquire);
+        if key.is_null() || value.is_null() {
+            None
+        } else {
+            unsafe {
+                Some((
+                    GCRef::new(GCCell::from_header(key)),
+                    GCRef::new(GCCell::from_header(value)),
+                ))
+            }
+        }
+    }
+}
+
+impl<K: Trace, V: Trace> Clone for Ephemeron<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            phantom: core::marker::PhantomData,
+        }
+    }
+}
+
+/// a top level root independent of any frame, like the globals of a VM.
+/// it's a manual root of the state, see `State::add_root`, so it outlives the frame allocating the object.
+/// clones share the object, and it's unrooted when the last of them is dropped.
+///
+/// it's the handle to send an object to another thread, `GCBox` and `GCRef` are neither `Send` nor `Sync`,
+/// since they belong to a frame, or are only kept alive by one.
+/// a root is `Send + Sync` like an `Arc<T>` when `T` is:
+/// the state is `'static`, its manual roots are synchronized, and a rooted object is never moved,
+/// so the root could be dropped or dereferenced on any thread, even while gc is running.
+/// the object is still dropped by whichever thread sweeps it.
+pub struct GCRoot<T: Trace> {
+    state: &'static State,
+    value: GCCell<T>,
+}
+
+unsafe impl<T: Trace + Send + Sync> Send for GCRoot<T> {}
+unsafe impl<T: Trace + Send + Sync> Sync for GCRoot<T> {}
+
+impl<T: Trace> GCRoot<T> {
+    /// root the object currently referenced
+    pub fn new(reference: &GCRef<T>) -> Self {
+        let value = reference.cell();
+        let state = unsafe { (*value.header).frame().state() };
+        state.add_root(value.header);
+        Self { state, value }
+    }
+
+    /// rooted objects are never moved, so the header is stable while the root is alive
+    pub fn header(&self) -> *mut GCHeader {
+        self.value.header
+    }
+
+    /// a reference to store into other gc objects
+    pub fn to_ref(&self) -> GCRef<T> {
+        GCRef::new(self.value)
+    }
+}
+
+impl<T: Trace> From<&GCBox<T>> for GCRoot<T> {
+    fn from(value: &GCBox<T>) -> Self {
+        value.frame.state().add_root(value.header());
+        Self {
+            state: value.frame.state(),
+            value: value.value,
+        }
+    }
+}
+
+impl<T: Trace> Clone for GCRoot<T> {
+    fn clone(&self) -> Self {
+        self.state.add_root(self.value.header);
+        Self {
+            state: self.state,
+            value: self.value,
+        }
+    }
+}
+
+impl<T: Trace> Drop for GCRoot<T> {
+    fn drop(&mut self) {
+        self.state.remove_root(self.value.header);
+    }
+}
+
+impl<T: Trace> Deref for GCRoot<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*(self.value.data as *const T) }
+    }
+}
+
+impl<T: Trace + fmt::Debug> fmt::Debug for GCRoot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
     }
 }
 
-/// write barrier
+/// write barrier, the object is mutably borrowed until it's dropped
 pub struct GCMut<T: Trace> {
+    state: &'static State,
     prev_ptr: GCCell<T>,
     end_ptr: GCCell<T>,
+    /// `deref_mut` has been called
+    dirty: bool,
+}
+
+impl<T: Trace> GCMut<T> {
+    /// borrow the object, returns `Err(BorrowError)` if it's already borrowed
+    pub(crate) fn try_new(state: &'static State, cell: GCCell<T>) -> Result<Self, BorrowError> {
+        if !unsafe { (*cell.header).borrow_mut() } {
+            return Err(BorrowError {
+                header: cell.header as usize,
+            });
+        }
+        Ok(Self {
+            state,
+            prev_ptr: cell,
+            end_ptr: cell,
+            dirty: false,
+        })
+    }
+
+    /// record the mutation in the write barrier, same as dropping it
+    pub fn commit(self) {}
+
+    /// forget the mutation without recording it, so a rolled back speculative update
+    /// never adds the object to the remembered sets or the rescan list of major gc.
+    /// the memory written through it is not restored.
+    /// # Safety
+    /// every reference stored into the object through it should be restored to the previous one,
+    /// otherwise the gc may miss the newly referenced objects and free them while they are reachable.
+    pub unsafe fn abort(mut self) {
+        self.dirty = false;
+        self.end_ptr = self.prev_ptr;
+    }
 }
 
 impl<T: Trace> Deref for GCMut<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        unsafe { &*(self.end_ptr.data as *mut T) }
+        unsafe { &*(self.end_ptr.resolve().data as *mut T) }
     }
 }
 
 impl<T: Trace> DerefMut for GCMut<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *(self.end_ptr.data as *mut T) }
+        self.dirty = true;
+        unsafe { &mut *(self.end_ptr.resolve().data as *mut T) }
     }
 }
 
 impl<T: Trace> Drop for GCMut<T> {
     fn drop(&mut self) {
-        if self.prev_ptr != self.end_ptr {
-            //TODO: set header
+        if self.dirty || self.prev_ptr != self.end_ptr {
+            self.state.write_barrier(self.end_ptr.header);
         }
-        //TODO: if state is parallel scan, commit to rescan
-        unimplemented!()
+        unsafe { (*self.end_ptr.header).release_borrow() };
     }
 }
 
 /// a pointer to memory allocated by gc
 /// header should be next to data
-
 #[repr(C, align(8))]
 pub struct GCCell<T: Trace> {
     pub(crate) header: *mut GCHeader,
     pub(crate) data: *mut dyn Trace,
-    pub(crate) phantom: std::marker::PhantomData<T>,
+    pub(crate) phantom: core::marker::PhantomData<T>,
+}
+
+/// a reference to gc object which could be redirected after the object is moved
+pub trait GCSlot {
+    fn header(&self) -> *mut GCHeader;
+    /// # Safety
+    /// `header` should point to the moved copy of current object
+    unsafe fn redirect(&mut self, header: *mut GCHeader);
+}
+
+impl<T: Trace> GCSlot for GCCell<T> {
+    fn header(&self) -> *mut GCHeader {
+        self.header
+    }
+    unsafe fn redirect(&mut self, header: *mut GCHeader) {
+        *self = Self::from_header(header);
+    }
+}
+
+impl<T: Trace> GCCell<T> {
+    /// # Safety
+    /// `header` should point to a live cell of `T`
+    pub(crate) unsafe fn from_header(header: *mut GCHeader) -> Self {
+        Self {
+            header,
+            data: GCHeader::data(header) as *mut T,
+            phantom: core::marker::PhantomData,
+        }
+    }
+
+    /// read barrier, the cell of the moved copy if the object is being evacuated.
+    /// it's only compiled with `read-barrier`, otherwise the cell is returned as is.
+    #[inline(always)]
+    pub(crate) fn resolve(self) -> Self {
+        #[cfg(feature = "read-barrier")]
+        unsafe {
+            let forward = (*self.header).forward();
+            if !forward.is_null() {
+                return Self::from_header(forward);
+            }
+        }
+        self
+    }
 }
 
 #[repr(C, align(8))]
@@ -80,6 +656,13 @@ pub(crate) struct GCCellLayout<T: Trace> {
     pub(crate) data: T,
 }
 
+impl<T: Trace> Clone for GCCell<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T: Trace> Copy for GCCell<T> {}
+
 impl<T: Trace> PartialEq for GCCell<T> {
     fn eq(&self, other: &Self) -> bool {
         self.header == other.header
@@ -87,20 +670,722 @@ impl<T: Trace> PartialEq for GCCell<T> {
 }
 impl<T: Trace> Eq for GCCell<T> {}
 
+/// hashes the header address, consistent with `Eq`.
+/// the header changes when the object is moved, so keep it rooted or pinned while it's a key.
+impl<T: Trace> Hash for GCCell<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.header.hash(state);
+    }
+}
+
+/// tri-color marking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Color {
+    /// not reached yet, it's garbage if it's still white after marking
+    White = 0,
+    /// reached, but the children are not scanned yet
+    Grey = 1,
+    /// reached, and every child is at least grey
+    Black = 2,
+}
+
+impl Color {
+    fn from_u8(color: u8) -> Color {
+        match color {
+            0 => Color::White,
+            1 => Color::Grey,
+            2 => Color::Black,
+            _ => unreachable!("[FALTAL ERROR] invalid color {}", color),
+        }
+    }
+}
+
+/// type erased operations and layout of a gc cell, one static table shared by every object of a type
+pub(crate) struct CellFns {
+    pub(crate) type_id: fn() -> TypeId,
+    pub(crate) type_name: fn() -> &'static str,
+    /// type erased `Trace::trace` of the data next to the header
+    pub(crate) trace: unsafe fn(*mut GCHeader, &mut dyn Tracer),
+    /// type erased drop glue of the data
+    pub(crate) drop: unsafe fn(*mut GCHeader),
+    /// type erased copy of the whole cell, returns the new header
+    pub(crate) evacuate: unsafe fn(*mut GCHeader) -> *mut GCHeader,
+    /// type erased `Finalize::finalize`, only called if `GCHeader::finalizable` is set
+    pub(crate) finalize: unsafe fn(*mut GCHeader),
+    /// layout of the whole cell, it's allocated and freed with.
+    /// it's read from the cell, since the arrays of a type differ in length
+    pub(crate) layout: unsafe fn(*const GCHeader) -> Layout,
+    /// where the data starts from the header, the padding for the alignment of data is included
+    pub(crate) data_offset: usize,
+}
+
+/// the table of the objects of `T` without a finalizer
+pub(crate) const fn cell_fns<T: Trace>() -> &'static CellFns {
+    const {
+        &CellFns {
+            type_id: TypeId::of::<T>,
+            type_name: core::any::type_name::<T>,
+            trace: trace_erased::<T>,
+            drop: drop_erased::<T>,
+            evacuate: evacuate_erased::<T>,
+            finalize: finalize_noop,
+            layout: cell_layout::<T>,
+            data_offset: core::mem::offset_of!(GCCellLayout<T>, data),
+        }
+    }
+}
+
+/// the table of the objects of `T` registered by `GCBox::register_finalizer`
+const fn finalizing_fns<T: Finalize>() -> &'static CellFns {
+    const {
+        &CellFns {
+            finalize: finalize_erased::<T>,
+            ..*cell_fns::<T>()
+        }
+    }
+}
+
+/// which generation an object is stored in, ordered from the youngest to the oldest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum Generation {
+    /// allocated since the last minor gc, or survived fewer than `major_heap_liveness` ones
+    Minor = 0,
+    /// promoted by minor gc, only collected by major gc
+    Major = 1,
+    /// promoted by major gc, never collected
+    Imm = 2,
+}
+
+impl Generation {
+    fn from_u8(generation: u8) -> Generation {
+        match generation {
+            0 => Generation::Minor,
+            1 => Generation::Major,
+            2 => Generation::Imm,
+            _ => unreachable!("[FALTAL ERROR] invalid generation {}", generation),
+        }
+    }
+}
+
+/// `GCHeader::borrows` of a mutably borrowed object
+const MUT_BORROWED: usize = usize::MAX;
+
 #[repr(C, align(8))]
 pub struct GCHeader {
+    /// unique in the state, kept when the object is moved
+    id: u64,
     liveness: AtomicUsize,
-    marked: AtomicBool,
+    /// `Color` of tri-color marking
+    color: AtomicU8,
+    /// mutated through write barrier since last rescan
+    dirty: AtomicBool,
     pined: AtomicBool,
+    /// allocated by `GCFrame::alloc_constructing`, and `GCBox::finish_init` is not called yet
+    constructing: AtomicBool,
+    /// `MUT_BORROWED` while a `GCMut` of the object is alive,
+    /// otherwise the number of alive `GcRead`s of `arena`
+    borrows: AtomicUsize,
     generation: AtomicU8,
-    type_id: TypeId,
+    /// the new header after the object is moved, null if it's never moved
+    forward: AtomicPtr<GCHeader>,
+    /// the frame registering this object
+    frame: *const GCFrame,
+    /// the chunk this cell is carved from, null if it's allocated by the global allocator
+    chunk: *const Chunk,
+    /// the cell is freed, but the chunk holding it is not
+    freed: AtomicBool,
+    /// the type of the data, replaced by the one with the finalizer by `set_finalizer`
+    fns: &'static CellFns,
+    finalizable: AtomicBool,
+}
+
+impl fmt::Debug for GCHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GCHeader")
+            .field("liveness", &self.liveness.load(Ordering::Relaxed))
+            .field("color", &Color::from_u8(self.color.load(Ordering::Relaxed)))
+            .field("marked", &self.is_marked())
+            .field("pined", &self.pined.load(Ordering::Relaxed))
+            .field("generation", &self.generation())
+            .field("type_id", &self.type_id())
+            .finish()
+    }
 }
 
 impl GCHeader {
-    pub(crate) fn init<T: Trace>(&mut self) {
-        self.type_id = TypeId::of::<T>();
-        self.liveness.store(1, std::sync::atomic::Ordering::SeqCst);
-        self.marked
-            .store(false, std::sync::atomic::Ordering::SeqCst);
+    pub(crate) fn init<T: Trace>(&mut self, frame: &'static GCFrame) {
+        self.init_erased(frame, cell_fns::<T>());
+    }
+
+    /// for cells not laid out as `GCCellLayout`, like arrays
+    pub(crate) fn init_erased(&mut self, frame: &'static GCFrame, fns: &'static CellFns) {
+        self.id = frame.state().next_object_id.fetch_add(1, Ordering::Relaxed);
+        self.frame = frame;
+        self.chunk = core::ptr::null();
+        self.fns = fns;
+        self.finalizable.store(false, Ordering::SeqCst);
+        self.liveness.store(1, Ordering::SeqCst);
+        self.color.store(Color::White as u8, Ordering::SeqCst);
+        self.dirty.store(false, Ordering::SeqCst);
+        self.pined.store(false, Ordering::SeqCst);
+        self.constructing.store(false, Ordering::SeqCst);
+        self.borrows.store(0, Ordering::SeqCst);
+        self.generation.store(Generation::Minor as u8, Ordering::SeqCst);
+        self.forward.store(core::ptr::null_mut(), Ordering::SeqCst);
+        self.freed.store(false, Ordering::SeqCst);
+    }
+
+    pub fn color(&self) -> Color {
+        Color::from_u8(self.color.load(Ordering::Acquire))
+    }
+    pub(crate) fn set_color(&self, color: Color) {
+        self.color.store(color as u8, Ordering::Release);
+    }
+
+    pub(crate) fn frame(&self) -> &'static GCFrame {
+        unsafe { &*self.frame }
+    }
+    pub(crate) fn set_frame(&mut self, frame: &'static GCFrame) {
+        self.frame = frame;
+    }
+
+    pub(crate) fn set_chunk(&mut self, chunk: *const Chunk) {
+        self.chunk = chunk;
+    }
+    /// the bump chunk this cell is carved from, null for the ones from the allocator directly
+    pub(crate) fn chunk(&self) -> *const Chunk {
+        self.chunk
+    }
+    #[cfg(feature = "std")]
+    pub(crate) fn is_freed(&self) -> bool {
+        self.freed.load(Ordering::Acquire)
+    }
+    pub(crate) fn set_freed(&self) {
+        self.freed.store(true, Ordering::Release);
+    }
+
+    /// where the object is moved to, null if it's not moved.
+    /// readers of a moved object should follow it to the new header.
+    pub fn forward(&self) -> *mut GCHeader {
+        self.forward.load(Ordering::Acquire)
+    }
+    pub(crate) fn set_forward(&self, to: *mut GCHeader) {
+        self.forward.store(to, Ordering::Release);
+    }
+    pub fn is_forwarded(&self) -> bool {
+        !self.forward().is_null()
+    }
+
+    /// white to grey, returns true if this call marked the object
+    pub(crate) fn mark(&self) -> bool {
+        self.color
+            .compare_exchange(
+                Color::White as u8,
+                Color::Grey as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+    pub(crate) fn unmark(&self) {
+        self.set_color(Color::White);
+    }
+    /// grey or black
+    pub(crate) fn is_marked(&self) -> bool {
+        self.color() != Color::White
+    }
+
+    pub(crate) fn is_pinned(&self) -> bool {
+        self.pined.load(Ordering::Acquire)
+    }
+
+    /// returns true if this call borrowed the object, it fails while it's borrowed in any way
+    pub(crate) fn borrow_mut(&self) -> bool {
+        self.borrows
+            .compare_exchange(0, MUT_BORROWED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+    pub(crate) fn release_borrow(&self) {
+        self.borrows.store(0, Ordering::Release);
+    }
+    /// returns true if this call borrowed the object shared, it fails while it's mutably borrowed
+    #[cfg(feature = "arena")]
+    pub(crate) fn borrow_shared(&self) -> bool {
+        self.borrows
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |borrows| {
+                (borrows < MUT_BORROWED - 1).then(|| borrows + 1)
+            })
+            .is_ok()
+    }
+    #[cfg(feature = "arena")]
+    pub(crate) fn release_shared(&self) {
+        self.borrows.fetch_sub(1, Ordering::Release);
+    }
+    /// whether the object is mutably borrowed
+    pub fn is_borrowed(&self) -> bool {
+        self.borrows.load(Ordering::Acquire) == MUT_BORROWED
+    }
+    pub(crate) fn set_pinned(&self, pinned: bool) {
+        self.pined.store(pinned, Ordering::Release);
+    }
+
+    pub(crate) fn set_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+    /// returns whether the object was dirty, and clears it
+    pub(crate) fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+
+    pub(crate) fn set_constructing(&self, constructing: bool) {
+        self.constructing.store(constructing, Ordering::Release);
+    }
+
+    /// report the objects referenced by this one to the tracer,
+    /// nothing is reported for an object under construction
+    /// # Safety
+    /// `this` should point to a live gc cell
+    pub(crate) unsafe fn trace(this: *mut GCHeader, tracer: &mut dyn Tracer) {
+        if (*this).constructing.load(Ordering::Acquire) {
+            return;
+        }
+        ((*this).fns.trace)(this, tracer);
+        let offsets = (*this)
+            .frame()
+            .state()
+            .type_registry
+            .offsets((*this).type_id());
+        if let Some(offsets) = offsets {
+            trace_offsets(offsets, GCHeader::data(this), tracer);
+        }
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+    pub(crate) fn type_id(&self) -> TypeId {
+        (self.fns.type_id)()
+    }
+    pub(crate) fn type_name(&self) -> &'static str {
+        (self.fns.type_name)()
+    }
+    pub(crate) fn liveness(&self) -> usize {
+        self.liveness.load(Ordering::Acquire)
+    }
+    /// collections survived, minor gcs in minor heap and major gcs in major heap,
+    /// compared with `major_heap_liveness` and `imm_liveness` for promotion
+    pub fn survived(&self) -> usize {
+        self.liveness() - 1
+    }
+    /// it's changed by the collector when the object is promoted
+    pub fn generation(&self) -> Generation {
+        Generation::from_u8(self.generation.load(Ordering::Acquire))
+    }
+    pub(crate) fn set_generation(&self, generation: Generation) {
+        self.generation.store(generation as u8, Ordering::Release);
+    }
+    /// imm objects are never marked or swept
+    pub(crate) fn is_imm(&self) -> bool {
+        self.generation() == Generation::Imm
+    }
+
+    /// start of the data
+    /// # Safety
+    /// `this` should point to an initialized header
+    pub(crate) unsafe fn data(this: *mut GCHeader) -> *mut u8 {
+        (this as *mut u8).add((*this).fns.data_offset)
+    }
+
+    /// size of the whole gc cell in bytes, which is accounted in the heap sizes
+    pub fn size(&self) -> usize {
+        self.layout().size()
+    }
+    fn layout(&self) -> Layout {
+        unsafe { (self.fns.layout)(self) }
+    }
+
+    /// `fns` is the table of the same type, with the finalizer to run
+    pub(crate) fn set_finalizer(&mut self, fns: &'static CellFns) {
+        self.fns = fns;
+        self.finalizable.store(true, Ordering::Release);
+    }
+    /// run the finalizer if it's not run yet, a panic in it is caught
+    /// # Safety
+    /// `this` should point to a dead gc cell which is not freed yet
+    pub(crate) unsafe fn finalize(this: *mut GCHeader) {
+        if (*this).finalizable.swap(false, Ordering::AcqRel) {
+            let finalize = (*this).fns.finalize;
+            #[cfg(feature = "std")]
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| finalize(this)));
+            #[cfg(not(feature = "std"))]
+            finalize(this);
+        }
+    }
+
+    /// increase the survived round and returns the new liveness
+    pub(crate) fn survive(&self) -> usize {
+        self.liveness.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// copy the object to a new cell, and forward this one to the copy.
+    /// returns the new header.
+    /// # Safety
+    /// the old cell should only be freed after every reference to it is redirected
+    pub(crate) unsafe fn evacuate(this: *mut GCHeader) -> *mut GCHeader {
+        let copy = ((*this).fns.evacuate)(this);
+        (*copy).set_chunk(core::ptr::null());
+        (*this).frame().reregister(this, copy);
+        copy
+    }
+
+    /// run the drop glue of the data
+    /// # Safety
+    /// `this` should point to a dead gc cell, which is freed right after without being read
+    pub(crate) unsafe fn drop_data(this: *mut GCHeader) {
+        ((*this).fns.drop)(this)
+    }
+
+    /// free the whole gc cell and returns the freed size,
+    /// the data is not dropped, since it may be moved to another cell
+    /// # Safety
+    /// `this` should point to a live gc cell and should never be used afterwards
+    pub(crate) unsafe fn free(this: *mut GCHeader) -> usize {
+        let chunk = (*this).chunk;
+        if chunk.is_null() {
+            let layout = (*this).layout();
+            let frame = (*this).frame();
+            frame.unregister(this);
+            frame.state().allocator.dealloc(this as *mut u8, layout);
+            layout.size()
+        } else {
+            let size = (*this).size();
+            Chunk::free_cell(chunk, this);
+            size
+        }
+    }
+
+    /// free many gc cells at once like `free`, and returns the freed size.
+    /// the cells of one chunk release it together, the others are handed back to the allocator
+    /// grouped by layout, through `Allocator::dealloc_batch`.
+    /// # Safety
+    /// like `free` for every header, they should be distinct and belong to the same state
+    pub(crate) unsafe fn free_batch(headers: &[*mut GCHeader]) -> usize {
+        let Some(first) = headers.first() else {
+            return 0;
+        };
+        let allocator = &*(**first).frame().state().allocator;
+        let mut chunks: HashMap<*const Chunk, Vec<*mut GCHeader>> = HashMap::new();
+        let mut layouts: HashMap<(usize, usize), Vec<*mut u8>> = HashMap::new();
+        let mut freed = 0;
+        for &header in headers {
+            let layout = (*header).layout();
+            freed += layout.size();
+            let chunk = (*header).chunk;
+            if chunk.is_null() {
+                (*header).frame().unregister(header);
+                layouts
+                    .entry((layout.size(), layout.align()))
+                    .or_default()
+                    .push(header as *mut u8);
+            } else {
+                chunks.entry(chunk).or_default().push(header);
+            }
+        }
+        for (chunk, cells) in chunks {
+            Chunk::free_cells(chunk, &cells);
+        }
+        for ((size, align), cells) in layouts {
+            let layout = Layout::from_size_align_unchecked(size, align);
+            allocator.dealloc_batch(&cells, layout);
+        }
+        freed
+    }
+}
+
+unsafe fn trace_erased<T: Trace>(header: *mut GCHeader, tracer: &mut dyn Tracer) {
+    let data = &*(GCHeader::data(header) as *const T);
+    data.trace(tracer);
+}
+
+unsafe fn finalize_erased<T: Finalize>(header: *mut GCHeader) {
+    let data = &mut *(GCHeader::data(header) as *mut T);
+    data.finalize();
+}
+
+unsafe fn drop_erased<T: Trace>(header: *mut GCHeader) {
+    core::ptr::drop_in_place(GCHeader::data(header) as *mut T);
+}
+
+pub(crate) unsafe fn finalize_noop(_header: *mut GCHeader) {}
+
+unsafe fn cell_layout<T: Trace>(_header: *const GCHeader) -> Layout {
+    Layout::new::<GCCellLayout<T>>()
+}
+
+unsafe fn evacuate_erased<T: Trace>(header: *mut GCHeader) -> *mut GCHeader {
+    let layout = Layout::new::<GCCellLayout<T>>();
+    let copy = (*header).frame().state().allocator.alloc(layout) as *mut GCHeader;
+    if copy.is_null() {
+        handle_alloc_error(layout);
+    }
+    core::ptr::copy_nonoverlapping(header as *const u8, copy as *mut u8, layout.size());
+    (*copy).set_forward(core::ptr::null_mut());
+    (*header).set_forward(copy);
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::{Ephemeron, GCBox, GCRef, Generation};
+    use crate::state::{GCStage, MarkProgress};
+    use crate::test_util::{env, full_gc, get, is_tracked, Node};
+    use crate::trace::{Trace, Tracer};
+
+    #[test]
+    fn as_mut_records_old_to_young_before_next_borrow() {
+        let (state, frame) = env();
+        let mut old = frame.alloc_in(Node(1, vec![]), Generation::Major);
+        let young = frame.alloc(Node(2, vec![]));
+        old.as_mut().1.push(young.as_ref());
+        drop(young);
+        state.minor_heap_gen_gc();
+        assert!(is_tracked(state, get(&old).1[0].header()));
+        assert_eq!(get(&old).1[0].0, 2);
+        // the second borrow is recorded too
+        let newer = frame.alloc(Node(3, vec![]));
+        old.as_mut().1.push(newer.as_ref());
+        drop(newer);
+        state.minor_heap_gen_gc();
+        assert_eq!(get(&old).1[1].0, 3);
+    }
+
+    #[test]
+    fn as_mut_during_incremental_mark_is_rescanned() {
+        let (state, frame) = env();
+        let mut black = frame.alloc_in(Node(1, vec![]), Generation::Major);
+        let white = frame.alloc_in(Node(2, vec![]), Generation::Major);
+        let mut grey = frame.alloc_in(Node(3, vec![white.as_ref()]), Generation::Major);
+        let white_header = white.header();
+        drop(white);
+        state.mark_step(1);
+        black.as_mut().1.push(grey.as_ref().1[0].clone());
+        grey.as_mut().1.clear();
+        while state.mark_step(1) != MarkProgress::Complete {}
+        assert!(is_tracked(state, white_header));
+        assert_eq!(get(&black).1[0].0, 2);
+        drop(grey);
+        full_gc(state);
+        assert_eq!(get(&black).1[0].0, 2);
+    }
+
+    #[test]
+    fn cloned_refs_share_the_object() {
+        let (state, frame) = env();
+        let child = frame.alloc(Node(7, vec![]));
+        let reference = child.as_ref();
+        let parent = frame.alloc(Node(0, vec![reference.clone(), reference.clone()]));
+        let erased = reference.erase();
+        assert_eq!(erased.clone().header(), erased.header());
+        drop(child);
+        full_gc(state);
+        let children = &get(&parent).1;
+        assert!(GCRef::ptr_eq(&children[0], &children[1]));
+        assert_eq!(children[1].0, 7);
+        assert!(is_tracked(state, children[0].header()));
+    }
+
+    #[test]
+    fn mutation_during_parallel_scan_is_rescanned() {
+        let (state, frame) = env();
+        let mut node = frame.alloc_in(Node(1, vec![]), Generation::Major);
+        let untouched = frame.alloc_in(Node(2, vec![]), Generation::Major);
+        // only the initial scan, the marking is paused in parallel scan
+        state.mark_step(0);
+        assert_eq!(state.stage(), GCStage::ParallelScan);
+        state.major_heap_rescan_list.clear();
+        node.as_mut().0 = 3;
+        assert!(state.major_heap_rescan_list.contains(&node.header()));
+        assert!(!state.major_heap_rescan_list.contains(&untouched.header()));
+        while state.mark_step(usize::MAX) != MarkProgress::Complete {}
+        assert_eq!(get(&node).0, 3);
+    }
+
+    #[test]
+    fn clones_borrow_one_after_another() {
+        let (_state, frame) = env();
+        let mut a = frame.alloc(Node(1, vec![]));
+        a.as_mut().0 = 2;
+        let mut b = a.clone();
+        b.as_mut().0 += 1;
+        assert_eq!(get(&a).0, 3);
+        {
+            let _borrow = a.as_mut();
+            assert!(unsafe { (*b.header()).is_borrowed() });
+            assert_eq!(
+                b.try_borrow_mut().err().map(|err| err.header),
+                Some(b.header() as usize)
+            );
+        }
+        assert!(b.try_borrow_mut().is_ok());
+        assert!(!unsafe { (*a.header()).is_borrowed() });
+    }
+
+    #[test]
+    fn over_aligned_objects_stay_aligned_when_moved() {
+        #[repr(align(256))]
+        struct Page(u64);
+
+        impl Trace for Page {
+            fn trace(&self, _: &mut dyn Tracer) {}
+        }
+
+        struct Pages(Vec<GCRef<Page>>);
+
+        impl Trace for Pages {
+            fn trace(&self, tracer: &mut dyn Tracer) {
+                self.0.trace(tracer);
+            }
+        }
+
+        let (state, frame) = env();
+        let pages: Vec<_> = (0..10).map(|i| frame.alloc(Page(i))).collect();
+        let holder = frame.alloc(Pages(pages.iter().map(|page| page.as_ref()).collect()));
+        drop(pages);
+        let array = frame.allocate_array(3, |i| Page(i as u64));
+        for round in 0..3 {
+            for (i, page) in get(&holder).0.iter().enumerate() {
+                assert_eq!(&**page as *const Page as usize % 256, 0, "round {}", round);
+                assert_eq!(page.0, i as u64);
+            }
+            for (i, page) in array.iter().enumerate() {
+                assert_eq!(page as *const Page as usize % 256, 0);
+                assert_eq!(page.0, i as u64);
+            }
+            full_gc(state);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn equal_cells_hash_the_same() {
+        use std::collections::HashSet;
+
+        let (_state, frame) = env();
+        let a = frame.alloc(Node(1, vec![]));
+        let b = frame.alloc(Node(1, vec![]));
+        let mut set = HashSet::new();
+        set.insert(a.value);
+        assert!(set.contains(&a.clone().value));
+        assert!(!set.contains(&b.value));
+        set.insert(a.value);
+        set.insert(b.value);
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "read-barrier")]
+    fn reads_during_evacuation_see_the_moved_copy() {
+        use super::GCHeader;
+
+        let (_state, frame) = env();
+        let a = frame.alloc(Node(1, vec![]));
+        let reference = a.as_ref();
+        let copy = unsafe { GCHeader::evacuate(a.header()) };
+        unsafe { (*(GCHeader::data(copy) as *mut Node)).0 = 7 };
+        assert_eq!(reference.0, 7);
+        assert!(GCRef::ptr_eq(&reference, &unsafe {
+            GCRef::new(super::GCCell::from_header(copy))
+        }));
+        // the old cell is only read without the barrier
+        assert_eq!(get(&a).0, 1);
+    }
+
+    #[test]
+    fn reading_through_as_ref_skips_the_write_barrier() {
+        let (state, frame) = env();
+        let boxes: Vec<_> = (0..20)
+            .map(|i| frame.alloc_in(Node(i, vec![]), Generation::Major))
+            .collect();
+        state.mark_step(0);
+        assert_eq!(state.stage(), GCStage::ParallelScan);
+        state.major_heap_rescan_list.clear();
+        let sum: u64 = boxes.iter().map(|gc_box| gc_box.as_ref().0).sum();
+        assert_eq!(sum, 190);
+        assert!(state.major_heap_rescan_list.is_empty());
+        while state.mark_step(usize::MAX) != MarkProgress::Complete {}
+        assert_eq!(boxes[3].as_ref().0, 3);
+    }
+
+    #[test]
+    fn ephemeron_value_lives_only_while_its_key_does() {
+        for major in [false, true] {
+            let (state, frame) = env();
+            let collect = || {
+                if major {
+                    state.collect();
+                } else {
+                    state.minor_heap_gen_gc();
+                }
+            };
+            // the value referencing the key doesn't keep the key alive
+            let key = frame.alloc(Node(1, vec![]));
+            let value = frame.alloc(Node(2, vec![key.as_ref()]));
+            let ephemeron = Ephemeron::new(&key, &value);
+            drop(key);
+            drop(value);
+            collect();
+            assert!(ephemeron.get().is_none(), "major {}", major);
+            assert!(state.minor_heap_gen.is_empty() && state.major_heap_gen.is_empty());
+
+            let key = frame.alloc(Node(3, vec![]));
+            let value = frame.alloc(Node(4, vec![]));
+            let child = frame.alloc(Node(5, vec![]));
+            let chained = frame.alloc(Node(6, vec![child.as_ref()]));
+            drop(child);
+            let ephemeron = Ephemeron::new(&key, &value);
+            // the value of one is the key of the other
+            let next = Ephemeron::new(&value, &chained);
+            drop(value);
+            drop(chained);
+            for _ in 0..3 {
+                collect();
+                let (live_key, live_value) = ephemeron.get().unwrap();
+                assert_eq!((live_key.0, live_value.0), (3, 4));
+                let live_chained = next.value().unwrap();
+                assert_eq!((live_chained.0, live_chained.1[0].0), (6, 5));
+            }
+            drop(key);
+            full_gc(state);
+            assert!(ephemeron.get().is_none());
+            assert!(next.get().is_none());
+            assert!(state.minor_heap_gen.is_empty() && state.major_heap_gen.is_empty());
+        }
+    }
+
+    #[test]
+    fn every_box_clone_roots_the_object() {
+        let (state, frame) = env();
+        let a = frame.alloc(Node(7, vec![]));
+        let header = a.header();
+        let b = a.clone();
+        let c = b.clone();
+        assert!(GCBox::ptr_eq(&a, &c));
+        drop(a);
+        full_gc(state);
+        assert_eq!(get(&b).0, 7);
+        drop(b);
+        full_gc(state);
+        // never moved while it's rooted
+        assert_eq!(c.header(), header);
+        assert_eq!(get(&c).0, 7);
+        drop(c);
+        full_gc(state);
+        assert!(!is_tracked(state, header));
+        assert!(state.manual_roots.get(&header).is_none());
     }
 }
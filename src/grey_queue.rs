@@ -0,0 +1,163 @@
+//! work queues of grey objects for parallel marking.
+//! every gc worker pops from its own deque, and steals from the shared injector or the other workers
+//! when it runs dry, so the marking is balanced without splitting tasks by hand.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
+use super::gc_box::GCHeader;
+use super::sync;
+
+/// a grey object handed between gc workers
+pub(crate) struct Grey(*mut GCHeader);
+unsafe impl Send for Grey {}
+
+/// grey objects shared by the workers of one marking
+pub(crate) struct GreyQueue {
+    injector: Injector<Grey>,
+    stealers: Vec<Stealer<Grey>>,
+    /// started workers which may still push grey objects,
+    /// the marking is finished once it drops to zero with every queue empty.
+    /// a worker not started yet holds no grey object, so it's not counted
+    active: AtomicUsize,
+}
+
+/// the deque of one gc worker
+pub(crate) struct GreyWorker<'a> {
+    queue: &'a GreyQueue,
+    index: usize,
+    local: Worker<Grey>,
+}
+
+impl GreyQueue {
+    /// returns the queue, and the deques to be moved into `workers` gc workers
+    pub(crate) fn new(workers: usize) -> (Self, Vec<Worker<Grey>>) {
+        let locals: Vec<_> = (0..workers.max(1)).map(|_| Worker::new_lifo()).collect();
+        let queue = Self {
+            injector: Injector::new(),
+            stealers: locals.iter().map(|local| local.stealer()).collect(),
+            active: AtomicUsize::new(0),
+        };
+        (queue, locals)
+    }
+
+    /// share a grey object with every worker
+    pub(crate) fn push(&self, header: *mut GCHeader) {
+        self.injector.push(Grey(header));
+    }
+
+    /// take a grey object from the injector, or from any worker but `index`
+    pub(crate) fn steal(&self, index: usize) -> Option<*mut GCHeader> {
+        loop {
+            let mut retry = false;
+            let stolen = core::iter::once(self.injector.steal())
+                .chain(
+                    self.stealers
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| *i != index)
+                        .map(|(_, stealer)| stealer.steal()),
+                )
+                .find_map(|steal| match steal {
+                    Steal::Success(grey) => Some(grey.0),
+                    Steal::Retry => {
+                        retry = true;
+                        None
+                    }
+                    Steal::Empty => None,
+                });
+            if stolen.is_some() || !retry {
+                return stolen;
+            }
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.injector.is_empty() && self.stealers.iter().all(|stealer| stealer.is_empty())
+    }
+
+    /// start worker `index` on its deque, `local` should be the one returned by `new` at `index`
+    pub(crate) fn worker(&self, index: usize, local: Worker<Grey>) -> GreyWorker<'_> {
+        self.active.fetch_add(1, Ordering::AcqRel);
+        GreyWorker {
+            queue: self,
+            index,
+            local,
+        }
+    }
+}
+
+impl GreyWorker<'_> {
+    pub(crate) fn push(&self, header: *mut GCHeader) {
+        self.local.push(Grey(header));
+    }
+
+    /// the next grey object of this worker, stolen from the others if it has none,
+    /// returns `None` only after every worker runs out of grey objects
+    pub(crate) fn pop(&self) -> Option<*mut GCHeader> {
+        if let Some(grey) = self.local.pop() {
+            return Some(grey.0);
+        }
+        loop {
+            if let Some(header) = self.queue.steal(self.index) {
+                return Some(header);
+            }
+            // idle workers never push, so nothing could be pushed once every worker is idle
+            self.queue.active.fetch_sub(1, Ordering::AcqRel);
+            loop {
+                if !self.queue.is_empty() {
+                    self.queue.active.fetch_add(1, Ordering::AcqRel);
+                    break;
+                }
+                if self.queue.active.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+                sync::yield_now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::GreyQueue;
+    use crate::gc_box::GCHeader;
+
+    /// the objects form a binary tree of `NODES`, they are never dereferenced
+    const NODES: usize = 10_000;
+
+    fn grey(node: usize) -> *mut GCHeader {
+        (node + 1) as *mut GCHeader
+    }
+
+    #[test]
+    fn every_grey_object_is_popped_once_before_the_workers_stop() {
+        let (queue, locals) = GreyQueue::new(4);
+        let popped: [AtomicUsize; NODES] = core::array::from_fn(|_| AtomicUsize::new(0));
+        queue.push(grey(0));
+        std::thread::scope(|scope| {
+            for (index, local) in locals.into_iter().enumerate() {
+                let (queue, popped) = (&queue, &popped);
+                scope.spawn(move || {
+                    let worker = queue.worker(index, local);
+                    while let Some(header) = worker.pop() {
+                        let node = header as usize - 1;
+                        popped[node].fetch_add(1, Ordering::Relaxed);
+                        for child in [2 * node + 1, 2 * node + 2] {
+                            if child < NODES {
+                                worker.push(grey(child));
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert!(queue.is_empty());
+        assert!(popped
+            .iter()
+            .all(|count| count.load(Ordering::Relaxed) == 1));
+    }
+}
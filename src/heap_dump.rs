@@ -0,0 +1,373 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::any::TypeId;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+use super::gc_box::{Color, GCHeader, Generation};
+use super::state::State;
+use super::sync::{HashMap, HashSet};
+
+/// an object on the heap when the dump is taken,
+/// headers are recorded as addresses, which are only meaningful inside the same dump,
+/// since objects could be moved, and the address of a freed one could be reused.
+/// use `id` to identify an object across dumps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectDump {
+    /// unique in the state, never reused
+    pub id: u64,
+    pub header: usize,
+    pub type_id: TypeId,
+    /// see `State::register_type_name`
+    pub type_name: &'static str,
+    pub generation: Generation,
+    pub liveness: usize,
+    /// size of the whole gc cell
+    pub size: usize,
+    /// headers reported by `Trace`
+    pub references: Vec<usize>,
+}
+
+/// every tracked object and root, see `State::dump_heap`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapDump {
+    pub objects: Vec<ObjectDump>,
+    /// rooted, manually rooted and pinned headers
+    pub roots: Vec<usize>,
+}
+
+/// live objects of one type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeUsage {
+    pub type_id: TypeId,
+    pub type_name: &'static str,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// how the objects of one type changed between two dumps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeGrowth {
+    pub type_id: TypeId,
+    pub type_name: &'static str,
+    pub earlier_count: usize,
+    pub later_count: usize,
+    pub earlier_bytes: usize,
+    pub later_bytes: usize,
+}
+
+impl TypeGrowth {
+    pub fn count_growth(&self) -> isize {
+        self.later_count as isize - self.earlier_count as isize
+    }
+    pub fn bytes_growth(&self) -> isize {
+        self.later_bytes as isize - self.earlier_bytes as isize
+    }
+}
+
+/// see `HeapDump::diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LeakReport {
+    /// types grew in count or bytes, the largest bytes growth first
+    pub growth: Vec<TypeGrowth>,
+    /// objects in both dumps, but unreachable from roots in the later one,
+    /// which means they survived at least one chance to be collected
+    pub uncollected: Vec<ObjectDump>,
+}
+
+impl LeakReport {
+    pub fn sort_by_bytes_growth(&mut self) {
+        self.growth
+            .sort_by_key(|g| core::cmp::Reverse(g.bytes_growth()));
+    }
+    pub fn sort_by_count_growth(&mut self) {
+        self.growth
+            .sort_by_key(|g| core::cmp::Reverse(g.count_growth()));
+    }
+}
+
+impl HeapDump {
+    /// compare with an earlier dump of the same state
+    pub fn diff(&self, earlier: &HeapDump) -> LeakReport {
+        let mut growth: HashMap<TypeId, TypeGrowth> = HashMap::new();
+        for (dump, is_later) in [(earlier, false), (self, true)] {
+            for usage in dump.histogram() {
+                let entry = growth.entry(usage.type_id).or_insert(TypeGrowth {
+                    type_id: usage.type_id,
+                    type_name: usage.type_name,
+                    earlier_count: 0,
+                    later_count: 0,
+                    earlier_bytes: 0,
+                    later_bytes: 0,
+                });
+                if is_later {
+                    entry.later_count = usage.count;
+                    entry.later_bytes = usage.bytes;
+                } else {
+                    entry.earlier_count = usage.count;
+                    entry.earlier_bytes = usage.bytes;
+                }
+            }
+        }
+        let earlier_ids: HashSet<u64> = earlier.objects.iter().map(|o| o.id).collect();
+        let reachable = self.reachable();
+        let mut report = LeakReport {
+            growth: growth
+                .into_values()
+                .filter(|g| g.count_growth() > 0 || g.bytes_growth() > 0)
+                .collect(),
+            uncollected: self
+                .objects
+                .iter()
+                .filter(|o| earlier_ids.contains(&o.id) && !reachable.contains(&o.header))
+                .cloned()
+                .collect(),
+        };
+        report.sort_by_bytes_growth();
+        report
+    }
+
+    /// headers reachable from roots
+    fn reachable(&self) -> HashSet<usize> {
+        let objects: HashMap<usize, &ObjectDump> =
+            self.objects.iter().map(|o| (o.header, o)).collect();
+        let mut reachable = HashSet::new();
+        let mut grey = self.roots.clone();
+        while let Some(header) = grey.pop() {
+            if !reachable.insert(header) {
+                continue;
+            }
+            if let Some(object) = objects.get(&header) {
+                grey.extend(object.references.iter().copied());
+            }
+        }
+        reachable
+    }
+
+    /// the object graph in Graphviz dot format, roots are drawn in bold
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph heap {\n");
+        for object in self.objects.iter() {
+            let style = if self.roots.contains(&object.header) {
+                ", style=bold"
+            } else {
+                ""
+            };
+            let _ = writeln!(
+                dot,
+                "    \"{:#x}\" [label=\"{}\\n{} bytes, gen {}, liveness {}\"{}];",
+                object.header,
+                object.type_name.replace('"', "\\\""),
+                object.size,
+                object.generation as u8,
+                object.liveness,
+                style
+            );
+            for reference in object.references.iter() {
+                let _ = writeln!(dot, "    \"{:#x}\" -> \"{:#x}\";", object.header, reference);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// live bytes grouped by type, the largest first
+    pub fn histogram(&self) -> Vec<TypeUsage> {
+        let mut usages: HashMap<TypeId, TypeUsage> = HashMap::new();
+        for object in self.objects.iter() {
+            let usage = usages.entry(object.type_id).or_insert(TypeUsage {
+                type_id: object.type_id,
+                type_name: object.type_name,
+                count: 0,
+                bytes: 0,
+            });
+            usage.count += 1;
+            usage.bytes += object.size;
+        }
+        let mut usages: Vec<_> = usages.into_values().collect();
+        usages.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.type_name.cmp(b.type_name)));
+        usages
+    }
+}
+
+impl State {
+    /// name the type in heap dumps, instead of `core::any::type_name`,
+    /// which is only meant for diagnostics and could change between compiler versions.
+    /// registering a type again replaces the name.
+    pub fn register_type_name<T: ?Sized + 'static>(&self, name: &'static str) {
+        self.type_names.insert(TypeId::of::<T>(), name);
+    }
+
+    fn registered_type_name(&self, object: &GCHeader) -> &'static str {
+        match self.type_names.get(&object.type_id()) {
+            Some(name) => *name,
+            None => object.type_name(),
+        }
+    }
+
+    /// record every object tracked by the collector with the world stopped and minor gc held off,
+    /// useful for finding out what keeps the memory from being reclaimed.
+    pub fn dump_heap(&self) -> HeapDump {
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.stop_world(false);
+        let mut dump = HeapDump::default();
+        for header in self
+            .minor_heap_gen
+            .iter()
+            .chain(self.major_heap_gen.iter())
+            .chain(self.imm_gen.iter())
+        {
+            let header = *header;
+            let mut references: Vec<*mut GCHeader> = Vec::new();
+            unsafe { GCHeader::trace(header, &mut references) };
+            let object = unsafe { &*header };
+            dump.objects.push(ObjectDump {
+                id: object.id(),
+                header: header as usize,
+                type_id: object.type_id(),
+                type_name: self.registered_type_name(object),
+                generation: object.generation(),
+                liveness: object.liveness(),
+                size: object.size(),
+                references: references.into_iter().map(|r| r as usize).collect(),
+            });
+        }
+        dump.roots = self
+            .minor_heap_roots
+            .iter()
+            .chain(self.major_heap_roots.iter())
+            .chain(self.pinned_objects.iter())
+            .chain(self.conservative_roots.iter())
+            .map(|header| *header as usize)
+            .chain(self.manual_roots.iter().map(|root| *root.key() as usize))
+            .collect();
+        dump.roots.sort_unstable();
+        dump.roots.dedup();
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while dumping heap");
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        dump
+    }
+
+    /// the address and tri-color of every tracked object, sorted by address,
+    /// with the world stopped and minor gc held off.
+    /// taken between `mark_step`s it shows the wavefront of the incremental marking exactly,
+    /// but the parallel marking of `collect` is not paused by stopping the world,
+    /// so a snapshot taken meanwhile is only a rough picture of it.
+    /// every object is white while no major gc is running. see `color_snapshot_to_dot`
+    pub fn color_snapshot(&self) -> Vec<(usize, Color)> {
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.stop_world(false);
+        let mut colors: Vec<(usize, Color)> = self
+            .minor_heap_gen
+            .iter()
+            .chain(self.major_heap_gen.iter())
+            .chain(self.imm_gen.iter())
+            .map(|header| (*header as usize, unsafe { (**header).color() }))
+            .collect();
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while taking color snapshot");
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        colors.sort_unstable_by_key(|(header, _)| *header);
+        colors
+    }
+}
+
+/// the objects of `State::color_snapshot` in Graphviz dot format, filled with their colors.
+/// references are not recorded by the snapshot, so only the nodes are drawn
+pub fn color_snapshot_to_dot(snapshot: &[(usize, Color)]) -> String {
+    let mut dot = String::from("digraph colors {\n    node [style=filled];\n");
+    for (header, color) in snapshot.iter() {
+        let attributes = match color {
+            Color::White => "fillcolor=white",
+            Color::Grey => "fillcolor=grey",
+            Color::Black => "fillcolor=black, fontcolor=white",
+        };
+        let _ = writeln!(dot, "    \"{:#x}\" [{}];", header, attributes);
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// the version of `HeapSnapshot` format, increased when a field is changed
+pub const HEAP_SNAPSHOT_VERSION: u32 = 1;
+
+/// a `HeapDump` which could be written by serde, and inspected by an offline tool.
+/// `TypeId` is not stable across runs, so types are written as their names, see `State::register_type_name`.
+///
+/// format of version 1:
+/// - `version`: `HEAP_SNAPSHOT_VERSION` of the writer
+/// - `types`: type names, every type appears once
+/// - `objects`: `id`, `header`, `type_index` into `types`, `generation`, `liveness`, `size`,
+///   and `references` as headers of other objects
+/// - `roots`: headers of the roots
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HeapSnapshot {
+    pub version: u32,
+    pub types: Vec<String>,
+    pub objects: Vec<SnapshotObject>,
+    pub roots: Vec<usize>,
+}
+
+/// an object of `HeapSnapshot`, see `ObjectDump`
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotObject {
+    pub id: u64,
+    pub header: usize,
+    pub type_index: usize,
+    /// `Generation` as a number
+    pub generation: u8,
+    pub liveness: usize,
+    pub size: usize,
+    pub references: Vec<usize>,
+}
+
+#[cfg(feature = "serde")]
+impl HeapSnapshot {
+    /// name of the type of an object, `None` if the index is out of `types`
+    pub fn type_name(&self, object: &SnapshotObject) -> Option<&str> {
+        self.types.get(object.type_index).map(String::as_str)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<&HeapDump> for HeapSnapshot {
+    fn from(dump: &HeapDump) -> Self {
+        let mut type_indices: HashMap<TypeId, usize> = HashMap::new();
+        let mut snapshot = HeapSnapshot {
+            version: HEAP_SNAPSHOT_VERSION,
+            roots: dump.roots.clone(),
+            ..Default::default()
+        };
+        for object in dump.objects.iter() {
+            let type_index = *type_indices.entry(object.type_id).or_insert_with(|| {
+                snapshot.types.push(String::from(object.type_name));
+                snapshot.types.len() - 1
+            });
+            snapshot.objects.push(SnapshotObject {
+                id: object.id,
+                header: object.header,
+                type_index,
+                generation: object.generation as u8,
+                liveness: object.liveness,
+                size: object.size,
+                references: object.references.clone(),
+            });
+        }
+        snapshot
+    }
+}
@@ -1,7 +1,29 @@
-#![feature(core_intrinsics)]
 #![feature(min_specialization)]
-#![feature(backtrace)]
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
+// `#[derive(Trace)]` refers to `::cgc`, which is this crate in its own tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as cgc;
+
 pub mod state;
 pub mod frame;
 pub mod gc_box;
-pub mod trace;
\ No newline at end of file
+pub mod trace;
+pub mod monitoring;
+pub mod stats;
+pub mod heap_dump;
+pub mod gc_array;
+pub mod pinned_region;
+pub mod type_registry;
+#[cfg(feature = "arena")]
+pub mod arena;
+#[cfg(feature = "validate")]
+pub mod validate;
+#[cfg(feature = "workload")]
+pub mod workload;
+mod bump;
+#[cfg(feature = "std")]
+mod grey_queue;
+mod sync;
+#[cfg(test)]
+mod test_util;
\ No newline at end of file
@@ -0,0 +1,482 @@
+//! ready to use `Monitoring` backends
+pub use self::fn_monitoring::FnMonitoring;
+#[cfg(feature = "std")]
+pub use self::histogram_monitoring::{Histogram, HistogramMonitoring};
+#[cfg(feature = "tracing")]
+pub use self::tracing_monitoring::TracingMonitoring;
+
+#[cfg(feature = "tracing")]
+mod tracing_monitoring {
+    use std::sync::Mutex;
+
+    use core::any::TypeId;
+
+    use tracing::{error, field, info, info_span, warn, Span};
+
+    use crate::state::Monitoring;
+
+    /// emits a span for every minor and major gc, and events for stw and memory usage.
+    /// the callbacks of one gc could be fired from different threads, so the spans are never entered,
+    /// the start and the end of a gc are recorded in its span by `Span::in_scope`,
+    /// and the span is closed when the gc ends.
+    #[derive(Default)]
+    pub struct TracingMonitoring {
+        minor_gc: Mutex<Option<Span>>,
+        major_gc: Mutex<Option<Span>>,
+    }
+
+    impl TracingMonitoring {
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    fn start(slot: &Mutex<Option<Span>>, span: Span) {
+        span.in_scope(|| info!(target: "cgc", "gc started"));
+        *slot.lock().unwrap() = Some(span);
+    }
+
+    fn end(slot: &Mutex<Option<Span>>, field: &str, heap_size: usize) {
+        if let Some(span) = slot.lock().unwrap().take() {
+            span.record(field, heap_size);
+            span.in_scope(|| info!(target: "cgc", "gc finished"));
+        }
+    }
+
+    impl Monitoring for TracingMonitoring {
+        fn start_minor_gc(&self, minor_heap_size: usize) {
+            let span = info_span!(
+                target: "cgc",
+                "minor_gc",
+                minor_heap_size,
+                end_minor_heap_size = field::Empty
+            );
+            start(&self.minor_gc, span);
+        }
+
+        fn end_minor_gc(&self, minor_heap_size: usize) {
+            end(&self.minor_gc, "end_minor_heap_size", minor_heap_size);
+        }
+
+        fn start_major_gc(&self, major_heap_size: usize) {
+            let span = info_span!(
+                target: "cgc",
+                "major_gc",
+                major_heap_size,
+                end_major_heap_size = field::Empty
+            );
+            start(&self.major_gc, span);
+        }
+
+        fn end_major_gc(&self, major_heap_size: usize) {
+            end(&self.major_gc, "end_major_heap_size", major_heap_size);
+        }
+
+        fn start_stw(&self) {
+            info!(target: "cgc", pause = "start", "stop the world");
+        }
+
+        fn end_stw(&self) {
+            info!(target: "cgc", pause = "end", "continue the world");
+        }
+
+        fn record_memory_usage(&self, major_heap_size: usize, minor_heap_size: usize) {
+            info!(
+                target: "cgc",
+                major_heap_size,
+                minor_heap_size,
+                "memory usage"
+            );
+        }
+
+        fn trace_panicked(&self, _type_id: TypeId, type_name: &'static str) {
+            error!(target: "cgc", type_name, "trace panicked, nothing is freed by this gc");
+        }
+
+        fn stw_timeout(&self, outstanding: &[std::thread::Thread]) {
+            for thread in outstanding {
+                warn!(
+                    target: "cgc",
+                    thread = thread.name().unwrap_or("<unnamed>"),
+                    thread_id = ?thread.id(),
+                    "mutator didn't reach a safepoint in time"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod histogram_monitoring {
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use crate::state::Monitoring;
+
+    /// bits of sub buckets in every power of 2, the error of a recorded value is at most 1/16
+    const SUB_BUCKET_BITS: u32 = 4;
+    const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+    const BUCKETS: usize = SUB_BUCKETS * (64 - SUB_BUCKET_BITS as usize + 1);
+
+    /// a lock free log-linear histogram of durations in nanoseconds, like HDR histogram.
+    /// quantiles are reported as the highest value of their bucket, but never above `max`.
+    pub struct Histogram {
+        buckets: Vec<AtomicU64>,
+        count: AtomicU64,
+        max: AtomicU64,
+    }
+
+    impl Default for Histogram {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Histogram {
+        pub fn new() -> Self {
+            Self {
+                buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+                count: AtomicU64::new(0),
+                max: AtomicU64::new(0),
+            }
+        }
+
+        pub fn record(&self, duration: Duration) {
+            let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+            self.buckets[bucket_of(nanos)].fetch_add(1, Ordering::Relaxed);
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.max.fetch_max(nanos, Ordering::Relaxed);
+        }
+
+        pub fn count(&self) -> u64 {
+            self.count.load(Ordering::Relaxed)
+        }
+        pub fn max(&self) -> Duration {
+            Duration::from_nanos(self.max.load(Ordering::Relaxed))
+        }
+        pub fn p50(&self) -> Duration {
+            self.quantile(0.5)
+        }
+        pub fn p99(&self) -> Duration {
+            self.quantile(0.99)
+        }
+
+        /// the smallest value which `quantile` of the recorded values are not greater than,
+        /// zero if nothing is recorded
+        pub fn quantile(&self, quantile: f64) -> Duration {
+            let count = self.count();
+            if count == 0 {
+                return Duration::ZERO;
+            }
+            let rank = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+            let max = self.max.load(Ordering::Relaxed);
+            let mut seen = 0;
+            for (bucket, recorded) in self.buckets.iter().enumerate() {
+                seen += recorded.load(Ordering::Relaxed);
+                if seen >= rank {
+                    return Duration::from_nanos(highest_of(bucket).min(max));
+                }
+            }
+            Duration::from_nanos(max)
+        }
+    }
+
+    /// values below `SUB_BUCKETS` have their own buckets,
+    /// then every power of 2 is split into `SUB_BUCKETS` linear buckets
+    fn bucket_of(value: u64) -> usize {
+        if value < SUB_BUCKETS as u64 {
+            return value as usize;
+        }
+        let exponent = 63 - value.leading_zeros();
+        let shift = exponent - SUB_BUCKET_BITS;
+        let sub = (value >> shift) as usize - SUB_BUCKETS;
+        SUB_BUCKETS * (shift as usize + 1) + sub
+    }
+
+    fn highest_of(bucket: usize) -> u64 {
+        if bucket < SUB_BUCKETS {
+            return bucket as u64;
+        }
+        let shift = (bucket / SUB_BUCKETS - 1) as u32;
+        let sub = (bucket % SUB_BUCKETS + SUB_BUCKETS) as u64;
+        ((sub + 1) << shift).wrapping_sub(1)
+    }
+
+    /// how far back the allocation rate looks
+    const ALLOCATION_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+    struct AllocationSamples {
+        last_heap_size: usize,
+        /// when the heap grew, and how many bytes
+        growth: VecDeque<(Instant, usize)>,
+    }
+
+    /// records the duration of every minor gc, major gc and stw pause,
+    /// and the allocation rate from the memory usage reported before and after every gc.
+    /// it reads `std::time::Instant`, which panics on `wasm32-unknown-unknown`.
+    pub struct HistogramMonitoring {
+        minor_gc: Histogram,
+        major_gc: Histogram,
+        stw: Histogram,
+        minor_gc_start: Mutex<Option<Instant>>,
+        major_gc_start: Mutex<Option<Instant>>,
+        stw_start: Mutex<Option<Instant>>,
+        created: Instant,
+        allocation: Mutex<AllocationSamples>,
+    }
+
+    impl Default for HistogramMonitoring {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl HistogramMonitoring {
+        pub fn new() -> Self {
+            Self {
+                minor_gc: Histogram::new(),
+                major_gc: Histogram::new(),
+                stw: Histogram::new(),
+                minor_gc_start: Mutex::new(None),
+                major_gc_start: Mutex::new(None),
+                stw_start: Mutex::new(None),
+                created: Instant::now(),
+                allocation: Mutex::new(AllocationSamples {
+                    last_heap_size: 0,
+                    growth: VecDeque::new(),
+                }),
+            }
+        }
+
+        pub fn minor_gc(&self) -> &Histogram {
+            &self.minor_gc
+        }
+        pub fn major_gc(&self) -> &Histogram {
+            &self.major_gc
+        }
+        /// every stop the world pause, including the ones of minor gc
+        pub fn stw(&self) -> &Histogram {
+            &self.stw
+        }
+
+        /// bytes allocated per second in the last 10 seconds,
+        /// or since this monitoring is created if it's younger.
+        /// allocations are only seen when a gc starts.
+        pub fn allocation_rate(&self) -> f64 {
+            let now = Instant::now();
+            let mut allocation = self.allocation.lock().unwrap();
+            Self::expire(&mut allocation.growth, now);
+            let bytes: usize = allocation.growth.iter().map(|(_, bytes)| bytes).sum();
+            let elapsed = (now - self.created).min(ALLOCATION_RATE_WINDOW);
+            if elapsed.is_zero() {
+                return 0.0;
+            }
+            bytes as f64 / elapsed.as_secs_f64()
+        }
+
+        fn expire(growth: &mut VecDeque<(Instant, usize)>, now: Instant) {
+            while let Some((at, _)) = growth.front() {
+                if now - *at <= ALLOCATION_RATE_WINDOW {
+                    break;
+                }
+                growth.pop_front();
+            }
+        }
+    }
+
+    fn start(slot: &Mutex<Option<Instant>>) {
+        *slot.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn end(slot: &Mutex<Option<Instant>>, histogram: &Histogram) {
+        if let Some(start) = slot.lock().unwrap().take() {
+            histogram.record(start.elapsed());
+        }
+    }
+
+    impl Monitoring for HistogramMonitoring {
+        fn start_minor_gc(&self, _minor_heap_size: usize) {
+            start(&self.minor_gc_start);
+        }
+
+        fn end_minor_gc(&self, _minor_heap_size: usize) {
+            end(&self.minor_gc_start, &self.minor_gc);
+        }
+
+        fn start_major_gc(&self, _major_heap_size: usize) {
+            start(&self.major_gc_start);
+        }
+
+        fn end_major_gc(&self, _major_heap_size: usize) {
+            end(&self.major_gc_start, &self.major_gc);
+        }
+
+        fn start_stw(&self) {
+            start(&self.stw_start);
+        }
+
+        fn end_stw(&self) {
+            end(&self.stw_start, &self.stw);
+        }
+
+        fn record_memory_usage(&self, major_heap_size: usize, minor_heap_size: usize) {
+            let now = Instant::now();
+            let heap_size = major_heap_size + minor_heap_size;
+            let mut allocation = self.allocation.lock().unwrap();
+            if heap_size > allocation.last_heap_size {
+                let grown = heap_size - allocation.last_heap_size;
+                allocation.growth.push_back((now, grown));
+            }
+            allocation.last_heap_size = heap_size;
+            Self::expire(&mut allocation.growth, now);
+        }
+    }
+}
+
+mod fn_monitoring {
+    use alloc::boxed::Box;
+    use core::any::TypeId;
+
+    use crate::state::Monitoring;
+
+    type Hook = Box<dyn Fn() + Send + Sync>;
+    type SizeHook = Box<dyn Fn(usize) + Send + Sync>;
+    type MemoryUsageHook = Box<dyn Fn(usize, usize) + Send + Sync>;
+    type TracePanicHook = Box<dyn Fn(TypeId, &'static str) + Send + Sync>;
+    #[cfg(feature = "std")]
+    type StwTimeoutHook = Box<dyn Fn(&[std::thread::Thread]) + Send + Sync>;
+
+    /// a `Monitoring` made of closures, the events without a closure are ignored.
+    /// ```ignore
+    /// let monitoring = FnMonitoring::new()
+    ///     .on_end_major_gc(|major_heap_size| println!("major heap: {} bytes", major_heap_size));
+    /// let state = State::new(config).with_monitoring(Box::new(monitoring));
+    /// ```
+    #[derive(Default)]
+    pub struct FnMonitoring {
+        start_minor_gc: Option<SizeHook>,
+        end_minor_gc: Option<SizeHook>,
+        start_major_gc: Option<SizeHook>,
+        end_major_gc: Option<SizeHook>,
+        start_stw: Option<Hook>,
+        end_stw: Option<Hook>,
+        record_memory_usage: Option<MemoryUsageHook>,
+        trace_panicked: Option<TracePanicHook>,
+        #[cfg(feature = "std")]
+        stw_timeout: Option<StwTimeoutHook>,
+    }
+
+    impl FnMonitoring {
+        pub fn new() -> Self {
+            Self::default()
+        }
+        pub fn on_start_minor_gc(mut self, hook: impl Fn(usize) + Send + Sync + 'static) -> Self {
+            self.start_minor_gc = Some(Box::new(hook));
+            self
+        }
+        pub fn on_end_minor_gc(mut self, hook: impl Fn(usize) + Send + Sync + 'static) -> Self {
+            self.end_minor_gc = Some(Box::new(hook));
+            self
+        }
+        pub fn on_start_major_gc(mut self, hook: impl Fn(usize) + Send + Sync + 'static) -> Self {
+            self.start_major_gc = Some(Box::new(hook));
+            self
+        }
+        pub fn on_end_major_gc(mut self, hook: impl Fn(usize) + Send + Sync + 'static) -> Self {
+            self.end_major_gc = Some(Box::new(hook));
+            self
+        }
+        pub fn on_start_stw(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+            self.start_stw = Some(Box::new(hook));
+            self
+        }
+        pub fn on_end_stw(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+            self.end_stw = Some(Box::new(hook));
+            self
+        }
+        /// the hook is called with `(major_heap_size, minor_heap_size)`
+        pub fn on_record_memory_usage(
+            mut self,
+            hook: impl Fn(usize, usize) + Send + Sync + 'static,
+        ) -> Self {
+            self.record_memory_usage = Some(Box::new(hook));
+            self
+        }
+        /// the hook is called with the type of the object whose `Trace::trace` panicked
+        pub fn on_trace_panicked(
+            mut self,
+            hook: impl Fn(TypeId, &'static str) + Send + Sync + 'static,
+        ) -> Self {
+            self.trace_panicked = Some(Box::new(hook));
+            self
+        }
+        /// the hook is called with the threads `stw` has given up waiting for
+        #[cfg(feature = "std")]
+        pub fn on_stw_timeout(
+            mut self,
+            hook: impl Fn(&[std::thread::Thread]) + Send + Sync + 'static,
+        ) -> Self {
+            self.stw_timeout = Some(Box::new(hook));
+            self
+        }
+    }
+
+    impl Monitoring for FnMonitoring {
+        fn start_minor_gc(&self, minor_heap_size: usize) {
+            if let Some(hook) = &self.start_minor_gc {
+                hook(minor_heap_size);
+            }
+        }
+
+        fn end_minor_gc(&self, minor_heap_size: usize) {
+            if let Some(hook) = &self.end_minor_gc {
+                hook(minor_heap_size);
+            }
+        }
+
+        fn start_major_gc(&self, major_heap_size: usize) {
+            if let Some(hook) = &self.start_major_gc {
+                hook(major_heap_size);
+            }
+        }
+
+        fn end_major_gc(&self, major_heap_size: usize) {
+            if let Some(hook) = &self.end_major_gc {
+                hook(major_heap_size);
+            }
+        }
+
+        fn start_stw(&self) {
+            if let Some(hook) = &self.start_stw {
+                hook();
+            }
+        }
+
+        fn end_stw(&self) {
+            if let Some(hook) = &self.end_stw {
+                hook();
+            }
+        }
+
+        fn record_memory_usage(&self, major_heap_size: usize, minor_heap_size: usize) {
+            if let Some(hook) = &self.record_memory_usage {
+                hook(major_heap_size, minor_heap_size);
+            }
+        }
+
+        fn trace_panicked(&self, type_id: TypeId, type_name: &'static str) {
+            if let Some(hook) = &self.trace_panicked {
+                hook(type_id, type_name);
+            }
+        }
+
+        #[cfg(feature = "std")]
+        fn stw_timeout(&self, outstanding: &[std::thread::Thread]) {
+            if let Some(hook) = &self.stw_timeout {
+                hook(outstanding);
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt;
+use core::sync::atomic::Ordering;
+
+use super::frame::{AllocError, GCFrame};
+use super::gc_box::{GCCell, GCCellLayout, GCHeader, GCRef, Generation};
+use super::sync::Mutex;
+use super::trace::Trace;
+
+/// regions are aligned as bump chunks, objects aligned more than this are aligned in place
+const REGION_ALIGN: usize = 16;
+
+/// a contiguous block of gc objects which stay put while C holds pointers into them.
+/// the objects are never moved or freed by gc, and they are traced as roots of every collection,
+/// so the objects referenced by them are kept alive too.
+/// they are dropped by `free` together with the region, using a reference to them afterwards is UB.
+/// a region dropped without `free` is leaked, its objects stay alive and keep being traced as roots.
+/// the region is not counted in any generation, see `GcStats::pinned_size`.
+/// it's neither `Send` nor `Sync`, since the objects need not be, and they are dropped by the freeing thread.
+pub struct PinnedRegion {
+    frame: &'static GCFrame,
+    start: *mut u8,
+    layout: Layout,
+    bump: Mutex<RegionBump>,
+}
+
+struct RegionBump {
+    /// offset of the free space from `start`
+    cursor: usize,
+    objects: Vec<*mut GCHeader>,
+}
+
+impl PinnedRegion {
+    pub(crate) fn try_new(frame: &'static GCFrame, size: usize) -> Result<Self, AllocError> {
+        let layout = Layout::from_size_align(size.max(1), REGION_ALIGN)
+            .map_err(|_| AllocError::SystemOom { size })?;
+        let state = frame.state();
+        let start = state.allocator.alloc(layout);
+        if start.is_null() {
+            return Err(AllocError::SystemOom { size });
+        }
+        state.pinned_size.fetch_add(layout.size(), Ordering::AcqRel);
+        Ok(Self {
+            frame,
+            start,
+            layout,
+            bump: Mutex::new(RegionBump {
+                cursor: 0,
+                objects: Vec::new(),
+            }),
+        })
+    }
+
+    /// place an object right after the previous one,
+    /// returns `Err(AllocError::RegionFull)` if the rest of the region is too small for it
+    pub fn alloc<T: Trace>(&self, value: T) -> Result<GCRef<T>, AllocError> {
+        let layout = Layout::new::<GCCellLayout<T>>();
+        let mut bump = self.bump.lock().unwrap();
+        let base = self.start as usize;
+        let addr = (base + bump.cursor + layout.align() - 1) & !(layout.align() - 1);
+        if addr + layout.size() > base + self.layout.size() {
+            return Err(AllocError::RegionFull {
+                size: layout.size(),
+            });
+        }
+        bump.cursor = addr + layout.size() - base;
+        unsafe {
+            let header = addr as *mut GCHeader;
+            (*header).init::<T>(self.frame);
+            let cell = header as *mut GCCellLayout<T>;
+            core::ptr::addr_of_mut!((*cell).data).write(value);
+            (*header).set_generation(Generation::Major);
+            (*header).set_pinned(true);
+            bump.objects.push(header);
+            self.frame.state().pinned_region_objects.insert(header);
+            Ok(GCRef::new(GCCell::from_header(header)))
+        }
+    }
+
+    /// bytes reserved by the region
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// bytes taken by the objects, including the padding between them
+    pub fn used(&self) -> usize {
+        self.bump.lock().unwrap().cursor
+    }
+
+    /// the start of the region, for handing the whole block to C
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.start
+    }
+
+    /// drop the objects and release the region.
+    /// it waits for the running major gc, then the objects are removed with the world stopped and minor gc held off,
+    /// so no collection is tracing them meanwhile.
+    ///
+    /// # Safety
+    /// nothing may use the objects afterwards, which means every `GCRef` to them,
+    /// the references from other gc objects, and the pointers handed to C.
+    pub unsafe fn free(self) {
+        let state = self.frame.state();
+        let objects = core::mem::take(&mut self.bump.lock().unwrap().objects);
+        state.forget_pinned_objects(&objects);
+        for header in objects {
+            unsafe { GCHeader::drop_data(header) };
+        }
+        state
+            .pinned_size
+            .fetch_sub(self.layout.size(), Ordering::AcqRel);
+        unsafe { state.allocator.dealloc(self.start, self.layout) };
+    }
+}
+
+impl fmt::Debug for PinnedRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PinnedRegion")
+            .field("start", &self.start)
+            .field("size", &self.size())
+            .field("used", &self.used())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::test_util::{env, full_gc, is_tracked, Node};
+    use crate::trace::{Trace, Tracer};
+
+    #[test]
+    fn region_objects_are_roots_until_freed() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        struct Counted;
+        impl Trace for Counted {
+            fn trace(&self, _: &mut dyn Tracer) {}
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let (state, frame) = env();
+        let region = frame.alloc_pinned_region(1024);
+        let child = frame.alloc(Node(1, vec![]));
+        let parent = region.alloc(Node(2, vec![child.as_ref()])).unwrap();
+        region.alloc(Counted).unwrap();
+        drop(child);
+        full_gc(state);
+        // the child is moved out of minor heap, and the reference of the region is redirected
+        let child_header = parent.1[0].header();
+        assert!(is_tracked(state, child_header));
+        assert_eq!(parent.1[0].0, 1);
+        assert_eq!(state.stats().pinned_size, region.size());
+
+        unsafe { region.free() };
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+        assert!(state.pinned_region_objects.is_empty());
+        assert_eq!(state.stats().pinned_size, 0);
+        full_gc(state);
+        assert!(!is_tracked(state, child_header));
+    }
+}
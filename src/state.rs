@@ -1,9 +1,32 @@
+use super::bump::{Chunk, CHUNK_SIZE};
 use super::frame::GCFrame;
-use super::gc_box::GCHeader;
-use dashmap::DashSet as Set;
-use std::sync::atomic::{AtomicBool, AtomicUsize};
+use super::gc_box::{Color, EphemeronSlot, GCCell, GCHeader, GCRef, GCSlot, Generation};
+#[cfg(feature = "std")]
+use super::grey_queue::{GreyQueue, GreyWorker};
+use super::sync::{self, HashMap, HashSet, Map, Mutex, Set};
+use super::trace::{Trace, Tracer};
+use super::type_registry::TypeRegistry;
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
+use alloc::sync::Arc;
+use alloc::sync::Weak;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::any::TypeId;
+#[cfg(feature = "std")]
+use core::marker::PhantomData;
+use core::sync::atomic::{
+    AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
 
-pub trait Monitoring {
+/// see `State::set_alloc_hook`
+pub type AllocHook = dyn Fn(TypeId, usize) + Send + Sync;
+
+/// receives gc events.
+/// the callbacks are fired from whatever thread is running the gc,
+/// including mutator threads and workers of the gc thread pool,
+/// so the implementation should be `Send + Sync`.
+pub trait Monitoring: Send + Sync {
     fn start_minor_gc(&self, minor_heap_size: usize);
     fn end_minor_gc(&self, minor_heap_size: usize);
     fn start_major_gc(&self, major_heap_size: usize);
@@ -11,6 +34,13 @@ pub trait Monitoring {
     fn start_stw(&self);
     fn end_stw(&self);
     fn record_memory_usage(&self, major_heap_size: usize, minor_heap_size: usize);
+    /// `Trace::trace` of an object panicked during marking, it's caught with `std`,
+    /// and nothing is freed by that gc. ignored by default
+    fn trace_panicked(&self, _type_id: TypeId, _type_name: &'static str) {}
+    /// `stw` waited `GCConfigBuilder::stw_timeout` for the registered mutators in vain,
+    /// `outstanding` are the threads not in a `safepoint`. ignored by default
+    #[cfg(feature = "std")]
+    fn stw_timeout(&self, _outstanding: &[std::thread::Thread]) {}
 }
 
 struct DummyMonitoring {}
@@ -30,7 +60,95 @@ impl Monitoring for DummyMonitoring {
     fn record_memory_usage(&self, _major_heap_size: usize, _minor_heap_size: usize) {}
 }
 
-enum GCStage {
+/// backs the gc heap, every object is allocated and freed through it.
+/// like `Monitoring`, it's called from mutator threads and gc workers.
+pub trait Allocator: Send + Sync {
+    /// returns null if the memory could not be allocated
+    fn alloc(&self, layout: Layout) -> *mut u8;
+    /// # Safety
+    /// `ptr` should be allocated by this allocator with the same `layout`
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+    /// free many cells of the same `layout` at once, it's called by the sweeper
+    /// with `GCConfig::batch_sweep`. override it if the allocator could do better
+    /// than freeing them one by one.
+    /// # Safety
+    /// like `dealloc`, for every pointer in `ptrs`
+    unsafe fn dealloc_batch(&self, ptrs: &[*mut u8], layout: Layout) {
+        for ptr in ptrs {
+            self.dealloc(*ptr, layout);
+        }
+    }
+}
+
+/// the global allocator of Rust
+/// default allocator
+pub struct GlobalAllocator {}
+impl Allocator for GlobalAllocator {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { alloc::alloc::alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::alloc::dealloc(ptr, layout)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomAction {
+    /// kill the process
+    Abort,
+    /// force a collection, and check the limit again
+    Retry,
+    /// raise the limit to this value
+    GrowLimit(usize),
+    /// promote every survivor of minor heap into major heap, see `State::flush_minor_to_major`,
+    /// for major heap it's the same as `Retry`
+    FlushMinor,
+}
+
+/// what the outermost `stw` does after `GCConfigBuilder::stw_timeout`,
+/// when some registered mutators are still not in a `safepoint`.
+/// they are reported by `Monitoring::stw_timeout` first for every action
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StwTimeoutAction {
+    /// give up stopping the world, `stw` returns `Err(StwError::TimedOut)`
+    /// and a gc cycle waiting for it is abandoned, to be tried again on the next trigger.
+    /// the stops the gc can't do without, like for `dump_heap` or `validate`, keep waiting instead
+    Skip,
+    /// keep waiting, reporting them again after every timeout
+    Wait,
+    /// kill the process, so a stuck mutator shows up as a crash instead of a hang
+    Abort,
+}
+
+/// decides what to do when a heap exceeds its size limit.
+/// the handler is asked again and again until the heap fits in the limit,
+/// so keep returning `Retry` without freeing anything means looping forever.
+pub trait OomHandler {
+    fn on_minor_oom(&self, minor_heap_size: usize) -> OomAction;
+    fn on_major_oom(&self, major_heap_size: usize) -> OomAction;
+    /// the size of every generation together exceeds `total_heap_size_limit`,
+    /// `Retry` runs both minor and major gc. aborts by default
+    fn on_total_oom(&self, _total_size: usize) -> OomAction {
+        OomAction::Abort
+    }
+}
+
+struct AbortOomHandler {}
+impl OomHandler for AbortOomHandler {
+    fn on_minor_oom(&self, _minor_heap_size: usize) -> OomAction {
+        OomAction::Abort
+    }
+
+    fn on_major_oom(&self, _major_heap_size: usize) -> OomAction {
+        OomAction::Abort
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum GCStage {
     /// starting stage
     /// when finishing concurrent sweep, it will be back to ready stage.
     Ready,
@@ -43,72 +161,485 @@ enum GCStage {
     ConcurrentSweep,
 }
 
-enum MinorGCStage {
+impl GCStage {
+    fn from_u8(stage: u8) -> GCStage {
+        match stage {
+            0 => GCStage::Ready,
+            1 => GCStage::ParallelScan,
+            2 => GCStage::FinalScan,
+            3 => GCStage::ConcurrentSweep,
+            _ => unreachable!("[FALTAL ERROR] invalid gc stage {}", stage),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum MinorGCStage {
     Ready,
     Scan,
     Sweep,
 }
 
+impl MinorGCStage {
+    fn from_u8(stage: u8) -> MinorGCStage {
+        match stage {
+            0 => MinorGCStage::Ready,
+            1 => MinorGCStage::Scan,
+            2 => MinorGCStage::Sweep,
+            _ => unreachable!("[FALTAL ERROR] invalid minor gc stage {}", stage),
+        }
+    }
+}
+
+/// where the collector is in its cycle, see `State::current_phase`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GcPhase {
+    /// no gc is running
+    Ready,
+    /// minor gc is marking, with the world stopped
+    MinorScan,
+    /// minor gc is moving and freeing objects, with the world stopped
+    MinorSweep,
+    /// major gc is marking concurrently with the mutators
+    ParallelScan,
+    /// major gc is rescanning mutated objects, with the world stopped
+    FinalScan,
+    /// major gc is freeing dead objects concurrently with the mutators
+    ConcurrentSweep,
+}
+
+impl From<GCStage> for GcPhase {
+    fn from(stage: GCStage) -> Self {
+        match stage {
+            GCStage::Ready => GcPhase::Ready,
+            GCStage::ParallelScan => GcPhase::ParallelScan,
+            GCStage::FinalScan => GcPhase::FinalScan,
+            GCStage::ConcurrentSweep => GcPhase::ConcurrentSweep,
+        }
+    }
+}
+
+impl From<MinorGCStage> for GcPhase {
+    fn from(stage: MinorGCStage) -> Self {
+        match stage {
+            MinorGCStage::Ready => GcPhase::Ready,
+            MinorGCStage::Scan => GcPhase::MinorScan,
+            MinorGCStage::Sweep => GcPhase::MinorSweep,
+        }
+    }
+}
+
 pub struct GCConfig {
     /// gc thread pool size
-    /// default is 1/4 of cpu cores
-    thread_pool_size: usize,
+    /// default is 1/4 of cpu cores, at least 1
+    pub(crate) thread_pool_size: usize,
+    /// run the gc on this pool instead of building one, for embeddings already running rayon,
+    /// so the gc doesn't spawn threads of its own. `thread_pool_size` is ignored then.
+    /// default is none
+    #[cfg(feature = "std")]
+    pub(crate) thread_pool: Option<Arc<rayon::ThreadPool>>,
     /// force to trigger minor gc when size exceeds this value
     /// default is 10mb
-    minor_gc_trigger_size: usize,
+    pub(crate) minor_gc_trigger_size: usize,
     /// when minor heap is greater than this size, OOM will be triggered
     /// OOM usually means that you are allocating too fast
     /// default is 100mb
-    minor_heap_size_limit: usize,
+    pub(crate) minor_heap_size_limit: usize,
+    /// minor gc is also triggered when more frames than this are alive,
+    /// for workloads pushing many frames with tiny allocations.
+    /// it's only fired again after more frames are pushed,
+    /// so the same living frames don't trigger it on every allocation
+    /// default is 0 for only triggering by size
+    pub(crate) minor_gc_frame_trigger: usize,
     /// for minor_heap generation object, when lived more than this value, it will be moved to major_heap generation.
     /// default is 3
-    major_heap_liveness: usize,
+    pub(crate) major_heap_liveness: usize,
     /// when memory exceeds this value * last size, it will trigger major gc
     /// default is 2.0
-    major_gc_pacer_rate: f32,
+    pub(crate) major_gc_pacer_rate: f32,
     /// when major gc is greater than this size, OOM will be triggered
     /// OOM usually means that you are leaking memory or you don't have enough memory to run your program
     /// default is 0 for no limit
-    major_heap_size_limit: usize,
+    pub(crate) major_heap_size_limit: usize,
+    /// when minor, major and imm heap together are greater than this size, OOM will be triggered,
+    /// regardless of the limits of each generation, like the memory limit of a container
+    /// default is 0 for no limit
+    pub(crate) total_heap_size_limit: usize,
     /// for enable imm generation
     /// sometimes some memory are static, and we don't want to collect them
     /// they usally live longer than any other object in the program
     /// default is false
-    enable_imm_gen: bool,
+    pub(crate) enable_imm_gen: bool,
     /// this value lives longer than 100 times major gc
     /// default is 100
-    imm_liveness: usize,
+    pub(crate) imm_liveness: usize,
+    /// run every phase of gc on the calling thread, without building the gc thread pool,
+    /// so tests could observe exactly which objects survived right after `collect`.
+    /// `thread_pool_size` is ignored.
+    /// default is false, and it's always on without `std` or with `wasm`
+    pub(crate) deterministic: bool,
+    /// free the dead objects of a sweep in batches, grouped by bump chunk and by layout,
+    /// on a single thread instead of one by one in parallel. see `Allocator::dealloc_batch`
+    /// default is false
+    pub(crate) batch_sweep: bool,
+    /// objects at least this large are never copied by minor gc, they are swept in place,
+    /// and tracked as large objects besides their generation, see `GcStats::large_object_heap_size`
+    /// default is 32KB, 0 for treating every object the same
+    pub(crate) large_object_size: usize,
+    /// compact major heap after major gc, when the fragmentation of it by `fragmentation_report`
+    /// exceeds `compaction_threshold`, see `State::compact_major_heap`
+    /// default is false
+    pub(crate) enable_compaction: bool,
+    /// from 0.0 to 1.0, both for triggering the compaction and for picking the chunks to empty
+    /// default is 0.5
+    pub(crate) compaction_threshold: f32,
+    /// allocate every object straight into major heap, and never run minor gc,
+    /// for workloads building large long lived data, where copying young objects is a waste.
+    /// objects are only collected by major gc, triggered by `major_gc_pacer_rate`.
+    /// default is false
+    pub(crate) disable_minor_gen: bool,
+    /// the outermost `stw` waits at most this long for the registered mutators to reach a `safepoint`,
+    /// then the ones still running are reported by `Monitoring::stw_timeout`,
+    /// and `stw_timeout_action` is taken.
+    /// default is none for waiting forever
+    #[cfg(feature = "std")]
+    pub(crate) stw_timeout: Option<core::time::Duration>,
+    /// default is `StwTimeoutAction::Skip`
+    #[cfg(feature = "std")]
+    pub(crate) stw_timeout_action: StwTimeoutAction,
 }
 
 impl Default for GCConfig{
     fn default() -> Self {
+        #[cfg(all(feature = "std", not(feature = "wasm")))]
+        let thread_pool_size = (num_cpus::get() / 4).max(1);
+        #[cfg(any(not(feature = "std"), feature = "wasm"))]
+        let thread_pool_size = 1;
         Self {
-            thread_pool_size: num_cpus::get() / 4,
+            thread_pool_size,
+            #[cfg(feature = "std")]
+            thread_pool: None,
             minor_gc_trigger_size: 10 * 1024 * 1024,
             minor_heap_size_limit: 100 * 1024 * 1024,
+            minor_gc_frame_trigger: 0,
             major_heap_liveness: 3,
             major_gc_pacer_rate: 2.0,
             major_heap_size_limit: 0,
+            total_heap_size_limit: 0,
             enable_imm_gen: false,
             imm_liveness: 100,
+            deterministic: cfg!(feature = "wasm"),
+            batch_sweep: false,
+            large_object_size: 32 * 1024,
+            enable_compaction: false,
+            compaction_threshold: 0.5,
+            disable_minor_gen: false,
+            #[cfg(feature = "std")]
+            stw_timeout: None,
+            #[cfg(feature = "std")]
+            stw_timeout_action: StwTimeoutAction::Skip,
+        }
+    }
+}
+
+impl GCConfig {
+    pub fn builder() -> GCConfigBuilder {
+        GCConfigBuilder::new()
+    }
+    fn has_thread_pool(&self) -> bool {
+        #[cfg(feature = "std")]
+        return self.thread_pool.is_some();
+        #[cfg(not(feature = "std"))]
+        false
+    }
+    pub(crate) fn validate(&self) -> Result<(), GCConfigError> {
+        if self.thread_pool_size == 0 && !self.deterministic && !self.has_thread_pool() {
+            return Err(GCConfigError::ZeroThreadPoolSize);
+        }
+        if self.minor_gc_trigger_size == 0 {
+            return Err(GCConfigError::ZeroMinorGCTriggerSize);
+        }
+        if self.minor_heap_size_limit < self.minor_gc_trigger_size {
+            return Err(GCConfigError::MinorHeapLimitTooSmall {
+                limit: self.minor_heap_size_limit,
+                trigger_size: self.minor_gc_trigger_size,
+            });
+        }
+        if self.major_heap_liveness == 0 {
+            return Err(GCConfigError::ZeroMajorHeapLiveness);
+        }
+        if self.major_gc_pacer_rate.is_nan() || self.major_gc_pacer_rate <= 1.0 {
+            return Err(GCConfigError::InvalidPacerRate(self.major_gc_pacer_rate));
+        }
+        if self.enable_imm_gen && self.imm_liveness == 0 {
+            return Err(GCConfigError::ZeroImmLiveness);
+        }
+        Ok(())
+    }
+}
+
+/// a greater pacer rate would let the major heap grow so much between major gcs,
+/// that it's OOM before the pacer ever triggers
+pub const MAX_MAJOR_GC_PACER_RATE: f32 = 100.0;
+
+/// NaN is kept, so it's still rejected
+fn clamp_pacer_rate(rate: f32) -> f32 {
+    if rate > MAX_MAJOR_GC_PACER_RATE {
+        MAX_MAJOR_GC_PACER_RATE
+    } else {
+        rate
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GCConfigError {
+    /// gc thread pool should have at least one thread
+    ZeroThreadPoolSize,
+    /// minor gc could never be triggered by size
+    ZeroMinorGCTriggerSize,
+    /// minor heap would be OOM before minor gc is triggered
+    MinorHeapLimitTooSmall { limit: usize, trigger_size: usize },
+    /// objects would be promoted without surviving any minor gc
+    ZeroMajorHeapLiveness,
+    /// pacer rate should be greater than 1.0, otherwise major gc would thrash
+    InvalidPacerRate(f32),
+    /// with imm generation enabled every object would be immortal
+    ZeroImmLiveness,
+}
+
+impl core::fmt::Display for GCConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            GCConfigError::ZeroThreadPoolSize => write!(f, "thread_pool_size should be at least 1"),
+            GCConfigError::ZeroMinorGCTriggerSize => {
+                write!(f, "minor_gc_trigger_size should not be 0")
+            }
+            GCConfigError::MinorHeapLimitTooSmall {
+                limit,
+                trigger_size,
+            } => write!(
+                f,
+                "minor_heap_size_limit ({}) should not be less than minor_gc_trigger_size ({})",
+                limit, trigger_size
+            ),
+            GCConfigError::ZeroMajorHeapLiveness => {
+                write!(f, "major_heap_liveness should not be 0")
+            }
+            GCConfigError::InvalidPacerRate(rate) => {
+                write!(
+                    f,
+                    "major_gc_pacer_rate should be greater than 1.0, got {}",
+                    rate
+                )
+            }
+            GCConfigError::ZeroImmLiveness => {
+                write!(
+                    f,
+                    "imm_liveness should not be 0 when imm generation is enabled"
+                )
+            }
+        }
+    }
+}
+
+impl core::error::Error for GCConfigError {}
+
+/// build a `GCConfig` from the default one, only overriding what you need.
+/// ```ignore
+/// let config = GCConfig::builder()
+///     .thread_pool_size(2)
+///     .minor_gc_trigger_size(1024 * 1024)
+///     .build()?;
+/// ```
+pub struct GCConfigBuilder {
+    config: GCConfig,
+}
+
+impl Default for GCConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GCConfigBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: GCConfig::default(),
+        }
+    }
+    pub fn thread_pool_size(mut self, thread_pool_size: usize) -> Self {
+        self.config.thread_pool_size = thread_pool_size;
+        self
+    }
+    /// share an existing pool with the gc, `thread_pool_size` is ignored
+    #[cfg(feature = "std")]
+    pub fn thread_pool(mut self, pool: Arc<rayon::ThreadPool>) -> Self {
+        self.config.thread_pool = Some(pool);
+        self
+    }
+    pub fn minor_gc_trigger_size(mut self, bytes: usize) -> Self {
+        self.config.minor_gc_trigger_size = bytes;
+        self
+    }
+    /// 0 for only triggering by size
+    pub fn minor_gc_frame_trigger(mut self, frames: usize) -> Self {
+        self.config.minor_gc_frame_trigger = frames;
+        self
+    }
+    pub fn minor_heap_size_limit(mut self, bytes: usize) -> Self {
+        self.config.minor_heap_size_limit = bytes;
+        self
+    }
+    pub fn major_heap_liveness(mut self, liveness: usize) -> Self {
+        self.config.major_heap_liveness = liveness;
+        self
+    }
+    /// should be greater than 1.0, and it's clamped to `MAX_MAJOR_GC_PACER_RATE`
+    pub fn major_gc_pacer_rate(mut self, rate: f32) -> Self {
+        self.config.major_gc_pacer_rate = clamp_pacer_rate(rate);
+        self
+    }
+    /// 0 for no limit
+    pub fn major_heap_size_limit(mut self, bytes: usize) -> Self {
+        self.config.major_heap_size_limit = bytes;
+        self
+    }
+    /// 0 for no limit
+    pub fn total_heap_size_limit(mut self, bytes: usize) -> Self {
+        self.config.total_heap_size_limit = bytes;
+        self
+    }
+    pub fn enable_imm_gen(mut self, enable: bool) -> Self {
+        self.config.enable_imm_gen = enable;
+        self
+    }
+    pub fn imm_liveness(mut self, liveness: usize) -> Self {
+        self.config.imm_liveness = liveness;
+        self
+    }
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.config.deterministic = deterministic;
+        self
+    }
+    pub fn batch_sweep(mut self, batch: bool) -> Self {
+        self.config.batch_sweep = batch;
+        self
+    }
+    /// 0 for treating every object the same
+    pub fn large_object_size(mut self, bytes: usize) -> Self {
+        self.config.large_object_size = bytes;
+        self
+    }
+    pub fn enable_compaction(mut self, enable: bool) -> Self {
+        self.config.enable_compaction = enable;
+        self
+    }
+    /// from 0.0 to 1.0
+    pub fn compaction_threshold(mut self, threshold: f32) -> Self {
+        self.config.compaction_threshold = threshold;
+        self
+    }
+    pub fn disable_minor_gen(mut self, disable: bool) -> Self {
+        self.config.disable_minor_gen = disable;
+        self
+    }
+    #[cfg(feature = "std")]
+    pub fn stw_timeout(mut self, timeout: core::time::Duration) -> Self {
+        self.config.stw_timeout = Some(timeout);
+        self
+    }
+    #[cfg(feature = "std")]
+    pub fn stw_timeout_action(mut self, action: StwTimeoutAction) -> Self {
+        self.config.stw_timeout_action = action;
+        self
+    }
+    pub fn build(self) -> Result<GCConfig, GCConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// the error of `stw` and `ctw`. there is no `AlreadyStopped`, since `stw` is nestable,
+/// stopping a stopped world only deepens the nesting.
+/// a defensive `stw` is fine as long as a successful one is paired with a `ctw`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StwError {
+    /// `ctw` is called while the world is running
+    AlreadyRunning,
+    /// the outermost `stw` gave up by `StwTimeoutAction::Skip`,
+    /// the world is running and must not be continued by a `ctw`
+    #[cfg(feature = "std")]
+    TimedOut,
+}
+
+impl core::fmt::Display for StwError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StwError::AlreadyRunning => write!(f, "the world is already running"),
+            #[cfg(feature = "std")]
+            StwError::TimedOut => write!(f, "mutators didn't reach a safepoint in time"),
+        }
+    }
+}
+
+impl core::error::Error for StwError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    /// the config could not be used by a state
+    Config(GCConfigError),
+    /// the gc thread pool could not be built, with the message from rayon
+    #[cfg(feature = "std")]
+    ThreadPool(alloc::string::String),
+}
+
+impl core::fmt::Display for StateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StateError::Config(err) => write!(f, "invalid gc config: {}", err),
+            #[cfg(feature = "std")]
+            StateError::ThreadPool(err) => {
+                write!(f, "failed to build the gc thread pool: {}", err)
+            }
         }
     }
 }
 
+impl core::error::Error for StateError {}
+
 /// according to Rust's lifetime
 /// State should always be static lifetime.
-/// if you find this is super slow plz use a better allocator
+/// if you find this is super slow plz use a better allocator through `set_allocator`
 /// if you don't know one you can use [my wrap of mimalloc](https://github.com/LemonHX/mimalloc-rust)
 pub struct State {
-    /// TODO: discuss if we can change it in runtime
+    /// fixed after creation,
+    /// the knobs tunable in runtime are copied into atomics, see `set_minor_gc_trigger_size`
     pub(crate) config: GCConfig,
 
-    pub(crate)  rayon_pool: rayon::ThreadPool,
+    /// none in deterministic mode, with `wasm`, or if the pool could not be built,
+    /// it's shared with the embedder if given by `GCConfigBuilder::thread_pool`
+    #[cfg(feature = "std")]
+    pub(crate)  rayon_pool: Option<Arc<rayon::ThreadPool>>,
 
     /// collect flags
-    pub(crate) stw: AtomicBool,
+    /// depth of nested stop the world, the world is stopped when it's not 0
+    pub(crate) stw: AtomicUsize,
+    /// mutators registered by `register_mutator`,
+    /// the outermost `stw` waits until every registered mutator is waiting in `safepoint`
+    #[cfg(feature = "std")]
+    pub(crate) mutators: Mutex<Vec<Arc<MutatorSlot>>>,
     pub(crate) start_minor_gc_flag: AtomicBool,
     pub(crate) start_major_gc_flag: AtomicBool,
+    /// depth of nested `no_gc_scope`, gc is not triggered by allocations when it's not 0
+    pub(crate) no_gc_depth: AtomicUsize,
+    /// a `mutate` closure is running, holding `start_minor_gc_flag`,
+    /// gc is not triggered by allocations meanwhile
+    pub(crate) mutating: AtomicBool,
+    /// current `GCStage` of major gc
+    pub(crate) stage: AtomicU8,
+    /// current `MinorGCStage` of minor gc
+    pub(crate) minor_stage: AtomicU8,
 
     // ========== insight ==========
     /// the size of minor heap generation
@@ -119,13 +650,48 @@ pub struct State {
     pub(crate) imm_size: AtomicUsize,
     /// the size of total heap
     pub(crate) total_size: AtomicUsize,
+    /// cumulative counters since the state is created
+    pub(crate) total_allocated_bytes: AtomicUsize,
+    pub(crate) total_allocated_objects: AtomicUsize,
+    pub(crate) total_freed_bytes: AtomicUsize,
+    pub(crate) minor_gc_count: AtomicUsize,
+    pub(crate) major_gc_count: AtomicUsize,
+    /// names of the types registered by `register_type_name`, used by heap dumps
+    pub(crate) type_names: Map<TypeId, &'static str>,
 
     /// monitoring backend
     /// default: DummyMonitoring
     pub(crate) monitoring: Box<dyn Monitoring>,
 
+    /// backs the gc heap
+    /// default: GlobalAllocator
+    pub(crate) allocator: Box<dyn Allocator>,
+
+    /// called when a heap exceeds its limit
+    /// default: AbortOomHandler
+    pub(crate) oom_handler: Box<dyn OomHandler>,
+    /// called with the type and size of every allocated object
+    /// default: None
+    pub(crate) alloc_hook: Option<Box<AllocHook>>,
+    /// current limits, starting from config, could be raised by `OomAction::GrowLimit`
+    pub(crate) minor_heap_size_limit: AtomicUsize,
+    pub(crate) major_heap_size_limit: AtomicUsize,
+    pub(crate) total_heap_size_limit: AtomicUsize,
+    /// current pacing knobs, starting from config
+    pub(crate) minor_gc_trigger_size: AtomicUsize,
+    /// bits of the f32 rate
+    pub(crate) major_gc_pacer_rate: AtomicU32,
+    /// `major_heap_size` after last major gc, major gc is paced against it
+    pub(crate) last_major_heap_size: AtomicUsize,
+    /// alive frames when last minor gc finished, see `minor_gc_frame_trigger`
+    pub(crate) last_minor_frame_count: AtomicUsize,
+
     // ========== minor_heap generation ==========
     pub(crate) current_frame_count: AtomicUsize,
+    /// popped frames, reused by `push_frame`
+    pub(crate) free_frames: Mutex<Vec<&'static GCFrame>>,
+    /// id of the next allocated object
+    pub(crate) next_object_id: AtomicU64,
     // minor_heap generation
     // frame per unit
     // any element shouldn't live more than three round
@@ -134,6 +700,31 @@ pub struct State {
     pub(crate) minor_heap_gen: Set<*mut GCHeader>,
     pub(crate) minor_heap_marked: Set<*mut GCHeader>,
     pub(crate) minor_heap_dead: Set<*mut GCHeader>,
+    // slots of every `GCWeak`, fixed by the collector after objects are found dead or moved
+    pub(crate) weak_slots: Mutex<Vec<Weak<AtomicPtr<GCHeader>>>>,
+    // slots of every `Ephemeron`, the values are traced only after the keys are found live
+    pub(crate) ephemeron_slots: Mutex<Vec<Weak<EphemeronSlot>>>,
+    // roots of both heaps added through `add_root`, with the times they are added
+    pub(crate) manual_roots: Map<*mut GCHeader, usize>,
+    // objects pinned through `GCBox::pin`, they are roots of both heaps and never moved
+    pub(crate) pinned_objects: Set<*mut GCHeader>,
+    // objects found by the last `scan_conservative_roots`, they are roots of both heaps and never moved
+    pub(crate) conservative_roots: Set<*mut GCHeader>,
+    // per type defaults applied when allocating, see `register_type`
+    pub(crate) type_registry: TypeRegistry,
+    /// objects in pinned regions, they are traced as roots and never moved or swept
+    pub(crate) pinned_region_objects: Set<*mut GCHeader>,
+    /// bytes reserved by pinned regions, not counted in any generation
+    pub(crate) pinned_size: AtomicUsize,
+    /// objects of at least `large_object_size`, they are still counted in their generations
+    pub(crate) large_object_set: Set<*mut GCHeader>,
+    pub(crate) large_object_heap_size: AtomicUsize,
+    /// cumulative counters seen by the last `validate`
+    #[cfg(feature = "validate")]
+    pub(crate) validated_counters: Mutex<[usize; 5]>,
+    /// liveness of every object seen by the last `validate`, by the id of the object
+    #[cfg(feature = "validate")]
+    pub(crate) validated_liveness: Mutex<HashMap<u64, usize>>,
 
 
     // ========== major_heap generation ==========
@@ -142,49 +733,2771 @@ pub struct State {
     pub(crate) major_heap_gen: Set<*mut GCHeader>,
     pub(crate) major_heap_marked: Set<*mut GCHeader>,
     pub(crate) major_heap_rescan_list: Set<*mut GCHeader>,
+    // grey objects left by the last `mark_step`, none if no incremental major gc is running
+    pub(crate) incremental_grey: Mutex<Option<Vec<*mut GCHeader>>>,
+    // some `Trace::trace` panicked during the marking of running major gc
+    pub(crate) major_trace_panicked: AtomicBool,
+    // nanoseconds taken by the last step of `collect_within` finishing a major gc
+    #[cfg(feature = "std")]
+    pub(crate) last_finish_nanos: AtomicU64,
+    pub(crate) major_heap_dead: Set<*mut GCHeader>,
+    // objects out of minor heap which point into minor heap,
+    // recorded by write barrier and promotion, they are treated as roots by minor gc.
+    pub(crate) remembered_set: Set<*mut GCHeader>,
 
     // ========== imm generation ==========
     // enable imm gen will greatly increase the peek performance,
     // but it will also increase the memory usage
     // and it will be harder to descover the memory leak
     pub(crate) imm_gen: Set<*mut GCHeader>,
+    // imm objects pointing out of imm generation,
+    // since imm objects are never scanned, they are the roots of major gc.
+    pub(crate) imm_remembered_set: Set<*mut GCHeader>,
 }
 
-impl State {
-    pub fn new() -> State {
-        // State{}
-        todo!()
-    }
-    pub fn stw(&self) -> () {
-        self.monitoring.start_stw();
-        self.stw
-            .compare_exchange(
-                false,
-                true,
-                std::sync::atomic::Ordering::Acquire,
-                std::sync::atomic::Ordering::Acquire,
-            )
-            .expect("[FALTAL ERROR] could not stop the world twice");
-    }
-    pub fn ctw(&self) -> () {
-        self.monitoring.end_stw();
-        self.stw
-            .compare_exchange(
-                true,
-                false,
-                std::sync::atomic::Ordering::Acquire,
-                std::sync::atomic::Ordering::Acquire,
-            )
-            .expect("[FALTAL ERROR] failed to continue the world");
-    }
-    pub fn minor_heap_gen_gc(&self) {}
+/// returned by `State::mark_step`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkProgress {
+    /// some grey objects are left, call `mark_step` again
+    Incomplete,
+    /// marking is finished, and the dead objects are freed
+    Complete,
 }
 
-unsafe impl Send for State {}
+/// a manual root, the object is unrooted when it's dropped
+pub struct RootGuard {
+    state: &'static State,
+    header: *mut GCHeader,
+}
 
-unsafe impl Sync for State {}
+impl RootGuard {
+    pub fn header(&self) -> *mut GCHeader {
+        self.header
+    }
+}
+
+impl Drop for RootGuard {
+    fn drop(&mut self) {
+        self.state.remove_root(self.header);
+    }
+}
+
+/// a scope deferring the gc triggered by allocations, see `State::no_gc_scope`
+pub struct NoGcGuard {
+    state: &'static State,
+}
+
+impl Drop for NoGcGuard {
+    fn drop(&mut self) {
+        if self.state.no_gc_depth.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // the triggers are checked again, running the gc deferred by the scope
+            self.state.poll_minor_heap();
+        }
+    }
+}
+
+/// a mutator thread registered by `State::register_mutator`, it's unregistered when it's dropped.
+/// it's bound to the registering thread
+#[cfg(feature = "std")]
+pub struct MutatorGuard {
+    state: &'static State,
+    slot: Arc<MutatorSlot>,
+    thread_bound: PhantomData<*const ()>,
+}
+
+#[cfg(feature = "std")]
+impl Drop for MutatorGuard {
+    fn drop(&mut self) {
+        let mut mutators = self.state.mutators.lock().unwrap();
+        if let Some(i) = mutators
+            .iter()
+            .position(|slot| Arc::ptr_eq(slot, &self.slot))
+        {
+            mutators.swap_remove(i);
+        }
+    }
+}
+
+/// a registered mutator, stopping the world from it never waits for itself
+#[cfg(feature = "std")]
+pub(crate) struct MutatorSlot {
+    thread: std::thread::Thread,
+    /// waiting in `safepoint`
+    parked: AtomicBool,
+}
+
+#[cfg(all(feature = "std", feature = "arena"))]
+std::thread_local! {
+    /// states current thread is running a `mutate` closure of
+    pub(crate) static MUTATING: core::cell::RefCell<Vec<usize>> =
+        const { core::cell::RefCell::new(Vec::new()) };
+}
+
+impl State {
+    /// create a collector instance from config.
+    /// panics if `minor_heap_size_limit` is less than `minor_gc_trigger_size`.
+    /// if the gc thread pool could not be built, it runs in deterministic mode instead,
+    /// use `try_new` to get the error.
+    pub fn new(config: GCConfig) -> State {
+        if let Err(err) = Self::check_config(&config) {
+            panic!("[FALTAL ERROR] {}", err);
+        }
+        // without threads, like on wasm, every phase falls back to current thread
+        #[cfg(feature = "std")]
+        let rayon_pool = Self::build_pool(&config).ok().flatten();
+        Self::from_parts(
+            config,
+            #[cfg(feature = "std")]
+            rayon_pool,
+        )
+    }
+
+    /// same as `new`, but returns the error instead of panicking,
+    /// or falling back to deterministic mode when the gc thread pool could not be built.
+    /// embedders in constrained environments, where threads may not be spawned,
+    /// should prefer `GCConfigBuilder::deterministic`, which never builds the pool.
+    pub fn try_new(config: GCConfig) -> Result<State, StateError> {
+        Self::check_config(&config).map_err(StateError::Config)?;
+        #[cfg(feature = "std")]
+        let rayon_pool =
+            Self::build_pool(&config).map_err(|err| StateError::ThreadPool(err.to_string()))?;
+        Ok(Self::from_parts(
+            config,
+            #[cfg(feature = "std")]
+            rayon_pool,
+        ))
+    }
+
+    fn check_config(config: &GCConfig) -> Result<(), GCConfigError> {
+        if config.minor_heap_size_limit < config.minor_gc_trigger_size {
+            return Err(GCConfigError::MinorHeapLimitTooSmall {
+                limit: config.minor_heap_size_limit,
+                trigger_size: config.minor_gc_trigger_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// `None` for deterministic mode, the pool of the config is reused if given
+    #[cfg(feature = "std")]
+    fn build_pool(
+        config: &GCConfig,
+    ) -> Result<Option<Arc<rayon::ThreadPool>>, rayon::ThreadPoolBuildError> {
+        if config.deterministic || cfg!(feature = "wasm") {
+            return Ok(None);
+        }
+        if let Some(pool) = &config.thread_pool {
+            return Ok(Some(pool.clone()));
+        }
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(config.thread_pool_size.max(1))
+            .thread_name(|i| format!("cgc-worker-{}", i))
+            .build()
+            .map(|pool| Some(Arc::new(pool)))
+    }
+
+    /// threads of the gc thread pool, 0 if every phase runs on the calling thread,
+    /// like in deterministic mode
+    pub fn thread_pool_size(&self) -> usize {
+        #[cfg(feature = "std")]
+        if let Some(pool) = &self.rayon_pool {
+            return pool.current_num_threads();
+        }
+        0
+    }
+
+    fn from_parts(
+        config: GCConfig,
+        #[cfg(feature = "std")] rayon_pool: Option<Arc<rayon::ThreadPool>>,
+    ) -> State {
+        State {
+            #[cfg(feature = "std")]
+            rayon_pool,
+            stw: AtomicUsize::new(0),
+            start_minor_gc_flag: AtomicBool::new(false),
+            start_major_gc_flag: AtomicBool::new(false),
+            no_gc_depth: AtomicUsize::new(0),
+            mutating: AtomicBool::new(false),
+            stage: AtomicU8::new(GCStage::Ready as u8),
+            minor_stage: AtomicU8::new(MinorGCStage::Ready as u8),
+            minor_heap_size: AtomicUsize::new(0),
+            major_heap_size: AtomicUsize::new(0),
+            imm_size: AtomicUsize::new(0),
+            total_size: AtomicUsize::new(0),
+            total_allocated_bytes: AtomicUsize::new(0),
+            total_allocated_objects: AtomicUsize::new(0),
+            total_freed_bytes: AtomicUsize::new(0),
+            minor_gc_count: AtomicUsize::new(0),
+            major_gc_count: AtomicUsize::new(0),
+            type_names: Map::new(),
+            monitoring: Box::new(DummyMonitoring {}),
+            allocator: Box::new(GlobalAllocator {}),
+            oom_handler: Box::new(AbortOomHandler {}),
+            alloc_hook: None,
+            minor_heap_size_limit: AtomicUsize::new(config.minor_heap_size_limit),
+            major_heap_size_limit: AtomicUsize::new(config.major_heap_size_limit),
+            total_heap_size_limit: AtomicUsize::new(config.total_heap_size_limit),
+            minor_gc_trigger_size: AtomicUsize::new(config.minor_gc_trigger_size),
+            major_gc_pacer_rate: AtomicU32::new(config.major_gc_pacer_rate.to_bits()),
+            last_major_heap_size: AtomicUsize::new(config.minor_gc_trigger_size),
+            last_minor_frame_count: AtomicUsize::new(0),
+            current_frame_count: AtomicUsize::new(0),
+            free_frames: Mutex::new(Vec::new()),
+            next_object_id: AtomicU64::new(0),
+            minor_heap_roots: Set::new(),
+            minor_heap_gen: Set::new(),
+            minor_heap_marked: Set::new(),
+            minor_heap_dead: Set::new(),
+            weak_slots: Mutex::new(Vec::new()),
+            ephemeron_slots: Mutex::new(Vec::new()),
+            manual_roots: Map::new(),
+            pinned_objects: Set::new(),
+            conservative_roots: Set::new(),
+            type_registry: TypeRegistry::new(),
+            pinned_region_objects: Set::new(),
+            pinned_size: AtomicUsize::new(0),
+            large_object_set: Set::new(),
+            large_object_heap_size: AtomicUsize::new(0),
+            #[cfg(feature = "validate")]
+            validated_counters: Mutex::new([0; 5]),
+            #[cfg(feature = "validate")]
+            validated_liveness: Mutex::new(HashMap::new()),
+            major_heap_roots: Set::new(),
+            major_heap_gen: Set::new(),
+            major_heap_marked: Set::new(),
+            major_heap_rescan_list: Set::new(),
+            incremental_grey: Mutex::new(None),
+            #[cfg(feature = "std")]
+            last_finish_nanos: AtomicU64::new(0),
+            #[cfg(feature = "std")]
+            mutators: Mutex::new(Vec::new()),
+            major_trace_panicked: AtomicBool::new(false),
+            major_heap_dead: Set::new(),
+            remembered_set: Set::new(),
+            imm_gen: Set::new(),
+            imm_remembered_set: Set::new(),
+            config,
+        }
+    }
+    /// stop the world, could be nested.
+    /// the world is stopped until every `stw` is paired with a `ctw`.
+    /// with `std` the outermost one returns after every other registered mutator is waiting in `safepoint`,
+    /// or returns `Err(StwError::TimedOut)` after `GCConfigBuilder::stw_timeout` with `StwTimeoutAction::Skip`,
+    /// the world is not stopped then.
+    pub fn stw(&self) -> Result<(), StwError> {
+        #[cfg(feature = "std")]
+        if !self.stop_world(true) {
+            return Err(StwError::TimedOut);
+        }
+        #[cfg(not(feature = "std"))]
+        self.stop_world(true);
+        Ok(())
+    }
+    /// `stw` for the gc itself, returns false if it's skipped.
+    /// a stop that is not `skippable` keeps waiting after the timeout like `StwTimeoutAction::Wait`,
+    /// it's for the walks the caller asked for explicitly, like `dump_heap`
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    pub(crate) fn stop_world(&self, skippable: bool) -> bool {
+        if self.stw.fetch_add(1, Ordering::SeqCst) == 0 {
+            self.monitoring.start_stw();
+            #[cfg(feature = "std")]
+            if !self.wait_for_mutators(skippable) {
+                self.stw.fetch_sub(1, Ordering::SeqCst);
+                self.monitoring.end_stw();
+                return false;
+            }
+        }
+        true
+    }
+    /// continue the world if this is the outermost `stw`,
+    /// returns `Err(StwError::AlreadyRunning)` if the world is not stopped
+    pub fn ctw(&self) -> Result<(), StwError> {
+        let depth = self
+            .stw
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |depth| {
+                depth.checked_sub(1)
+            })
+            .map_err(|_| StwError::AlreadyRunning)?;
+        if depth == 1 {
+            self.monitoring.end_stw();
+        }
+        Ok(())
+    }
+    /// register current thread as a mutator, so `stw` waits for it to reach a `safepoint`.
+    /// a registered thread should call `safepoint` periodically, like on loop back edges,
+    /// and must not block on anything else while the world could be stopped.
+    /// the thread is unregistered when the guard is dropped, by the same thread
+    #[cfg(feature = "std")]
+    pub fn register_mutator(&'static self) -> MutatorGuard {
+        let slot = Arc::new(MutatorSlot {
+            thread: std::thread::current(),
+            parked: AtomicBool::new(false),
+        });
+        self.mutators.lock().unwrap().push(slot.clone());
+        MutatorGuard {
+            state: self,
+            slot,
+            thread_bound: PhantomData,
+        }
+    }
+
+    /// a point where the mutator could be suspended,
+    /// it blocks while the world is stopped, otherwise it's just an atomic load.
+    /// it must not be called while current thread is stopping the world itself
+    #[cfg(feature = "std")]
+    pub fn safepoint(&self) {
+        if self.stw.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        loop {
+            self.park(true);
+            while self.stw.load(Ordering::SeqCst) != 0 {
+                sync::yield_now();
+            }
+            self.park(false);
+            // a stop started after the check above may have counted this thread as parked
+            if self.stw.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+        }
+    }
+
+    /// the wait of a mutator for the collector, like for a running gc,
+    /// it's also a safepoint so the collector could stop the world meanwhile
+    pub(crate) fn wait_for_collector(&self) {
+        #[cfg(feature = "std")]
+        if self.mutating_on_current_thread() {
+            panic!("[FALTAL ERROR] waiting for the gc inside `State::mutate`, it waits for the closure");
+        }
+        #[cfg(feature = "std")]
+        self.safepoint();
+        sync::yield_now();
+    }
+
+    /// mark every slot of current thread as waiting in `safepoint` or not
+    #[cfg(feature = "std")]
+    fn park(&self, parked: bool) {
+        let current = std::thread::current().id();
+        for slot in self.mutators.lock().unwrap().iter() {
+            if slot.thread.id() == current {
+                slot.parked.store(parked, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// registered mutators of other threads, not waiting in `safepoint`
+    #[cfg(feature = "std")]
+    fn outstanding_mutators(&self) -> Vec<std::thread::Thread> {
+        let current = std::thread::current().id();
+        self.mutators
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|slot| slot.thread.id() != current && !slot.parked.load(Ordering::SeqCst))
+            .map(|slot| slot.thread.clone())
+            .collect()
+    }
+
+    /// returns false if the stop is skipped
+    #[cfg(feature = "std")]
+    fn wait_for_mutators(&self, skippable: bool) -> bool {
+        let mut start = std::time::Instant::now();
+        loop {
+            let outstanding = self.outstanding_mutators();
+            if outstanding.is_empty() {
+                return true;
+            }
+            if let Some(timeout) = self.config.stw_timeout {
+                if start.elapsed() >= timeout {
+                    self.monitoring.stw_timeout(&outstanding);
+                    match self.config.stw_timeout_action {
+                        StwTimeoutAction::Skip if skippable => return false,
+                        StwTimeoutAction::Skip | StwTimeoutAction::Wait => {
+                            start = std::time::Instant::now()
+                        }
+                        StwTimeoutAction::Abort => {
+                            std::eprintln!(
+                                "[FALTAL ERROR] {} mutators didn't reach a safepoint in {:?}",
+                                outstanding.len(),
+                                timeout
+                            );
+                            std::process::abort();
+                        }
+                    }
+                }
+            }
+            sync::yield_now();
+        }
+    }
+
+    /// whether the world is stopped
+    pub fn is_stopped(&self) -> bool {
+        self.stw.load(Ordering::Acquire) != 0
+    }
+    pub fn set_monitoring(&mut self, monitoring: Box<dyn Monitoring>) {
+        self.monitoring = monitoring;
+    }
+    pub fn with_monitoring(mut self, monitoring: Box<dyn Monitoring>) -> Self {
+        self.set_monitoring(monitoring);
+        self
+    }
+
+    /// should be set before anything is allocated,
+    /// objects allocated by the previous allocator would be freed by the new one.
+    pub fn set_allocator(&mut self, allocator: Box<dyn Allocator>) {
+        self.allocator = allocator;
+    }
+    pub fn with_allocator(mut self, allocator: Box<dyn Allocator>) -> Self {
+        self.set_allocator(allocator);
+        self
+    }
+
+    pub fn set_oom_handler(&mut self, oom_handler: Box<dyn OomHandler>) {
+        self.oom_handler = oom_handler;
+    }
+
+    /// profile allocation sites, the hook is called on the allocating thread
+    /// for every object and array, with its type and size in bytes including the header.
+    /// unlike `Monitoring`, it's on the allocation path, so it should be cheap.
+    pub fn set_alloc_hook(&mut self, hook: Box<AllocHook>) {
+        self.alloc_hook = Some(hook);
+    }
+    pub fn clear_alloc_hook(&mut self) {
+        self.alloc_hook = None;
+    }
+
+    pub fn minor_gc_trigger_size(&self) -> usize {
+        self.minor_gc_trigger_size.load(Ordering::Acquire)
+    }
+    /// takes effect from the next allocation.
+    /// the pacer starts from at least one minor heap after the next major gc.
+    pub fn set_minor_gc_trigger_size(&self, bytes: usize) -> Result<(), GCConfigError> {
+        if bytes == 0 {
+            return Err(GCConfigError::ZeroMinorGCTriggerSize);
+        }
+        let limit = self.minor_heap_size_limit.load(Ordering::Acquire);
+        if limit < bytes {
+            return Err(GCConfigError::MinorHeapLimitTooSmall {
+                limit,
+                trigger_size: bytes,
+            });
+        }
+        self.minor_gc_trigger_size.store(bytes, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn major_gc_pacer_rate(&self) -> f32 {
+        f32::from_bits(self.major_gc_pacer_rate.load(Ordering::Acquire))
+    }
+    /// takes effect from the next minor gc, against the major heap size after last major gc.
+    /// it's clamped to `MAX_MAJOR_GC_PACER_RATE`
+    pub fn set_major_gc_pacer_rate(&self, rate: f32) -> Result<(), GCConfigError> {
+        let rate = clamp_pacer_rate(rate);
+        if rate.is_nan() || rate <= 1.0 {
+            return Err(GCConfigError::InvalidPacerRate(rate));
+        }
+        self.major_gc_pacer_rate
+            .store(rate.to_bits(), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn minor_heap_size_limit(&self) -> usize {
+        self.minor_heap_size_limit.load(Ordering::Acquire)
+    }
+    /// takes effect from the next allocation, the `OomHandler` is called if it's already exceeded.
+    /// it replaces the limit raised by `OomAction::GrowLimit`.
+    pub fn set_minor_heap_size_limit(&self, bytes: usize) -> Result<(), GCConfigError> {
+        let trigger_size = self.minor_gc_trigger_size();
+        if bytes < trigger_size {
+            return Err(GCConfigError::MinorHeapLimitTooSmall {
+                limit: bytes,
+                trigger_size,
+            });
+        }
+        self.minor_heap_size_limit.store(bytes, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn total_heap_size_limit(&self) -> usize {
+        self.total_heap_size_limit.load(Ordering::Acquire)
+    }
+    /// takes effect from the next allocation, 0 for no limit.
+    /// it replaces the limit raised by `OomAction::GrowLimit`.
+    pub fn set_total_heap_size_limit(&self, bytes: usize) {
+        self.total_heap_size_limit.store(bytes, Ordering::Release);
+    }
+
+    /// root an object the collector could not see otherwise,
+    /// like the ones referenced from VM stack slots or memory owned by C.
+    /// `header` should point to a live object.
+    ///
+    /// manual roots are counted, and independent from the ones of `GCBox`,
+    /// so every `add_root` should be paired with a `remove_root`,
+    /// over-rooting keeps the object alive forever,
+    /// while using an object after it's unrooted and unreachable from other roots is UB.
+    pub fn add_root(&self, header: *mut GCHeader) {
+        *self.manual_roots.entry(header).or_insert(0) += 1;
+        // the running major gc may have scanned the roots and the object referencing it already
+        self.allocate_black(header);
+    }
+    pub fn remove_root(&self, header: *mut GCHeader) {
+        if let Some(mut count) = self.manual_roots.get_mut(&header) {
+            *count -= 1;
+        }
+        self.manual_roots.remove_if(&header, |_, count| *count == 0);
+    }
+    /// root an object until the returned guard is dropped, see `add_root`
+    pub fn root(&'static self, header: *mut GCHeader) -> RootGuard {
+        self.add_root(header);
+        RootGuard {
+            state: self,
+            header,
+        }
+    }
+
+    /// treat every word in the stack range equal to a tracked header as a root,
+    /// for embedders which could not enumerate their roots precisely, like interpreters with native frames.
+    /// the roots of the previous scan are replaced, so it should cover the whole stack right before
+    /// the collection points, until `clear_conservative_roots`. returns the number of objects found.
+    ///
+    /// the words are checked against every generation instead of the frames,
+    /// since objects allocated from bump chunks are not registered to them one by one.
+    /// only the exact headers are recognized, not pointers to the data or the middle of an object.
+    /// an integer happening to equal a header retains the object and everything reachable from it,
+    /// that false retention is the price of not knowing the roots.
+    /// the words could not be told apart from integers, so they are never redirected,
+    /// and the found objects are pinned against moving like `GCBox::pin` until the next scan.
+    /// # Safety
+    /// the whole range should be readable, the bounds could be given in either order
+    pub unsafe fn scan_conservative_roots(
+        &self,
+        stack_bottom: *const u8,
+        stack_top: *const u8,
+    ) -> usize {
+        let start = stack_bottom.min(stack_top) as usize;
+        let end = stack_bottom.max(stack_top) as usize;
+        let word = core::mem::size_of::<usize>();
+        // minor gc and the sweep of major gc are held off, so no candidate is moved or freed while checking it
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.conservative_roots.clear();
+        let mut addr = (start + word - 1) & !(word - 1);
+        while addr + word <= end {
+            let header = core::ptr::read_volatile(addr as *const *mut GCHeader);
+            let tracked = self.minor_heap_gen.contains(&header)
+                || self.major_heap_gen.contains(&header)
+                || self.imm_gen.contains(&header);
+            // a running major gc may have scanned the roots already
+            if tracked && self.conservative_roots.insert(header) {
+                self.allocate_black(header);
+            }
+            addr += word;
+        }
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        self.conservative_roots.len()
+    }
+    /// forget the roots found by `scan_conservative_roots`
+    pub fn clear_conservative_roots(&self) {
+        self.conservative_roots.clear();
+    }
+
+    /// call `f` with every live object of `T` in all generations, like invalidating caches of a VM.
+    /// it runs with the world stopped and minor gc held off, and the objects allocated by `f` are not visited.
+    /// `f` must not allocate though, the collection triggered by it could free or move the objects not visited yet.
+    /// the references are only valid until the next gc, unless they are stored in a traced object.
+    pub fn for_each_of_type<T: Trace>(&self, mut f: impl FnMut(GCRef<T>)) {
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.stop_world(false);
+        let type_id = TypeId::of::<T>();
+        let headers: Vec<_> = self
+            .minor_heap_gen
+            .iter()
+            .chain(self.major_heap_gen.iter())
+            .chain(self.imm_gen.iter())
+            .map(|header| *header)
+            .filter(|header| unsafe { (**header).type_id() } == type_id)
+            .collect();
+        for header in headers {
+            f(GCRef::new(unsafe { GCCell::from_header(header) }));
+        }
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while iterating objects");
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+    }
+
+    /// whether the object is still in one of the generations, and not scheduled for freeing
+    /// by the running gc, like catching a use-after-free of an interpreter in tests.
+    /// it's checked with the world stopped, but it's only meaningful at a safepoint:
+    /// a freed header could be reused by a later allocation, and a live one could die right after.
+    pub fn is_live(&self, header: *mut GCHeader) -> bool {
+        self.stop_world(false);
+        let tracked = self.minor_heap_gen.contains(&header)
+            || self.major_heap_gen.contains(&header)
+            || self.imm_gen.contains(&header);
+        // unmarked objects are dead once the final scan is done, even before they are swept
+        let live = tracked
+            && (self.stage() != GCStage::ConcurrentSweep
+                || self.major_heap_marked.contains(&header)
+                || self.imm_gen.contains(&header));
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while checking an object");
+        live
+    }
+
+    /// start a frame for a scope, it should be ended by `pop_frame`
+    pub fn push_frame(&'static self) -> &'static GCFrame {
+        self.current_frame_count.fetch_add(1, Ordering::AcqRel);
+        if let Some(frame) = self.free_frames.lock().unwrap().pop() {
+            return frame;
+        }
+        Box::leak(Box::new(GCFrame::new(self)))
+    }
+    /// end a frame from `push_frame`,
+    /// the objects allocated in it are not rooted anymore,
+    /// so they are only kept alive if they are reachable from other roots,
+    /// except the ones marked by `GCFrame::mark_escaped`, which are handed to the parent frame.
+    /// the frame is reused by later `push_frame`.
+    /// # Safety
+    /// every `GCBox` allocated in the frame should be dropped, unless its object is escaped,
+    /// and the frame should never be used afterwards
+    pub unsafe fn pop_frame(&self, frame: &'static GCFrame) {
+        for header in frame.clear() {
+            self.minor_heap_roots.remove(&header);
+            self.major_heap_roots.remove(&header);
+        }
+        let count = self.current_frame_count.fetch_sub(1, Ordering::AcqRel) - 1;
+        self.last_minor_frame_count
+            .fetch_min(count, Ordering::AcqRel);
+        self.free_frames.lock().unwrap().push(frame);
+    }
+
+    /// called after every allocation into minor heap,
+    /// triggers minor gc when needed, and handles OOM of both heaps and of the total size.
+    /// with minor generation disabled, only major heap is polled.
+    pub(crate) fn poll_minor_heap(&self) {
+        // the gc would wait for the closure forever, it's polled again after the closure returns
+        if self.mutating_on_current_thread() {
+            return;
+        }
+        if self.config.disable_minor_gen {
+            self.poll_major_heap();
+            self.poll_total_heap();
+            return;
+        }
+        if !self.gc_deferred()
+            && (self.minor_heap_size.load(Ordering::Acquire) >= self.minor_gc_trigger_size()
+                || self.frame_triggered())
+        {
+            self.minor_heap_gen_gc();
+            self.poll_major_heap();
+        }
+        loop {
+            let size = self.minor_heap_size.load(Ordering::Acquire);
+            if size <= self.minor_heap_size_limit.load(Ordering::Acquire) {
+                break;
+            }
+            match self.oom_handler.on_minor_oom(size) {
+                OomAction::Abort => abort_on_oom("minor", size),
+                OomAction::Retry => {
+                    self.minor_heap_gen_gc();
+                    self.poll_major_heap();
+                }
+                OomAction::FlushMinor => {
+                    self.flush_minor_to_major();
+                    self.poll_major_heap();
+                }
+                OomAction::GrowLimit(limit) => {
+                    self.minor_heap_size_limit.store(limit, Ordering::Release)
+                }
+            }
+        }
+        self.poll_total_heap();
+    }
+
+    /// defer the gc triggered by allocations until the guard is dropped, for latency critical sections,
+    /// then the gc they would have triggered runs once. it could be nested, and covers every thread.
+    /// allocations still grow the heap, the limits are checked as usual, and the OOM handler is called
+    /// when one is hit, so a `Retry` collects even inside the scope.
+    /// explicit collections like `collect` or `minor_collect` are not deferred.
+    pub fn no_gc_scope(&'static self) -> NoGcGuard {
+        self.no_gc_depth.fetch_add(1, Ordering::AcqRel);
+        NoGcGuard { state: self }
+    }
+
+    /// whether current thread is running a `mutate` closure of the state,
+    /// without `std` whether any thread is
+    pub(crate) fn mutating_on_current_thread(&self) -> bool {
+        #[cfg(all(feature = "std", feature = "arena"))]
+        return MUTATING.with(|states| states.borrow().contains(&(self as *const State as usize)));
+        #[cfg(not(all(feature = "std", feature = "arena")))]
+        self.mutating.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn gc_deferred(&self) -> bool {
+        self.no_gc_depth.load(Ordering::Acquire) != 0 || self.mutating.load(Ordering::Acquire)
+    }
+
+    /// more than `minor_gc_frame_trigger` frames are alive, and they have grown since last minor gc
+    fn frame_triggered(&self) -> bool {
+        let trigger = self.config.minor_gc_frame_trigger;
+        let count = self.current_frame_count.load(Ordering::Acquire);
+        trigger != 0
+            && count > trigger
+            && count > self.last_minor_frame_count.load(Ordering::Acquire)
+    }
+
+    /// handles OOM of every generation together, 0 limit means no limit.
+    /// it's checked after the new object is rooted, like the other limits,
+    /// so a gc run by `Retry` never frees the objects referenced by it.
+    fn poll_total_heap(&self) {
+        loop {
+            let size = self.total_size.load(Ordering::Acquire);
+            let limit = self.total_heap_size_limit.load(Ordering::Acquire);
+            if limit == 0 || size <= limit {
+                return;
+            }
+            match self.oom_handler.on_total_oom(size) {
+                OomAction::Abort => abort_on_oom("total", size),
+                OomAction::Retry => {
+                    self.minor_heap_gen_gc();
+                    self.collect();
+                }
+                OomAction::FlushMinor => {
+                    self.flush_minor_to_major();
+                    self.collect();
+                }
+                OomAction::GrowLimit(limit) => {
+                    self.total_heap_size_limit.store(limit, Ordering::Release)
+                }
+            }
+        }
+    }
+
+    /// called after objects are promoted into major heap,
+    /// triggers major gc by the pacer, and handles OOM of major heap, 0 limit means no limit
+    pub(crate) fn poll_major_heap(&self) {
+        if self.mutating_on_current_thread() {
+            return;
+        }
+        if self.major_heap_size.load(Ordering::Acquire) > self.major_gc_threshold()
+            && !self.start_major_gc_flag.load(Ordering::Acquire)
+            && !self.gc_deferred()
+        {
+            self.collect();
+        }
+        loop {
+            let size = self.major_heap_size.load(Ordering::Acquire);
+            let limit = self.major_heap_size_limit.load(Ordering::Acquire);
+            if limit == 0 || size <= limit {
+                return;
+            }
+            match self.oom_handler.on_major_oom(size) {
+                OomAction::Abort => abort_on_oom("major", size),
+                OomAction::Retry | OomAction::FlushMinor => self.collect(),
+                OomAction::GrowLimit(limit) => {
+                    self.major_heap_size_limit.store(limit, Ordering::Release)
+                }
+            }
+        }
+    }
 
-// ThreadPool
-struct ThreadPool {
-    threads: Vec<std::thread::JoinHandle<()>>,
+    /// reported before and after every gc,
+    /// so the growth between the end of one gc and the start of the next one is the allocated bytes.
+    fn record_memory_usage(&self) {
+        self.monitoring.record_memory_usage(
+            self.major_heap_size.load(Ordering::Acquire),
+            self.minor_heap_size.load(Ordering::Acquire),
+        );
+    }
+
+    /// `major_gc_pacer_rate` times the major heap size after last major gc,
+    /// but no more than the major heap limit, so the pacer collects before OOM
+    pub(crate) fn major_gc_threshold(&self) -> usize {
+        let last = self.last_major_heap_size.load(Ordering::Acquire);
+        let threshold = (last as f64 * self.major_gc_pacer_rate() as f64) as usize;
+        match self.major_heap_size_limit.load(Ordering::Acquire) {
+            0 => threshold,
+            limit => threshold.min(limit),
+        }
+    }
+
+    /// collect the minor heap only, with the world stopped.
+    ///
+    /// it's a mostly copying collector:
+    /// live objects are evacuated into newly allocated cells, and every reference to them is redirected,
+    /// then the old cells are reclaimed together with the dead ones.
+    /// objects are left in place if they are pinned, or the collector could not redirect all of their references,
+    /// which means roots, or objects reported by `Tracer::visit` instead of `Tracer::visit_slot`,
+    /// nothing is moved while major gc is running.
+    ///
+    /// survivors get older, and the ones lived more than `major_heap_liveness` rounds
+    /// are promoted to major heap.
+    /// returns immediately if a minor gc is already running, or minor generation is disabled.
+    pub fn minor_heap_gen_gc(&self) {
+        if self.config.disable_minor_gen {
+            return;
+        }
+        if self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return;
+        }
+        self.minor_gc(false);
+    }
+
+    /// a minor gc promoting every survivor into major heap regardless of `major_heap_liveness`,
+    /// for relieving the minor heap when minor gc could not keep up, like from `OomAction::FlushMinor`.
+    /// the minor heap is empty afterwards, except the objects allocated meanwhile by other threads,
+    /// or if a `Trace::trace` panicked, which frees and promotes nothing.
+    /// it waits for the running minor gc instead of returning.
+    pub fn flush_minor_to_major(&self) {
+        self.wait_minor_gc(true);
+    }
+
+    /// a minor gc like `minor_heap_gen_gc`, but it waits for the running one and then runs its own,
+    /// so the objects dropped before are always collected on return.
+    /// only the minor heap is collected, survivors are promoted by `major_heap_liveness` as usual,
+    /// and they are only freed by a later major gc. it's counted in `GcStats::minor_gc_count`,
+    /// and fires `Monitoring::start_minor_gc` and `Monitoring::end_minor_gc`.
+    /// it does nothing if minor generation is disabled.
+    pub fn minor_collect(&self) {
+        self.wait_minor_gc(false);
+    }
+
+    /// a major gc like `collect`, counted in `GcStats::major_gc_count`,
+    /// and firing `Monitoring::start_major_gc` and `Monitoring::end_major_gc`.
+    /// the minor heap is marked and swept together with major heap anyway,
+    /// with `minor_first` a `minor_collect` runs before, so the survivors old enough are promoted
+    /// and the dead minor objects are freed before marking starts.
+    pub fn major_collect(&self, minor_first: bool) {
+        if minor_first {
+            self.minor_collect();
+        }
+        self.collect();
+    }
+
+    fn wait_minor_gc(&self, promote_all: bool) {
+        if self.config.disable_minor_gen {
+            return;
+        }
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.minor_gc(promote_all);
+    }
+
+    /// the minor gc after `start_minor_gc_flag` is taken, it's released at the end
+    fn minor_gc(&self, promote_all: bool) {
+        self.record_memory_usage();
+        self.monitoring
+            .start_minor_gc(self.minor_heap_size.load(Ordering::Acquire));
+        if !self.stop_world(true) {
+            // nothing is moved yet, the young objects wait for the next minor gc
+            self.monitoring
+                .end_minor_gc(self.minor_heap_size.load(Ordering::Acquire));
+            self.start_minor_gc_flag.store(false, Ordering::Release);
+            return;
+        }
+
+        // scan
+        self.set_minor_stage(MinorGCStage::Scan);
+        let mut marker = MinorMarker {
+            state: self,
+            grey: Vec::new(),
+            pinned: HashSet::new(),
+            panicked: false,
+        };
+        for root in self
+            .minor_heap_roots
+            .iter()
+            .chain(self.pinned_objects.iter())
+            .chain(self.conservative_roots.iter())
+        {
+            marker.visit(*root);
+        }
+        for root in self.manual_roots.iter() {
+            marker.visit(*root.key());
+        }
+        // the running major gc treats marked objects as live, and may still trace them
+        if self.stage() != GCStage::Ready {
+            for header in self.minor_heap_gen.iter() {
+                if unsafe { (**header).is_marked() } {
+                    marker.visit(*header);
+                }
+            }
+        }
+        // old objects pointing into minor heap
+        for remembered in self
+            .remembered_set
+            .iter()
+            .chain(self.pinned_region_objects.iter())
+        {
+            if !unsafe { self.try_trace(*remembered, &mut marker) } {
+                marker.panicked = true;
+            }
+        }
+        marker.drain();
+        // the value of an ephemeron could be the only path to the key of another one
+        loop {
+            let values = self.live_ephemeron_values(|key| {
+                !self.minor_heap_gen.contains(&key) || self.minor_heap_marked.contains(&key)
+            });
+            for value in values {
+                marker.mark(value);
+            }
+            if marker.grey.is_empty() {
+                break;
+            }
+            marker.drain();
+        }
+        if marker.panicked {
+            // the references of the panicked object are unknown, so nothing could be moved or freed
+            self.minor_heap_marked.clear();
+            self.set_minor_stage(MinorGCStage::Ready);
+            self.ctw()
+                .expect("[FALTAL ERROR] world is continued outside of minor gc");
+            self.monitoring
+                .end_minor_gc(self.minor_heap_size.load(Ordering::Acquire));
+            self.start_minor_gc_flag.store(false, Ordering::Release);
+            return;
+        }
+        let pinned = marker.pinned;
+
+        // copy
+        self.set_minor_stage(MinorGCStage::Sweep);
+        let major_gc_running = self.stage() != GCStage::Ready;
+        let mut from_space = Vec::new();
+        let mut to_space = Vec::new();
+        self.minor_heap_gen.retain(|header| {
+            let header = *header;
+            // published after the roots are scanned, it's kept in place and traced by the next gc
+            if !self.minor_heap_marked.contains(&header) && self.minor_heap_roots.contains(&header)
+            {
+                return true;
+            }
+            if !self.minor_heap_marked.contains(&header) {
+                self.major_heap_rescan_list.remove(&header);
+                self.minor_heap_dead.insert(header);
+                return false;
+            }
+            if major_gc_running
+                || pinned.contains(&header)
+                || unsafe { (*header).is_pinned() }
+                || self.large_object_set.contains(&header)
+            {
+                return true;
+            }
+            to_space.push(unsafe { GCHeader::evacuate(header) });
+            from_space.push(header);
+            false
+        });
+        for header in to_space {
+            self.minor_heap_gen.insert(header);
+        }
+        let mut forwarder = Forwarder {};
+        for header in self.minor_heap_gen.iter() {
+            unsafe { GCHeader::trace(*header, &mut forwarder) };
+        }
+        for header in self
+            .remembered_set
+            .iter()
+            .chain(self.pinned_region_objects.iter())
+        {
+            unsafe { GCHeader::trace(*header, &mut forwarder) };
+        }
+        self.fix_weak_slots(|header| self.minor_heap_dead.contains(&header));
+        self.fix_ephemeron_slots(|header| self.minor_heap_dead.contains(&header));
+        Self::finalize_dead(&self.minor_heap_dead);
+        Self::drop_dead(&self.minor_heap_dead);
+        for header in from_space {
+            unsafe { GCHeader::free(header) };
+        }
+        self.free_dead(&self.minor_heap_dead, &self.minor_heap_size);
+        self.minor_heap_marked.clear();
+
+        // promote
+        let mut promoted = Vec::new();
+        self.minor_heap_gen.retain(|header| {
+            let header = *header;
+            let gc_header = unsafe { &*header };
+            if gc_header.survive() <= self.config.major_heap_liveness && !promote_all {
+                return true;
+            }
+            let size = gc_header.size();
+            self.minor_heap_size.fetch_sub(size, Ordering::AcqRel);
+            self.major_heap_size.fetch_add(size, Ordering::AcqRel);
+            if self.minor_heap_roots.remove(&header).is_some() {
+                self.major_heap_roots.insert(header);
+            }
+            gc_header.set_generation(Generation::Major);
+            self.major_heap_gen.insert(header);
+            if major_gc_running {
+                // major gc already started, so it could not be treated as garbage in this round
+                self.mark_major(header);
+            }
+            promoted.push(header);
+            false
+        });
+        // promoted objects may still point to the young ones,
+        // and remembered ones may only point to promoted or collected ones now
+        for header in promoted {
+            if self.points_into_minor(header) {
+                self.remembered_set.insert(header);
+            }
+        }
+        self.remembered_set
+            .retain(|header| self.points_into_minor(*header));
+
+        self.debug_assert_generations();
+        self.minor_gc_count.fetch_add(1, Ordering::AcqRel);
+        self.last_minor_frame_count.store(
+            self.current_frame_count.load(Ordering::Acquire),
+            Ordering::Release,
+        );
+        self.set_minor_stage(MinorGCStage::Ready);
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued outside of minor gc");
+        self.monitoring
+            .end_minor_gc(self.minor_heap_size.load(Ordering::Acquire));
+        self.record_memory_usage();
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+    }
+
+    /// major objects survived more than `imm_liveness` rounds are moved into imm generation,
+    /// they are never marked or swept afterwards, only the ones pointing out of imm are traced.
+    fn promote_imm(&self) {
+        let mut promoted = Vec::new();
+        self.major_heap_gen.retain(|header| {
+            let header = *header;
+            let gc_header = unsafe { &*header };
+            if gc_header.survive() <= self.config.imm_liveness {
+                return true;
+            }
+            let size = gc_header.size();
+            self.major_heap_size.fetch_sub(size, Ordering::AcqRel);
+            self.imm_size.fetch_add(size, Ordering::AcqRel);
+            gc_header.set_generation(Generation::Imm);
+            self.imm_gen.insert(header);
+            promoted.push(header);
+            false
+        });
+        for header in promoted {
+            if self.points_out_of_imm(header) {
+                self.imm_remembered_set.insert(header);
+            }
+        }
+        self.imm_remembered_set
+            .retain(|header| self.points_out_of_imm(*header));
+    }
+
+    /// move the live objects of major heap out of the sparse bump chunks, so the chunks are released,
+    /// and the objects are packed by the allocator instead of holding mostly empty chunks.
+    /// a chunk is sparse when the part of it not used by the live objects of any generation
+    /// exceeds `compaction_threshold`. returns the moved bytes.
+    ///
+    /// like minor gc, the objects are copied and every reference to them is redirected through `Trace`,
+    /// and the ones whose references could not all be redirected are left in place,
+    /// which means rooted, pinned or large objects, and objects reported by `Tracer::visit`.
+    /// it waits for the running major gc, then runs with the world stopped and minor gc held off.
+    pub fn compact_major_heap(&self) -> usize {
+        while self
+            .start_major_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // an incremental major gc only advances when it's stepped
+            if self.incremental_paused() {
+                self.mark_step(usize::MAX);
+            }
+            self.wait_for_collector();
+        }
+        let moved = self.compact_major_heap_held();
+        self.start_major_gc_flag.store(false, Ordering::Release);
+        moved
+    }
+
+    /// stop tracing the objects of a `PinnedRegion` being freed.
+    /// it waits for the running major gc, which may have marked them,
+    /// then they are removed with the world stopped and minor gc held off
+    pub(crate) fn forget_pinned_objects(&self, objects: &[*mut GCHeader]) {
+        while self
+            .start_major_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // an incremental major gc only advances when it's stepped
+            if self.incremental_paused() {
+                self.mark_step(usize::MAX);
+            }
+            self.wait_for_collector();
+        }
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.stop_world(false);
+        for header in objects {
+            self.pinned_region_objects.remove(header);
+            // recorded by the write barrier when they pointed into minor heap
+            self.remembered_set.remove(header);
+        }
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while freeing pinned region");
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        self.start_major_gc_flag.store(false, Ordering::Release);
+    }
+
+    /// `compact_major_heap`, with major gc held off by the caller
+    fn compact_major_heap_held(&self) -> usize {
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        if !self.stop_world(true) {
+            self.start_minor_gc_flag.store(false, Ordering::Release);
+            return 0;
+        }
+        let moved = self.evacuate_sparse_chunks();
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while compacting");
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        moved
+    }
+
+    fn evacuate_sparse_chunks(&self) -> usize {
+        let generations = || {
+            self.minor_heap_gen
+                .iter()
+                .chain(self.major_heap_gen.iter())
+                .chain(self.imm_gen.iter())
+                .map(|header| *header)
+        };
+        let mut live: HashMap<*const Chunk, usize> = HashMap::new();
+        for header in generations() {
+            let header = unsafe { &*header };
+            if !header.chunk().is_null() {
+                *live.entry(header.chunk()).or_insert(0) += header.size();
+            }
+        }
+        let threshold = self.config.compaction_threshold as f64;
+        let sparse =
+            |chunk: *const Chunk| 1.0 - live[&chunk] as f64 / CHUNK_SIZE as f64 > threshold;
+
+        let mut unmovable = HashSet::new();
+        let mut finder = UnmovableFinder {
+            unmovable: &mut unmovable,
+        };
+        for header in generations().chain(self.pinned_region_objects.iter().map(|h| *h)) {
+            if !unsafe { self.try_trace(header, &mut finder) } {
+                // the references of the panicked object are unknown, so nothing could be moved
+                return 0;
+            }
+        }
+        for root in self
+            .minor_heap_roots
+            .iter()
+            .chain(self.major_heap_roots.iter())
+            .chain(self.pinned_objects.iter())
+            .chain(self.conservative_roots.iter())
+        {
+            unmovable.insert(*root);
+        }
+        for root in self.manual_roots.iter() {
+            unmovable.insert(*root.key());
+        }
+
+        let mut from_space = Vec::new();
+        self.major_heap_gen.retain(|header| {
+            let header = *header;
+            let gc_header = unsafe { &*header };
+            let chunk = gc_header.chunk();
+            if chunk.is_null()
+                || !sparse(chunk)
+                || gc_header.is_pinned()
+                || unmovable.contains(&header)
+                || self.large_object_set.contains(&header)
+            {
+                return true;
+            }
+            from_space.push(header);
+            false
+        });
+        let mut moved = 0;
+        for header in from_space.iter() {
+            let copy = unsafe { GCHeader::evacuate(*header) };
+            moved += unsafe { (*copy).size() };
+            self.major_heap_gen.insert(copy);
+            if self.remembered_set.remove(header).is_some() {
+                self.remembered_set.insert(copy);
+            }
+        }
+        let mut forwarder = Forwarder {};
+        for header in generations().chain(self.pinned_region_objects.iter().map(|h| *h)) {
+            unsafe { GCHeader::trace(header, &mut forwarder) };
+        }
+        self.fix_weak_slots(|_| false);
+        self.fix_ephemeron_slots(|_| false);
+        for header in from_space {
+            unsafe { GCHeader::free(header) };
+        }
+        moved
+    }
+
+    /// record a mutated object, its references could be changed arbitrarily
+    pub(crate) fn write_barrier(&self, header: *mut GCHeader) {
+        unsafe { (*header).set_dirty() };
+        let generation = unsafe { (*header).generation() };
+        let is_imm = generation == Generation::Imm;
+        // old object may point into minor heap now
+        if generation != Generation::Minor && self.points_into_minor(header) {
+            self.remembered_set.insert(header);
+        }
+        // imm object may point out of imm generation now
+        if is_imm && self.points_out_of_imm(header) {
+            self.imm_remembered_set.insert(header);
+        }
+        // the object may be scanned already, so the parallel scan could miss the new children
+        if self.stage() == GCStage::ParallelScan {
+            self.major_heap_rescan_list.insert(header);
+        }
+    }
+
+    /// whether the object references anything out of imm generation
+    pub(crate) fn points_out_of_imm(&self, header: *mut GCHeader) -> bool {
+        let mut finder = OutOfImmFinder { found: false };
+        unsafe { GCHeader::trace(header, &mut finder) };
+        finder.found
+    }
+
+    /// whether the object references anything in minor heap
+    pub(crate) fn points_into_minor(&self, header: *mut GCHeader) -> bool {
+        let mut finder = MinorFinder {
+            state: self,
+            found: false,
+        };
+        unsafe { GCHeader::trace(header, &mut finder) };
+        finder.found
+    }
+
+    /// returns true if this call marked the object,
+    /// objects out of minor heap are never marked
+    fn mark_minor(&self, header: *mut GCHeader) -> bool {
+        self.minor_heap_gen.contains(&header) && self.minor_heap_marked.insert(header)
+    }
+
+    /// the phase of the running major gc, or the running minor gc if major gc is ready.
+    /// a minor gc could run during parallel scan of major gc, it's reported as `ParallelScan` then.
+    pub fn current_phase(&self) -> GcPhase {
+        match self.stage() {
+            GCStage::Ready => self.minor_stage().into(),
+            stage => stage.into(),
+        }
+    }
+    pub(crate) fn stage(&self) -> GCStage {
+        GCStage::from_u8(self.stage.load(Ordering::Acquire))
+    }
+    fn set_stage(&self, stage: GCStage) {
+        self.stage.store(stage as u8, Ordering::Release);
+    }
+    pub(crate) fn minor_stage(&self) -> MinorGCStage {
+        MinorGCStage::from_u8(self.minor_stage.load(Ordering::Acquire))
+    }
+    fn set_minor_stage(&self, stage: MinorGCStage) {
+        self.minor_stage.store(stage as u8, Ordering::Release);
+    }
+
+    /// force a full major gc over both minor and major heap,
+    /// and block until every dead object is freed.
+    /// if a major gc is already running, it just waits for that one to finish,
+    /// an incremental one is finished on current thread, see `mark_step`.
+    ///
+    /// the world is stopped twice:
+    /// - initial scan, while marking the roots
+    /// - final scan, while rescanning objects mutated during parallel scan
+    ///
+    /// so the pause is proportional to the number of roots and mutated objects, not the heap size.
+    pub fn collect(&self) {
+        if self
+            .start_major_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            while self.start_major_gc_flag.load(Ordering::Acquire) {
+                // an incremental major gc only advances when it's stepped
+                if self.incremental_paused() {
+                    self.mark_step(usize::MAX);
+                }
+                self.wait_for_collector();
+            }
+            return;
+        }
+        let Some(grey) = self.initial_scan() else {
+            return;
+        };
+        self.parallel_mark(grey);
+        self.finish_major_gc();
+    }
+
+    /// advance an incremental major gc by tracing at most `budget` grey objects,
+    /// so the mutator could bound every pause by driving the steps between allocations.
+    /// the first step starts a major gc with the initial scan, and the step running out of
+    /// grey objects finishes it with the final scan and sweep, the world is stopped during both like `collect`.
+    /// objects mutated or allocated between steps are recorded by the write barrier, and traced again.
+    ///
+    /// returns `Incomplete` without tracing anything if a major gc not started by `mark_step` is running,
+    /// or if the major gc is abandoned by `StwTimeoutAction::Skip`, the next step starts a new one.
+    pub fn mark_step(&self, budget: usize) -> MarkProgress {
+        self.mark_step_then(budget, || true).0
+    }
+
+    /// run incremental major gc steps until `deadline`, each tracing a few grey objects,
+    /// so a frame loop could hand the collector what's left of its frame.
+    /// the marking resumes from the grey objects left by the previous call, like `mark_step`.
+    /// the final scan and sweep could not be split, so they are only started if they fit
+    /// by the duration of the last one, and the marking waits for the next call otherwise.
+    /// the initial scan of a new major gc, and the first final scan are not predicted though.
+    ///
+    /// returns `Incomplete` right away if a major gc not started by `mark_step` is running.
+    #[cfg(feature = "std")]
+    pub fn collect_within(&self, deadline: std::time::Instant) -> MarkProgress {
+        use std::time::{Duration, Instant};
+        loop {
+            let start = Instant::now();
+            if start >= deadline {
+                return MarkProgress::Incomplete;
+            }
+            let finish = Duration::from_nanos(self.last_finish_nanos.load(Ordering::Acquire));
+            let (progress, traced) =
+                self.mark_step_then(DEADLINE_STEP_BUDGET, || Instant::now() + finish <= deadline);
+            match progress {
+                MarkProgress::Complete => {
+                    let nanos = start.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+                    self.last_finish_nanos.store(nanos, Ordering::Release);
+                    return MarkProgress::Complete;
+                }
+                // blocked by another major gc, or the finish doesn't fit
+                MarkProgress::Incomplete if traced == 0 => return MarkProgress::Incomplete,
+                MarkProgress::Incomplete => {}
+            }
+        }
+    }
+
+    /// `mark_step`, but the major gc is only finished if `can_finish` allows it,
+    /// returns the progress and the traced objects
+    fn mark_step_then(
+        &self,
+        budget: usize,
+        can_finish: impl FnOnce() -> bool,
+    ) -> (MarkProgress, usize) {
+        // the lock is never held across a stop the world, which waits for the mutators.
+        // the grey objects are taken out while stepping, so a concurrent step finds none,
+        // and gives up on the major gc flag held by this one
+        let grey = self.incremental_grey.lock().unwrap().take();
+        let grey = match grey {
+            Some(grey) => grey,
+            None => {
+                if self
+                    .start_major_gc_flag
+                    .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    return (MarkProgress::Incomplete, 0);
+                }
+                match self.initial_scan() {
+                    Some(grey) => grey,
+                    None => return (MarkProgress::Incomplete, 0),
+                }
+            }
+        };
+        let mut marker = MajorMarker { state: self, grey };
+        let mut traced = 0;
+        while traced < budget {
+            if marker.grey.is_empty() {
+                let rescan: Vec<_> = self
+                    .major_heap_rescan_list
+                    .iter()
+                    .map(|h| *h)
+                    .take(budget - traced)
+                    .collect();
+                if rescan.is_empty() {
+                    break;
+                }
+                for header in rescan {
+                    self.major_heap_rescan_list.remove(&header);
+                    unsafe { (*header).take_dirty() };
+                    marker.grey.push(header);
+                }
+            }
+            let header = marker.grey.pop().unwrap();
+            unsafe {
+                self.trace_major(header, &mut marker);
+                (*header).set_color(Color::Black);
+            }
+            traced += 1;
+        }
+        if marker.grey.is_empty() && self.major_heap_rescan_list.is_empty() && can_finish() {
+            if self.finish_major_gc() {
+                (MarkProgress::Complete, traced)
+            } else {
+                (MarkProgress::Incomplete, traced)
+            }
+        } else {
+            *self.incremental_grey.lock().unwrap() = Some(marker.grey);
+            (MarkProgress::Incomplete, traced)
+        }
+    }
+
+    /// an incremental major gc is running, and no step of it is
+    fn incremental_paused(&self) -> bool {
+        self.incremental_grey.lock().unwrap().is_some()
+    }
+
+    /// start a major gc by marking the roots with the world stopped,
+    /// returns the grey objects for the parallel scan,
+    /// or none if the stop is skipped, and the major gc is abandoned
+    fn initial_scan(&self) -> Option<Vec<*mut GCHeader>> {
+        self.record_memory_usage();
+        self.monitoring
+            .start_major_gc(self.major_heap_size.load(Ordering::Acquire));
+        if !self.stop_world(true) {
+            self.abandon_major_gc();
+            return None;
+        }
+        let mut marker = MajorMarker {
+            state: self,
+            grey: Vec::new(),
+        };
+        self.visit_roots(&mut marker);
+        for remembered in self
+            .imm_remembered_set
+            .iter()
+            .chain(self.pinned_region_objects.iter())
+        {
+            unsafe { self.trace_major(*remembered, &mut marker) };
+        }
+        self.set_stage(GCStage::ParallelScan);
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued outside of major gc");
+        Some(marker.grey)
+    }
+
+    /// give up the running major gc after its stop is skipped, before anything is swept.
+    /// the marks are cleared, so the next major gc starts over from the roots
+    fn abandon_major_gc(&self) {
+        self.set_stage(GCStage::Ready);
+        for header in self.major_heap_rescan_list.iter() {
+            unsafe { (**header).take_dirty() };
+        }
+        self.major_heap_rescan_list.clear();
+        for header in self.major_heap_marked.iter() {
+            unsafe { (**header).unmark() };
+        }
+        self.major_heap_marked.clear();
+        self.monitoring
+            .end_major_gc(self.major_heap_size.load(Ordering::Acquire));
+        self.start_major_gc_flag.store(false, Ordering::Release);
+    }
+
+    /// every root of both heaps
+    fn visit_roots(&self, tracer: &mut dyn Tracer) {
+        for root in self
+            .minor_heap_roots
+            .iter()
+            .chain(self.major_heap_roots.iter())
+            .chain(self.pinned_objects.iter())
+            .chain(self.conservative_roots.iter())
+        {
+            tracer.visit(*root);
+        }
+        for root in self.manual_roots.iter() {
+            tracer.visit(*root.key());
+        }
+    }
+
+    /// rescan the roots and the mutated objects with the world stopped, then sweep the unmarked ones.
+    /// returns false if the stop is skipped, and the major gc is abandoned
+    fn finish_major_gc(&self) -> bool {
+        let mut marker = MajorMarker {
+            state: self,
+            grey: Vec::new(),
+        };
+        if !self.stop_world(true) {
+            self.abandon_major_gc();
+            return false;
+        }
+        self.set_stage(GCStage::FinalScan);
+        // roots added during parallel scan may hold the only reference to an object
+        // moved out of a scanned one, like `GCRoot::new` of a field cleared afterwards
+        self.visit_roots(&mut marker);
+        let rescan: Vec<_> = self.major_heap_rescan_list.iter().map(|h| *h).collect();
+        self.major_heap_rescan_list.clear();
+        // pinned regions are never recorded by the write barrier, so they are always rescanned
+        for header in self.pinned_region_objects.iter() {
+            unsafe { self.trace_major(*header, &mut marker) };
+        }
+        for header in rescan {
+            unsafe {
+                (*header).take_dirty();
+                self.trace_major(header, &mut marker);
+                (*header).set_color(Color::Black);
+            }
+        }
+        self.parallel_mark(core::mem::take(&mut marker.grey));
+        loop {
+            for value in self.live_ephemeron_values(|key| self.is_marked_major(key)) {
+                marker.visit(value);
+            }
+            if marker.grey.is_empty() {
+                break;
+            }
+            self.parallel_mark(core::mem::take(&mut marker.grey));
+        }
+        if self.major_trace_panicked.swap(false, Ordering::AcqRel) {
+            // the references of the panicked object are unknown, so everything is kept in this round
+            for header in self.minor_heap_gen.iter().chain(self.major_heap_gen.iter()) {
+                self.mark_major(*header);
+            }
+        }
+        // unmarked objects are dead now, clear the weak references before mutators could upgrade them
+        self.fix_weak_slots(|header| unsafe { !(*header).is_marked() && !(*header).is_imm() });
+        self.fix_ephemeron_slots(|header| !self.is_marked_major(header));
+        self.set_stage(GCStage::ConcurrentSweep);
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued outside of major gc");
+
+        // concurrent sweep
+        // minor gc is held off, so the minor dead set is only freed here
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        Self::collect_unmarked(&self.minor_heap_gen, &self.minor_heap_dead);
+        Self::collect_unmarked(&self.major_heap_gen, &self.major_heap_dead);
+        self.remembered_set
+            .retain(|header| !self.major_heap_dead.contains(header));
+        self.sweep_dead();
+        if self.config.enable_imm_gen {
+            self.promote_imm();
+        }
+        // the fragmentation is measured before a minor gc could move anything again
+        let compact = self.config.enable_compaction && self.stop_world(true) && {
+            let fragmentation = self.fragmentation_report_held().major.fragmentation();
+            self.ctw()
+                .expect("[FALTAL ERROR] world is continued while taking fragmentation report");
+            fragmentation > self.config.compaction_threshold as f64
+        };
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        for header in self.major_heap_marked.iter() {
+            unsafe { (**header).unmark() };
+        }
+        self.major_heap_marked.clear();
+
+        self.set_stage(GCStage::Ready);
+        if compact {
+            self.compact_major_heap_held();
+        }
+        // an empty heap would trigger major gc after every minor gc, so the pacer starts from one minor heap
+        let major_heap_size = self.major_heap_size.load(Ordering::Acquire);
+        self.last_major_heap_size.store(
+            major_heap_size.max(self.minor_gc_trigger_size()),
+            Ordering::Release,
+        );
+        self.major_gc_count.fetch_add(1, Ordering::AcqRel);
+        self.monitoring.end_major_gc(major_heap_size);
+        self.record_memory_usage();
+        self.start_major_gc_flag.store(false, Ordering::Release);
+        true
+    }
+
+    /// mark everything reachable from the grey objects on the gc thread pool,
+    /// returns when every worker runs out of grey objects.
+    /// in deterministic mode they are marked on current thread.
+    fn parallel_mark(&self, grey: Vec<*mut GCHeader>) {
+        #[cfg(feature = "std")]
+        if let Some(pool) = &self.rayon_pool {
+            let (queue, locals) = GreyQueue::new(pool.current_num_threads());
+            for header in grey {
+                queue.push(header);
+            }
+            let queue = &queue;
+            pool.scope(move |scope| {
+                for (index, local) in locals.into_iter().enumerate() {
+                    scope.spawn(move |_| self.mark_worker(queue.worker(index, local)));
+                }
+            });
+            return;
+        }
+        let mut marker = MajorMarker { state: self, grey };
+        while let Some(header) = marker.grey.pop() {
+            unsafe {
+                self.trace_major(header, &mut marker);
+                (*header).set_color(Color::Black);
+            }
+        }
+    }
+
+    /// mark on one gc worker until every worker runs out of grey objects,
+    /// the newly found ones are pushed to its own deque, where idle workers could steal them.
+    #[cfg(feature = "std")]
+    fn mark_worker(&self, worker: GreyWorker<'_>) {
+        let mut marker = MajorMarker {
+            state: self,
+            grey: Vec::new(),
+        };
+        while let Some(header) = worker.pop() {
+            unsafe {
+                self.trace_major(header, &mut marker);
+                (*header).set_color(Color::Black);
+            }
+            for grey in marker.grey.drain(..) {
+                worker.push(grey);
+            }
+        }
+    }
+
+    /// `GCHeader::trace`, but a panicking `Trace::trace` is caught with `std`,
+    /// it's reported to monitoring and returns false.
+    /// the object is still marked, while its references may be reported partially.
+    unsafe fn try_trace(&self, header: *mut GCHeader, tracer: &mut dyn Tracer) -> bool {
+        #[cfg(feature = "std")]
+        {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                GCHeader::trace(header, tracer)
+            }));
+            if result.is_err() {
+                self.monitoring
+                    .trace_panicked((*header).type_id(), (*header).type_name());
+                return false;
+            }
+            true
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            GCHeader::trace(header, tracer);
+            true
+        }
+    }
+
+    /// trace an object for major gc, if it panics nothing is freed in this round
+    unsafe fn trace_major(&self, header: *mut GCHeader, tracer: &mut dyn Tracer) {
+        if !self.try_trace(header, tracer) {
+            self.major_trace_panicked.store(true, Ordering::Release);
+        }
+    }
+
+    /// returns true if this call marked the object
+    fn mark_major(&self, header: *mut GCHeader) -> bool {
+        if unsafe { (*header).mark() } {
+            self.major_heap_marked.insert(header);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// clear the weak slots pointing to dead objects, redirect the ones pointing to moved objects,
+    /// and forget the slots no `GCWeak` holds anymore
+    fn fix_weak_slots(&self, is_dead: impl Fn(*mut GCHeader) -> bool) {
+        self.weak_slots.lock().unwrap().retain(|slot| {
+            let slot = match slot.upgrade() {
+                Some(slot) => slot,
+                None => return false,
+            };
+            let header = slot.load(Ordering::Acquire);
+            if header.is_null() {
+                return false;
+            }
+            if is_dead(header) {
+                slot.store(core::ptr::null_mut(), Ordering::Release);
+                return false;
+            }
+            let forward = unsafe { (*header).forward() };
+            if !forward.is_null() {
+                slot.store(forward, Ordering::Release);
+            }
+            true
+        });
+    }
+
+    /// marked in the running major gc, or never collected by it
+    fn is_marked_major(&self, header: *mut GCHeader) -> bool {
+        let unmarked = unsafe { !(*header).is_marked() && !(*header).is_imm() };
+        !unmarked || self.pinned_region_objects.contains(&header)
+    }
+
+    /// the values of the ephemerons whose keys are live by `is_live`, including the ones already marked
+    fn live_ephemeron_values(&self, is_live: impl Fn(*mut GCHeader) -> bool) -> Vec<*mut GCHeader> {
+        let slots = self.ephemeron_slots.lock().unwrap();
+        slots
+            .iter()
+            .filter_map(|slot| slot.upgrade())
+            .filter_map(|slot| {
+                let key = slot.key.load(Ordering::Acquire);
+                let value = slot.value.load(Ordering::Acquire);
+                (!key.is_null() && !value.is_null() && is_live(key)).then_some(value)
+            })
+            .collect()
+    }
+
+    /// clear the ephemerons whose key or value is dead, redirect the moved ones,
+    /// and forget the slots no `Ephemeron` holds anymore
+    fn fix_ephemeron_slots(&self, is_dead: impl Fn(*mut GCHeader) -> bool) {
+        self.ephemeron_slots.lock().unwrap().retain(|slot| {
+            let slot = match slot.upgrade() {
+                Some(slot) => slot,
+                None => return false,
+            };
+            let key = slot.key.load(Ordering::Acquire);
+            let value = slot.value.load(Ordering::Acquire);
+            if key.is_null() || value.is_null() {
+                return false;
+            }
+            if is_dead(key) || is_dead(value) {
+                slot.key.store(core::ptr::null_mut(), Ordering::Release);
+                slot.value.store(core::ptr::null_mut(), Ordering::Release);
+                return false;
+            }
+            for (slot, header) in [(&slot.key, key), (&slot.value, value)] {
+                let forward = unsafe { (*header).forward() };
+                if !forward.is_null() {
+                    slot.store(forward, Ordering::Release);
+                }
+            }
+            true
+        });
+    }
+
+    /// move every unmarked object of the generation into the dead set
+    fn collect_unmarked(gen: &Set<*mut GCHeader>, dead: &Set<*mut GCHeader>) {
+        gen.retain(|header| {
+            if unsafe { (**header).is_marked() } {
+                true
+            } else {
+                dead.insert(*header);
+                false
+            }
+        });
+    }
+
+    /// run the finalizers of every object in the dead set,
+    /// before dropping or freeing anything the finalizers could read
+    fn finalize_dead(dead: &Set<*mut GCHeader>) {
+        for header in dead.iter() {
+            unsafe { GCHeader::finalize(*header) };
+        }
+    }
+
+    /// run the drop glue of every object in the dead set,
+    /// before freeing anything the `Drop` impls could read
+    fn drop_dead(dead: &Set<*mut GCHeader>) {
+        for header in dead.iter() {
+            unsafe { GCHeader::drop_data(*header) };
+        }
+    }
+
+    /// finalize, drop and free the dead objects of both heaps.
+    /// mutators keep running, the freed headers are already out of every generation,
+    /// and unregistered from their frames before the memory is handed back to the allocator.
+    /// only the freeing runs on the gc thread pool, the finalizers and the drop glue run on
+    /// the collecting thread, since `Trace` objects need not be `Send`, like one holding an `Rc`.
+    fn sweep_dead(&self) {
+        let minor_dead: Vec<_> = self.minor_heap_dead.iter().map(|h| *h).collect();
+        let major_dead: Vec<_> = self.major_heap_dead.iter().map(|h| *h).collect();
+        self.minor_heap_dead.clear();
+        self.major_heap_dead.clear();
+        let dead = || minor_dead.iter().chain(major_dead.iter()).copied();
+        for header in dead() {
+            unsafe { GCHeader::finalize(header) };
+        }
+        for header in dead() {
+            unsafe { GCHeader::drop_data(header) };
+        }
+        self.forget_large_objects(dead());
+        for (dead, heap_size) in [
+            (&minor_dead, &self.minor_heap_size),
+            (&major_dead, &self.major_heap_size),
+        ] {
+            let freed = if self.config.batch_sweep {
+                unsafe { GCHeader::free_batch(dead) }
+            } else {
+                let freed = AtomicUsize::new(0);
+                self.parallel_for_each(dead.iter().copied(), |header| {
+                    let size = unsafe { GCHeader::free(header) };
+                    freed.fetch_add(size, Ordering::AcqRel);
+                });
+                freed.load(Ordering::Acquire)
+            };
+            self.shrink_heap(heap_size, freed);
+        }
+    }
+
+    /// split the headers into tasks for the gc thread pool, returns after every task is done.
+    /// in deterministic mode they are visited in order on current thread.
+    fn parallel_for_each(
+        &self,
+        headers: impl Iterator<Item = *mut GCHeader>,
+        f: impl Fn(*mut GCHeader) + Sync,
+    ) {
+        #[cfg(feature = "std")]
+        if let Some(pool) = &self.rayon_pool {
+            let headers: Vec<_> = headers.collect();
+            let tasks: Vec<_> = headers
+                .chunks(SWEEP_TASK_SIZE)
+                .map(|chunk| GreyObjects(chunk.to_vec()))
+                .collect();
+            let f = &f;
+            pool.scope(move |scope| {
+                for task in tasks {
+                    scope.spawn(move |_| {
+                        for header in task.into_inner() {
+                            f(header);
+                        }
+                    });
+                }
+            });
+            return;
+        }
+        headers.for_each(f);
+    }
+
+    /// objects allocated during major gc are treated as live in this round,
+    /// the ones allocated during parallel scan are rescanned, since they could hold
+    /// the only reference to an object moved out of a scanned one.
+    pub(crate) fn allocate_black(&self, header: *mut GCHeader) {
+        match self.stage() {
+            GCStage::Ready => {}
+            GCStage::ParallelScan => {
+                self.mark_major(header);
+                self.major_heap_rescan_list.insert(header);
+            }
+            GCStage::FinalScan | GCStage::ConcurrentSweep => {
+                self.mark_major(header);
+            }
+        }
+    }
+
+    /// every object should be stored in the set of its generation, only checked in debug builds
+    fn debug_assert_generations(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        for (set, generation) in [
+            (&self.minor_heap_gen, Generation::Minor),
+            (&self.major_heap_gen, Generation::Major),
+            (&self.imm_gen, Generation::Imm),
+        ] {
+            for header in set.iter() {
+                debug_assert_eq!(
+                    unsafe { (**header).generation() },
+                    generation,
+                    "[FALTAL ERROR] object {:p} is in the wrong generation",
+                    *header
+                );
+            }
+        }
+    }
+
+    /// free every object in the dead set, and shrink the heap size
+    fn free_dead(&self, dead: &Set<*mut GCHeader>, heap_size: &AtomicUsize) {
+        self.forget_large_objects(dead.iter().map(|h| *h));
+        if self.config.batch_sweep {
+            let headers: Vec<_> = dead.iter().map(|h| *h).collect();
+            let size = unsafe { GCHeader::free_batch(&headers) };
+            self.shrink_heap(heap_size, size);
+        } else {
+            for header in dead.iter() {
+                let size = unsafe { GCHeader::free(*header) };
+                self.shrink_heap(heap_size, size);
+            }
+        }
+        dead.clear();
+    }
+
+    /// stop tracking the dead large objects before they are freed
+    fn forget_large_objects(&self, dead: impl Iterator<Item = *mut GCHeader>) {
+        if self.large_object_set.is_empty() {
+            return;
+        }
+        for header in dead {
+            if self.large_object_set.remove(&header).is_some() {
+                let size = unsafe { (*header).size() };
+                self.large_object_heap_size
+                    .fetch_sub(size, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// account the freed dead objects
+    fn shrink_heap(&self, heap_size: &AtomicUsize, freed: usize) {
+        heap_size.fetch_sub(freed, Ordering::AcqRel);
+        self.total_size.fetch_sub(freed, Ordering::AcqRel);
+        self.total_freed_bytes.fetch_add(freed, Ordering::AcqRel);
+    }
+}
+
+/// without `std` there is no process to abort, so it panics instead
+fn abort_on_oom(heap: &str, size: usize) -> ! {
+    #[cfg(feature = "std")]
+    {
+        std::eprintln!("[FALTAL ERROR] {} heap OOM with {} bytes", heap, size);
+        std::process::abort();
+    }
+    #[cfg(not(feature = "std"))]
+    panic!("[FALTAL ERROR] {} heap OOM with {} bytes", heap, size);
+}
+
+/// grey objects traced by every step of `collect_within` before checking the deadline
+#[cfg(feature = "std")]
+const DEADLINE_STEP_BUDGET: usize = 256;
+
+/// dead objects are split into tasks of this size for parallel sweeping
+#[cfg(feature = "std")]
+const SWEEP_TASK_SIZE: usize = 1024;
+
+/// a batch of objects sent to a gc worker by `parallel_for_each`
+#[cfg(feature = "std")]
+struct GreyObjects(Vec<*mut GCHeader>);
+#[cfg(feature = "std")]
+unsafe impl Send for GreyObjects {}
+
+#[cfg(feature = "std")]
+impl GreyObjects {
+    fn into_inner(self) -> Vec<*mut GCHeader> {
+        self.0
+    }
+}
+
+/// marks every visited object for major gc
+struct MajorMarker<'a> {
+    state: &'a State,
+    /// marked objects whose children are not visited yet
+    grey: Vec<*mut GCHeader>,
+}
+
+impl Tracer for MajorMarker<'_> {
+    fn visit(&mut self, header: *mut GCHeader) {
+        if unsafe { (*header).is_imm() } {
+            return;
+        }
+        if self.state.mark_major(header) {
+            self.grey.push(header);
+        }
+    }
+}
+
+/// checks whether any visited object is in minor heap
+struct MinorFinder<'a> {
+    state: &'a State,
+    found: bool,
+}
+
+impl Tracer for MinorFinder<'_> {
+    fn visit(&mut self, header: *mut GCHeader) {
+        self.found = self.found || self.state.minor_heap_gen.contains(&header);
+    }
+}
+
+/// checks whether any visited object is out of imm generation
+struct OutOfImmFinder {
+    found: bool,
+}
+
+impl Tracer for OutOfImmFinder {
+    fn visit(&mut self, header: *mut GCHeader) {
+        self.found = self.found || unsafe { !(*header).is_imm() };
+    }
+}
+
+/// collects the objects reported by `Tracer::visit`, their references could not be redirected
+struct UnmovableFinder<'a> {
+    unmovable: &'a mut HashSet<*mut GCHeader>,
+}
+
+impl Tracer for UnmovableFinder<'_> {
+    fn visit(&mut self, header: *mut GCHeader) {
+        self.unmovable.insert(header);
+    }
+
+    fn visit_slot(&mut self, _slot: &mut dyn GCSlot) {}
+}
+
+/// redirects every slot pointing to a moved object
+struct Forwarder {}
+
+impl Tracer for Forwarder {
+    fn visit(&mut self, _header: *mut GCHeader) {}
+
+    fn visit_slot(&mut self, slot: &mut dyn GCSlot) {
+        let forward = unsafe { (*slot.header()).forward() };
+        if !forward.is_null() {
+            unsafe { slot.redirect(forward) };
+        }
+    }
+}
+
+/// marks every visited object in minor heap for minor gc
+struct MinorMarker<'a> {
+    state: &'a State,
+    /// marked objects whose children are not visited yet
+    grey: Vec<*mut GCHeader>,
+    /// objects which could not be moved, since some references to them could not be redirected
+    pinned: HashSet<*mut GCHeader>,
+    /// some `Trace::trace` panicked
+    panicked: bool,
+}
+
+impl MinorMarker<'_> {
+    /// mark everything in minor heap reachable from the grey objects
+    fn drain(&mut self) {
+        while let Some(header) = self.grey.pop() {
+            let state = self.state;
+            if !unsafe { state.try_trace(header, self) } {
+                self.panicked = true;
+            }
+        }
+    }
+}
+
+impl MinorMarker<'_> {
+    fn mark(&mut self, header: *mut GCHeader) {
+        if self.state.mark_minor(header) {
+            self.grey.push(header);
+        }
+    }
+}
+
+impl Tracer for MinorMarker<'_> {
+    fn visit(&mut self, header: *mut GCHeader) {
+        self.pinned.insert(header);
+        self.mark(header);
+    }
+
+    fn visit_slot(&mut self, slot: &mut dyn GCSlot) {
+        self.mark(slot.header());
+    }
+}
+
+// every collection of the state is a concurrent one or behind a lock,
+// and the raw headers in them are only dereferenced by the collector while the objects are alive.
+// it doesn't make the handles of objects thread safe, see `GCRoot` for sending an object.
+unsafe impl Send for State {}
+
+unsafe impl Sync for State {}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{
+        GCConfig, GCConfigError, MarkProgress, OomAction, OomHandler, State,
+        MAX_MAJOR_GC_PACER_RATE,
+    };
+    use crate::gc_box::{GCRoot, Generation};
+    use crate::test_util::{env, env_of, env_with, full_gc, get, is_tracked, Leaf, Node};
+    use crate::trace::{Trace, Tracer};
+
+    #[test]
+    fn root_added_during_incremental_mark_is_kept() {
+        let (state, frame) = env();
+        let child = frame.alloc_in(Node(2, vec![]), Generation::Major);
+        let mut parent = frame.alloc_in(Node(1, vec![child.as_ref()]), Generation::Major);
+        let child_header = child.header();
+        drop(child);
+        // only the initial scan, the parent is still grey
+        state.mark_step(0);
+        let root = GCRoot::new(&get(&parent).1[0]);
+        parent.as_mut().1.clear();
+        while state.mark_step(1) != MarkProgress::Complete {}
+        assert!(is_tracked(state, child_header));
+        assert_eq!(root.0, 2);
+        drop(root);
+        full_gc(state);
+        assert!(!is_tracked(state, child_header));
+    }
+
+    #[test]
+    fn stepwise_marking_keeps_the_same_objects_as_collect() {
+        // root -> b -> (c, d), and the unreachable x <-> y and z.
+        // y is linked to the root after the marking starts
+        let run = |stepwise: bool| {
+            let (state, frame) = env();
+            let major = |children| frame.alloc_in(Node(0, children), Generation::Major);
+            let c = major(vec![]);
+            let d = major(vec![]);
+            let b = major(vec![c.as_ref(), d.as_ref()]);
+            let mut root = major(vec![b.as_ref()]);
+            let mut x = major(vec![]);
+            let y = major(vec![x.as_ref()]);
+            x.as_mut().1.push(y.as_ref());
+            let z = major(vec![]);
+            let y_ref = y.as_ref();
+            let headers = vec![
+                root.header(),
+                b.header(),
+                c.header(),
+                d.header(),
+                x.header(),
+                y.header(),
+                z.header(),
+            ];
+            drop((b, c, d, x, y, z));
+            if stepwise {
+                // only the initial scan, the root is still grey
+                state.mark_step(0);
+                root.as_mut().1.push(y_ref);
+                while state.mark_step(1) != MarkProgress::Complete {}
+            } else {
+                root.as_mut().1.push(y_ref);
+                state.collect();
+            }
+            let survivors: Vec<bool> = headers
+                .into_iter()
+                .map(|header| is_tracked(state, header))
+                .collect();
+            drop(root);
+            survivors
+        };
+        let survivors = run(true);
+        assert_eq!(survivors, run(false));
+        assert_eq!(survivors, vec![true, true, true, true, true, true, false]);
+    }
+
+    #[test]
+    fn sweep_runs_the_drop_glue_of_every_dead_object() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        struct Counted;
+        impl Trace for Counted {
+            fn trace(&self, _: &mut dyn Tracer) {}
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let (state, frame) = env();
+        let old: Vec<_> = (0..3000).map(|_| frame.alloc(Counted)).collect();
+        for _ in 0..6 {
+            state.minor_heap_gen_gc();
+        }
+        assert_eq!(state.major_heap_gen.len(), 3000);
+        let young: Vec<_> = (0..10).map(|_| frame.alloc(Counted)).collect();
+        drop(old);
+        drop(young);
+        state.collect();
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 3010);
+        assert_eq!(state.major_heap_size.load(Ordering::Acquire), 0);
+        assert_eq!(state.minor_heap_size.load(Ordering::Acquire), 0);
+        assert_eq!(state.total_size.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn dead_objects_are_dropped_on_the_collecting_thread() {
+        use core::cell::Cell;
+
+        std::thread_local! {
+            static DROPPED_HERE: Cell<usize> = const { Cell::new(0) };
+        }
+        /// holds thread affine data, so it's not `Send`
+        struct Local(std::rc::Rc<()>);
+        impl Trace for Local {
+            fn trace(&self, _: &mut dyn Tracer) {}
+        }
+        impl Drop for Local {
+            fn drop(&mut self) {
+                assert_eq!(std::rc::Rc::strong_count(&self.0), 1);
+                DROPPED_HERE.with(|dropped| dropped.set(dropped.get() + 1));
+            }
+        }
+        let config = GCConfig::builder().thread_pool_size(4).build().unwrap();
+        let (state, frame) = env_with(config);
+        let objects: Vec<_> = (0..5000)
+            .map(|_| frame.alloc(Local(std::rc::Rc::new(()))))
+            .collect();
+        for _ in 0..6 {
+            state.minor_heap_gen_gc();
+        }
+        drop(objects);
+        state.collect();
+        assert_eq!(DROPPED_HERE.with(|dropped| dropped.get()), 5000);
+    }
+
+    #[test]
+    fn major_gc_is_paced_by_the_major_heap_after_last_major_gc() {
+        let trigger = 16 * 1024;
+        let config = GCConfig::builder()
+            .minor_gc_trigger_size(trigger)
+            .major_heap_liveness(1)
+            .major_gc_pacer_rate(3.0)
+            .build()
+            .unwrap();
+        let (state, frame) = env_with(config);
+        assert_eq!(state.major_gc_threshold(), 3 * trigger);
+        let mut keep = Vec::new();
+        let mut majors = 0;
+        while majors < 2 {
+            let threshold = state.major_gc_threshold();
+            keep.push(frame.alloc(Leaf(keep.len() as u64)));
+            assert!(keep.len() < 1_000_000);
+            if state.major_gc_count.load(Ordering::Acquire) == majors {
+                // no major gc below the threshold
+                assert!(state.major_heap_size.load(Ordering::Acquire) <= threshold);
+                continue;
+            }
+            majors += 1;
+            let major_heap_size = state.major_heap_size.load(Ordering::Acquire);
+            assert_eq!(
+                state.last_major_heap_size.load(Ordering::Acquire),
+                major_heap_size.max(trigger)
+            );
+            assert_eq!(
+                state.major_gc_threshold(),
+                (major_heap_size.max(trigger) as f64 * 3.0) as usize
+            );
+        }
+    }
+
+    #[test]
+    fn disabled_minor_gen_allocates_into_major_heap() {
+        let config = GCConfig::builder()
+            .disable_minor_gen(true)
+            .minor_gc_trigger_size(1024)
+            .minor_heap_size_limit(4096)
+            .build()
+            .unwrap();
+        let (state, frame) = env_with(config);
+        let keep = frame.alloc(Node(1, vec![]));
+        for i in 0..10_000 {
+            drop(frame.alloc(Node(i, vec![])));
+        }
+        state.minor_heap_gen_gc();
+        assert_eq!(state.minor_gc_count.load(Ordering::Acquire), 0);
+        assert!(state.major_gc_count.load(Ordering::Acquire) > 0);
+        assert_eq!(state.minor_heap_size.load(Ordering::Acquire), 0);
+        assert!(state.minor_heap_gen.is_empty());
+        assert!(state.major_heap_gen.contains(&keep.header()));
+        assert_eq!(get(&keep).0, 1);
+        drop(keep);
+        state.collect();
+        assert_eq!(state.total_size.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn generations_are_ordered_from_the_youngest() {
+        assert!(Generation::Minor < Generation::Major);
+        assert!(Generation::Major < Generation::Imm);
+        assert_eq!(Generation::Imm.max(Generation::Minor), Generation::Imm);
+    }
+
+    #[test]
+    fn total_heap_limit_calls_the_oom_handler() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+        struct Grow;
+
+        impl OomHandler for Grow {
+            fn on_minor_oom(&self, _: usize) -> OomAction {
+                OomAction::Abort
+            }
+            fn on_major_oom(&self, _: usize) -> OomAction {
+                OomAction::Abort
+            }
+            fn on_total_oom(&self, total_size: usize) -> OomAction {
+                FIRED.fetch_add(1, Ordering::AcqRel);
+                OomAction::GrowLimit(total_size * 2)
+            }
+        }
+
+        let limit = 64 * 1024;
+        let config = GCConfig::builder()
+            .minor_gc_trigger_size(4096)
+            .major_heap_liveness(1)
+            .total_heap_size_limit(limit)
+            .build()
+            .unwrap();
+        let mut state = State::new(config);
+        state.set_oom_handler(Box::new(Grow));
+        let (state, frame) = env_of(state);
+        let mut keep = Vec::new();
+        while FIRED.load(Ordering::Acquire) == 0 {
+            keep.push(frame.alloc(Leaf(1)));
+            assert!(keep.len() < 1_000_000);
+        }
+        assert!(state.total_size.load(Ordering::Acquire) > limit);
+        assert!(state.total_heap_size_limit() >= state.total_size.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn deep_frames_trigger_minor_gc() {
+        let config = GCConfig::builder()
+            .minor_gc_frame_trigger(64)
+            .build()
+            .unwrap();
+        let (state, _) = env_with(config);
+        let minor_gc_count = || state.minor_gc_count.load(Ordering::Acquire);
+        let mut frames = Vec::new();
+        for i in 0..64 {
+            let frame = state.push_frame();
+            drop(frame.alloc(Leaf(i)));
+            frames.push(frame);
+        }
+        assert_eq!(minor_gc_count(), 0);
+        let deepest = state.push_frame();
+        drop(deepest.alloc(Leaf(64)));
+        assert_eq!(minor_gc_count(), 1);
+        // only a deeper frame triggers it again
+        for i in 0..100 {
+            drop(deepest.alloc(Leaf(i)));
+        }
+        assert_eq!(minor_gc_count(), 1);
+        // a frame pushed again after it's popped runs one more
+        unsafe { state.pop_frame(deepest) };
+        let again = state.push_frame();
+        drop(again.alloc(Leaf(65)));
+        assert_eq!(minor_gc_count(), 2);
+        unsafe { state.pop_frame(again) };
+        for frame in frames.into_iter().rev() {
+            unsafe { state.pop_frame(frame) };
+        }
+    }
+
+    #[test]
+    fn pacer_rate_not_above_one_is_rejected() {
+        for rate in [0.5, 1.0, -1.0, f32::NAN, f32::NEG_INFINITY] {
+            assert!(matches!(
+                GCConfig::builder().major_gc_pacer_rate(rate).build(),
+                Err(GCConfigError::InvalidPacerRate(_))
+            ));
+        }
+        assert!(GCConfig::builder()
+            .major_gc_pacer_rate(1.0001)
+            .build()
+            .is_ok());
+        let (state, _) = env();
+        assert!(state.set_major_gc_pacer_rate(1.0).is_err());
+        assert_eq!(state.major_gc_pacer_rate(), 2.0);
+        state.set_major_gc_pacer_rate(f32::INFINITY).unwrap();
+        assert_eq!(state.major_gc_pacer_rate(), MAX_MAJOR_GC_PACER_RATE);
+        let config = GCConfig::builder()
+            .major_gc_pacer_rate(1e9)
+            .build()
+            .unwrap();
+        assert_eq!(
+            State::new(config).major_gc_pacer_rate(),
+            MAX_MAJOR_GC_PACER_RATE
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn compaction_releases_sparse_chunks() {
+        for enable_compaction in [false, true] {
+            let config = GCConfig::builder()
+                .enable_compaction(enable_compaction)
+                .build()
+                .unwrap();
+            let (state, frame) = env_with(config);
+            let mut holder = frame.alloc_in(Node(0, vec![]), Generation::Major);
+            let mut kept = Vec::new();
+            for i in 0..20_000 {
+                let node = frame.alloc_in(Node(i, vec![]), Generation::Major);
+                if i % 10 == 0 {
+                    kept.push(node.as_ref());
+                }
+            }
+            holder.as_mut().1 = kept;
+            let before = state.fragmentation_report();
+            state.collect();
+            let moved = state.compact_major_heap();
+            // compacted by `collect` already when it's enabled
+            assert_eq!(moved == 0, enable_compaction);
+            let after = state.fragmentation_report();
+            assert!(after.major.reserved_bytes < before.major.reserved_bytes);
+            for (i, node) in get(&holder).1.iter().enumerate() {
+                assert_eq!(node.0, i as u64 * 10);
+                assert!(state.major_heap_gen.contains(&node.header()));
+            }
+        }
+    }
+
+    #[test]
+    fn flush_minor_to_major_promotes_every_live_object() {
+        let (state, frame) = env();
+        let a = frame.alloc(Node(1, vec![]));
+        let b = frame.alloc(Node(2, vec![a.as_ref()]));
+        let garbage = frame.alloc(Node(3, vec![]));
+        let garbage_header = garbage.header();
+        drop(garbage);
+        let c = frame.alloc(Node(4, vec![]));
+        let mut holder = frame.alloc(Node(5, vec![]));
+        holder.as_mut().1.push(c.as_ref());
+        drop(c);
+        state.flush_minor_to_major();
+        assert!(state.minor_heap_gen.is_empty());
+        assert_eq!(state.minor_heap_size.load(Ordering::Acquire), 0);
+        assert_eq!(state.major_heap_gen.len(), 4);
+        assert!(state.major_heap_gen.contains(&a.header()));
+        assert!(!is_tracked(state, garbage_header));
+        assert_eq!(get(&b).1[0].0, 1);
+        assert_eq!(get(&holder).1[0].0, 4);
+        let c = get(&holder).1[0].header();
+        assert_eq!(unsafe { (*c).generation() }, Generation::Major);
+    }
+
+    #[test]
+    fn minor_collect_leaves_major_heap_alone() {
+        let (state, frame) = env();
+        let keep = frame.alloc(Node(1, vec![]));
+        let dead = frame.alloc_in(Node(2, vec![]), Generation::Major);
+        let dead_header = dead.header();
+        drop(dead);
+        let garbage = frame.alloc(Node(3, vec![]));
+        drop(garbage);
+        state.minor_collect();
+        assert_eq!(state.minor_gc_count.load(Ordering::Acquire), 1);
+        assert_eq!(state.major_gc_count.load(Ordering::Acquire), 0);
+        assert_eq!(state.minor_heap_gen.len(), 1);
+        assert!(state.major_heap_gen.contains(&dead_header));
+        assert_eq!(get(&keep).0, 1);
+    }
+
+    #[test]
+    fn major_collect_runs_a_minor_gc_first_only_if_asked() {
+        let (state, frame) = env();
+        let keep = frame.alloc(Node(1, vec![]));
+        let dead = frame.alloc_in(Node(2, vec![]), Generation::Major);
+        let dead_header = dead.header();
+        drop(dead);
+        state.major_collect(false);
+        assert_eq!(state.minor_gc_count.load(Ordering::Acquire), 0);
+        assert_eq!(state.major_gc_count.load(Ordering::Acquire), 1);
+        assert!(!is_tracked(state, dead_header));
+        state.major_collect(true);
+        assert_eq!(state.minor_gc_count.load(Ordering::Acquire), 1);
+        assert_eq!(state.major_gc_count.load(Ordering::Acquire), 2);
+        assert_eq!(get(&keep).0, 1);
+    }
+
+    #[test]
+    fn no_gc_scope_defers_the_gc_until_dropped() {
+        let config = GCConfig::builder()
+            .minor_gc_trigger_size(4096)
+            .build()
+            .unwrap();
+        let (state, frame) = env_with(config);
+        let minor_gc_count = || state.minor_gc_count.load(Ordering::Acquire);
+        {
+            let _outer = state.no_gc_scope();
+            let inner = state.no_gc_scope();
+            for i in 0..2000 {
+                drop(frame.alloc(Leaf(i)));
+            }
+            drop(inner);
+            assert_eq!(minor_gc_count(), 0);
+            assert!(state.minor_heap_size.load(Ordering::Acquire) > 4096);
+        }
+        // the deferred one runs once on exit
+        assert_eq!(minor_gc_count(), 1);
+        for i in 0..2000 {
+            drop(frame.alloc(Leaf(i)));
+        }
+        assert!(minor_gc_count() > 1);
+    }
+
+    #[test]
+    fn long_lived_major_objects_are_promoted_into_imm() {
+        let config = GCConfig::builder()
+            .enable_imm_gen(true)
+            .imm_liveness(2)
+            .build()
+            .unwrap();
+        let (state, frame) = env_with(config);
+        let child = frame.alloc(Node(5, vec![]));
+        let mut parent = frame.alloc(Node(1, vec![child.as_ref()]));
+        drop(child);
+        for _ in 0..4 {
+            state.minor_heap_gen_gc();
+        }
+        assert!(state.major_heap_gen.contains(&parent.header()));
+        for _ in 0..4 {
+            state.collect();
+        }
+        assert!(state.imm_gen.contains(&parent.header()));
+        assert!(state.imm_gen.contains(&get(&parent).1[0].header()));
+        assert_eq!(state.imm_gen.len(), 2);
+        assert_eq!(
+            state.imm_size.load(Ordering::Acquire),
+            state.total_size.load(Ordering::Acquire)
+        );
+        // the young objects referenced by imm ones are kept
+        let young = frame.alloc(Node(9, vec![]));
+        parent.as_mut().1.push(young.as_ref());
+        drop(young);
+        state.minor_heap_gen_gc();
+        state.collect();
+        let young = get(&parent).1[1].header();
+        assert!(is_tracked(state, young));
+        assert_eq!(get(&parent).1[1].0, 9);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn mark_step_and_collect_of_registered_mutators_never_deadlock() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let (state, frame) = env();
+        let _objects: Vec<_> = (0..64)
+            .map(|i| frame.alloc_in(Node(i, vec![]), Generation::Major))
+            .collect();
+        let (done, finished) = mpsc::channel();
+        for stepping in [true, false] {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let _mutator = state.register_mutator();
+                for _ in 0..50 {
+                    if stepping {
+                        while state.mark_step(4) != MarkProgress::Complete {
+                            state.safepoint();
+                        }
+                    } else {
+                        state.collect();
+                    }
+                    state.safepoint();
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..2 {
+            finished
+                .recv_timeout(Duration::from_secs(30))
+                .expect("mark_step and collect deadlocked");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn stw_waits_for_every_registered_mutator() {
+        use core::sync::atomic::AtomicBool;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let (state, _) = env();
+        static RUNNING: AtomicBool = AtomicBool::new(true);
+        let (registered, all_registered) = mpsc::channel();
+        let mut polls = vec![];
+        let mut mutators = vec![];
+        for _ in 0..2 {
+            let registered = registered.clone();
+            let (poll, start_polling) = mpsc::channel::<()>();
+            polls.push(poll);
+            mutators.push(std::thread::spawn(move || {
+                let _mutator = state.register_mutator();
+                registered.send(()).unwrap();
+                start_polling.recv().unwrap();
+                while RUNNING.load(Ordering::SeqCst) {
+                    state.safepoint();
+                }
+            }));
+        }
+        for _ in 0..2 {
+            all_registered.recv().unwrap();
+        }
+        let (stopped, world_stopped) = mpsc::channel();
+        let collector = std::thread::spawn(move || {
+            state.stw().unwrap();
+            stopped.send(()).unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(world_stopped.try_recv().is_err());
+        polls[0].send(()).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(world_stopped.try_recv().is_err());
+        polls[1].send(()).unwrap();
+        world_stopped
+            .recv_timeout(Duration::from_secs(30))
+            .expect("stw never returned");
+        collector.join().unwrap();
+        RUNNING.store(false, Ordering::SeqCst);
+        state.ctw().unwrap();
+        for mutator in mutators {
+            mutator.join().unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn stw_timeout_reports_outstanding_mutators() {
+        use alloc::string::String;
+        use core::sync::atomic::AtomicBool;
+        use core::time::Duration;
+        use std::sync::{mpsc, Mutex};
+
+        use super::StwError;
+        use crate::monitoring::FnMonitoring;
+
+        let reported: &'static Mutex<Vec<String>> = Box::leak(Box::new(Mutex::new(vec![])));
+        let monitoring = FnMonitoring::new().on_stw_timeout(|outstanding| {
+            reported.lock().unwrap().extend(
+                outstanding
+                    .iter()
+                    .map(|thread| String::from(thread.name().unwrap())),
+            );
+        });
+        let config = GCConfig::builder()
+            .stw_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let (state, _) = env_of(State::new(config).with_monitoring(Box::new(monitoring)));
+        static RUNNING: AtomicBool = AtomicBool::new(true);
+        let (registered, all_registered) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let stuck_registered = registered.clone();
+        let stuck = std::thread::Builder::new()
+            .name("stuck".into())
+            .spawn(move || {
+                let _mutator = state.register_mutator();
+                stuck_registered.send(()).unwrap();
+                released.recv().unwrap();
+            })
+            .unwrap();
+        let polling = std::thread::Builder::new()
+            .name("polling".into())
+            .spawn(move || {
+                let _mutator = state.register_mutator();
+                registered.send(()).unwrap();
+                while RUNNING.load(Ordering::SeqCst) {
+                    state.safepoint();
+                }
+            })
+            .unwrap();
+        for _ in 0..2 {
+            all_registered.recv().unwrap();
+        }
+        assert_eq!(state.stw(), Err(StwError::TimedOut));
+        assert!(!state.is_stopped());
+        assert_eq!(*reported.lock().unwrap(), vec![String::from("stuck")]);
+        RUNNING.store(false, Ordering::SeqCst);
+        release.send(()).unwrap();
+        stuck.join().unwrap();
+        polling.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn stw_timeout_wait_keeps_reporting_until_mutators_poll() {
+        use core::sync::atomic::AtomicBool;
+        use core::time::Duration;
+
+        use crate::monitoring::FnMonitoring;
+        use crate::state::StwTimeoutAction;
+
+        static REPORTS: AtomicUsize = AtomicUsize::new(0);
+        static RUNNING: AtomicBool = AtomicBool::new(true);
+        let monitoring = FnMonitoring::new().on_stw_timeout(|outstanding| {
+            assert_eq!(outstanding.len(), 1);
+            REPORTS.fetch_add(1, Ordering::SeqCst);
+        });
+        let config = GCConfig::builder()
+            .stw_timeout(Duration::from_millis(20))
+            .stw_timeout_action(StwTimeoutAction::Wait)
+            .build()
+            .unwrap();
+        let (state, _) = env_of(State::new(config).with_monitoring(Box::new(monitoring)));
+        let _mutator = state.register_mutator();
+        let late = std::thread::spawn(move || {
+            let _mutator = state.register_mutator();
+            // only poll after being reported twice
+            while REPORTS.load(Ordering::SeqCst) < 2 {
+                std::thread::yield_now();
+            }
+            while RUNNING.load(Ordering::SeqCst) {
+                state.safepoint();
+            }
+        });
+        while state.mutators.lock().unwrap().len() < 2 {
+            std::thread::yield_now();
+        }
+        state.stw().unwrap();
+        assert!(REPORTS.load(Ordering::SeqCst) >= 2);
+        assert!(state.outstanding_mutators().is_empty());
+        RUNNING.store(false, Ordering::SeqCst);
+        state.ctw().unwrap();
+        late.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn stw_timeout_skip_abandons_the_gc_cycle() {
+        use core::time::Duration;
+        use std::sync::mpsc;
+
+        use super::GCStage;
+
+        let config = GCConfig::builder()
+            .stw_timeout(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let (state, frame) = env_with(config);
+        let (registered, stuck_registered) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let stuck = std::thread::spawn(move || {
+            let _mutator = state.register_mutator();
+            registered.send(()).unwrap();
+            released.recv().unwrap();
+        });
+        stuck_registered.recv().unwrap();
+        let young = frame.alloc(Node(1, vec![]));
+        let dead = frame.alloc_in(Node(2, vec![]), Generation::Major);
+        let dead_header = dead.header();
+        drop(dead);
+
+        state.minor_collect();
+        assert_eq!(state.minor_gc_count.load(Ordering::Acquire), 0);
+        assert_eq!(unsafe { (*young.header()).generation() }, Generation::Minor);
+        assert!(!state.start_minor_gc_flag.load(Ordering::Acquire));
+        state.collect();
+        assert_eq!(state.major_gc_count.load(Ordering::Acquire), 0);
+        assert!(is_tracked(state, dead_header));
+        assert!(!state.start_major_gc_flag.load(Ordering::Acquire));
+        assert!(state.major_heap_marked.is_empty());
+        assert_eq!(state.stage(), GCStage::Ready);
+        assert!(!state.is_stopped());
+
+        release.send(()).unwrap();
+        stuck.join().unwrap();
+        state.collect();
+        assert_eq!(state.major_gc_count.load(Ordering::Acquire), 1);
+        assert!(!is_tracked(state, dead_header));
+        assert_eq!(get(&young).0, 1);
+    }
 }
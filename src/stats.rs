@@ -0,0 +1,218 @@
+use core::sync::atomic::Ordering;
+
+use super::bump::{Chunk, CHUNK_SIZE};
+use super::gc_box::GCHeader;
+use super::state::State;
+use super::sync::{HashSet, Set};
+
+/// a snapshot of the collector,
+/// see `State::stats` and `State::stats_stw` for the consistency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub minor_heap_size: usize,
+    pub major_heap_size: usize,
+    pub imm_size: usize,
+    pub total_size: usize,
+    /// bytes reserved by pinned regions, not counted in `total_size`
+    pub pinned_size: usize,
+    /// bytes of the objects of at least `large_object_size`, they are also counted in their generations
+    pub large_object_heap_size: usize,
+    pub current_frame_count: usize,
+
+    /// cardinalities of the tracking sets
+    pub minor_heap_roots: usize,
+    pub minor_heap_gen: usize,
+    pub minor_heap_marked: usize,
+    pub major_heap_roots: usize,
+    pub major_heap_gen: usize,
+    pub major_heap_marked: usize,
+    pub imm_gen: usize,
+    pub large_objects: usize,
+
+    /// cumulative counters since the state is created, for allocation rate and gc frequency
+    pub total_allocated_bytes: usize,
+    pub total_allocated_objects: usize,
+    pub total_freed_bytes: usize,
+    /// finished minor gcs
+    pub minor_gc_count: usize,
+    /// finished major gcs
+    pub major_gc_count: usize,
+
+    /// it walks every object, so it's only taken by `stats_stw`, and left empty by `stats`
+    pub fragmentation: FragReport,
+    /// live objects of every generation by `GCHeader::survived`, see `age_histogram`
+    pub ages: [usize; AGE_BUCKETS],
+}
+
+/// buckets of `GcStats::ages`, the last one counts every older object
+pub const AGE_BUCKETS: usize = 32;
+
+impl GcStats {
+    /// the object count at `i` survived `i` collections, only taken by `stats_stw`,
+    /// for tuning `major_heap_liveness` and `imm_liveness`
+    pub fn age_histogram(&self) -> &[usize] {
+        &self.ages
+    }
+}
+
+/// how the memory of one generation is used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GenerationFrag {
+    pub objects: usize,
+    /// sizes stored in the headers, including the headers
+    pub live_bytes: usize,
+    /// live bytes of the objects carved from bump chunks
+    pub chunked_bytes: usize,
+    /// bytes of the bump chunks holding at least one object of this generation
+    pub reserved_bytes: usize,
+}
+
+impl GenerationFrag {
+    /// the part of the reserved chunks not used by this generation, from 0.0 to 1.0,
+    /// objects from the allocator directly are not counted
+    pub fn fragmentation(&self) -> f64 {
+        if self.reserved_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.chunked_bytes as f64 / self.reserved_bytes as f64
+    }
+}
+
+/// an estimation of fragmentation, by the sizes stored in the headers,
+/// to decide whether compacting is worth it.
+/// a chunk shared by generations is reserved by each of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FragReport {
+    pub minor: GenerationFrag,
+    pub major: GenerationFrag,
+    pub imm: GenerationFrag,
+    /// bump chunks holding live objects
+    pub chunks: usize,
+    /// bytes of those chunks not used by any live object,
+    /// the holes left by dead cells and the unused tail of every chunk
+    pub wasted_bytes: usize,
+}
+
+impl FragReport {
+    /// the part of every chunk not used by live objects, from 0.0 to 1.0
+    pub fn fragmentation(&self) -> f64 {
+        if self.chunks == 0 {
+            return 0.0;
+        }
+        self.wasted_bytes as f64 / (self.chunks * CHUNK_SIZE) as f64
+    }
+}
+
+impl State {
+    /// every field is read atomically, but not all of them at once,
+    /// so with running mutators or gc the fields could disagree with each other.
+    /// use `stats_stw` for a consistent one.
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            minor_heap_size: self.minor_heap_size.load(Ordering::Acquire),
+            major_heap_size: self.major_heap_size.load(Ordering::Acquire),
+            imm_size: self.imm_size.load(Ordering::Acquire),
+            total_size: self.total_size.load(Ordering::Acquire),
+            pinned_size: self.pinned_size.load(Ordering::Acquire),
+            large_object_heap_size: self.large_object_heap_size.load(Ordering::Acquire),
+            current_frame_count: self.current_frame_count.load(Ordering::Acquire),
+            minor_heap_roots: self.minor_heap_roots.len(),
+            minor_heap_gen: self.minor_heap_gen.len(),
+            minor_heap_marked: self.minor_heap_marked.len(),
+            major_heap_roots: self.major_heap_roots.len(),
+            major_heap_gen: self.major_heap_gen.len(),
+            major_heap_marked: self.major_heap_marked.len(),
+            imm_gen: self.imm_gen.len(),
+            large_objects: self.large_object_set.len(),
+            total_allocated_bytes: self.total_allocated_bytes.load(Ordering::Acquire),
+            total_allocated_objects: self.total_allocated_objects.load(Ordering::Acquire),
+            total_freed_bytes: self.total_freed_bytes.load(Ordering::Acquire),
+            minor_gc_count: self.minor_gc_count.load(Ordering::Acquire),
+            major_gc_count: self.major_gc_count.load(Ordering::Acquire),
+            fragmentation: FragReport::default(),
+            ages: [0; AGE_BUCKETS],
+        }
+    }
+
+    /// take the snapshot with the world stopped and minor gc held off
+    pub fn stats_stw(&self) -> GcStats {
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.stop_world(false);
+        let mut stats = self.stats();
+        stats.fragmentation = self.fragmentation_report_held();
+        for header in self
+            .minor_heap_gen
+            .iter()
+            .chain(self.major_heap_gen.iter())
+            .chain(self.imm_gen.iter())
+        {
+            let survived = unsafe { (**header).survived() };
+            stats.ages[survived.min(AGE_BUCKETS - 1)] += 1;
+        }
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while taking stats");
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        stats
+    }
+
+    /// walk every object with the world stopped and minor gc held off
+    pub fn fragmentation_report(&self) -> FragReport {
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.stop_world(false);
+        let report = self.fragmentation_report_held();
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while taking fragmentation report");
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        report
+    }
+
+    /// `fragmentation_report`, with the world stopped and minor gc held off by the caller
+    pub(crate) fn fragmentation_report_held(&self) -> FragReport {
+        let mut chunks = HashSet::new();
+        let minor = generation_frag(&self.minor_heap_gen, &mut chunks);
+        let major = generation_frag(&self.major_heap_gen, &mut chunks);
+        let imm = generation_frag(&self.imm_gen, &mut chunks);
+        let chunked_bytes = minor.chunked_bytes + major.chunked_bytes + imm.chunked_bytes;
+        FragReport {
+            minor,
+            major,
+            imm,
+            chunks: chunks.len(),
+            wasted_bytes: chunks.len() * CHUNK_SIZE - chunked_bytes,
+        }
+    }
+}
+
+/// `chunks` collects the chunks of every generation
+fn generation_frag(
+    generation: &Set<*mut GCHeader>,
+    chunks: &mut HashSet<*const Chunk>,
+) -> GenerationFrag {
+    let mut frag = GenerationFrag::default();
+    let mut reserved = HashSet::new();
+    for header in generation.iter() {
+        let header = unsafe { &**header };
+        frag.objects += 1;
+        frag.live_bytes += header.size();
+        let chunk = header.chunk();
+        if !chunk.is_null() {
+            frag.chunked_bytes += header.size();
+            reserved.insert(chunk);
+            chunks.insert(chunk);
+        }
+    }
+    frag.reserved_bytes = reserved.len() * CHUNK_SIZE;
+    frag
+}
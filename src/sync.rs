@@ -0,0 +1,323 @@
+//! concurrent collections and locks of the collector.
+//! with `std` they are the ones of `dashmap` and `std`,
+//! without it they are spinlocks over the collections of `alloc`, which share the same interface.
+#[cfg(feature = "std")]
+pub(crate) use dashmap::{DashMap as Map, DashSet as Set};
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{HashMap, HashSet};
+#[cfg(feature = "std")]
+pub(crate) use std::sync::Mutex;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use self::spin::{Map, Mutex, Set};
+#[cfg(not(feature = "std"))]
+pub(crate) use alloc::collections::{BTreeMap as HashMap, BTreeSet as HashSet};
+
+/// give up the time slice while waiting for another thread
+pub(crate) fn yield_now() {
+    #[cfg(feature = "std")]
+    std::thread::yield_now();
+    #[cfg(not(feature = "std"))]
+    core::hint::spin_loop();
+}
+
+#[cfg(not(feature = "std"))]
+mod spin {
+    use alloc::collections::{BTreeMap, BTreeSet};
+    use alloc::vec::Vec;
+    use core::cell::UnsafeCell;
+    use core::convert::Infallible;
+    use core::ops::{Deref, DerefMut};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const WRITER: usize = 1;
+    const READER: usize = 2;
+
+    /// readers-writer spinlock, reading is reentrant
+    struct RwLock<T> {
+        state: AtomicUsize,
+        value: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Send for RwLock<T> {}
+    unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+    impl<T> RwLock<T> {
+        const fn new(value: T) -> Self {
+            Self {
+                state: AtomicUsize::new(0),
+                value: UnsafeCell::new(value),
+            }
+        }
+
+        fn read(&self) -> ReadGuard<'_, T> {
+            loop {
+                let state = self.state.load(Ordering::Relaxed);
+                if state & WRITER == 0
+                    && self
+                        .state
+                        .compare_exchange_weak(
+                            state,
+                            state + READER,
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                {
+                    return ReadGuard { lock: self };
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        fn write(&self) -> WriteGuard<'_, T> {
+            while self
+                .state
+                .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+            WriteGuard { lock: self }
+        }
+    }
+
+    struct ReadGuard<'a, T> {
+        lock: &'a RwLock<T>,
+    }
+
+    impl<T> Deref for ReadGuard<'_, T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            unsafe { &*self.lock.value.get() }
+        }
+    }
+
+    impl<T> Drop for ReadGuard<'_, T> {
+        fn drop(&mut self) {
+            self.lock.state.fetch_sub(READER, Ordering::Release);
+        }
+    }
+
+    pub(crate) struct WriteGuard<'a, T> {
+        lock: &'a RwLock<T>,
+    }
+
+    impl<T> Deref for WriteGuard<'_, T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            unsafe { &*self.lock.value.get() }
+        }
+    }
+
+    impl<T> DerefMut for WriteGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.lock.value.get() }
+        }
+    }
+
+    impl<T> Drop for WriteGuard<'_, T> {
+        fn drop(&mut self) {
+            self.lock.state.fetch_and(!WRITER, Ordering::Release);
+        }
+    }
+
+    /// a spinlock, `lock` returns `Result` like `std::sync::Mutex`, but it's never poisoned
+    pub(crate) struct Mutex<T> {
+        lock: RwLock<T>,
+    }
+
+    impl<T> Mutex<T> {
+        pub(crate) const fn new(value: T) -> Self {
+            Self {
+                lock: RwLock::new(value),
+            }
+        }
+
+        pub(crate) fn lock(&self) -> Result<WriteGuard<'_, T>, Infallible> {
+            Ok(self.lock.write())
+        }
+    }
+
+    /// an item copied out of a `Set` by `iter`
+    pub(crate) struct Ref<T> {
+        value: T,
+    }
+
+    impl<T> Deref for Ref<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            &self.value
+        }
+    }
+
+    /// an entry copied out of a `Map` by `iter` or `get`
+    pub(crate) struct MapRef<K, V> {
+        key: K,
+        value: V,
+    }
+
+    impl<K, V> MapRef<K, V> {
+        pub(crate) fn key(&self) -> &K {
+            &self.key
+        }
+        pub(crate) fn value(&self) -> &V {
+            &self.value
+        }
+    }
+
+    impl<K, V> Deref for MapRef<K, V> {
+        type Target = V;
+        fn deref(&self) -> &V {
+            &self.value
+        }
+    }
+
+    /// a set like `dashmap::DashSet`, `iter` walks a snapshot,
+    /// so the set could be modified while iterating.
+    pub(crate) struct Set<T> {
+        inner: RwLock<BTreeSet<T>>,
+    }
+
+    impl<T: Ord + Copy> Default for Set<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T: Ord + Copy> Set<T> {
+        pub(crate) const fn new() -> Self {
+            Self {
+                inner: RwLock::new(BTreeSet::new()),
+            }
+        }
+        pub(crate) fn insert(&self, value: T) -> bool {
+            self.inner.write().insert(value)
+        }
+        pub(crate) fn remove(&self, value: &T) -> Option<T> {
+            self.inner.write().take(value)
+        }
+        pub(crate) fn contains(&self, value: &T) -> bool {
+            self.inner.read().contains(value)
+        }
+        pub(crate) fn len(&self) -> usize {
+            self.inner.read().len()
+        }
+        pub(crate) fn is_empty(&self) -> bool {
+            self.inner.read().is_empty()
+        }
+        pub(crate) fn clear(&self) {
+            self.inner.write().clear();
+        }
+        pub(crate) fn retain(&self, mut f: impl FnMut(&T) -> bool) {
+            self.inner.write().retain(|value| f(value));
+        }
+        pub(crate) fn iter(&self) -> alloc::vec::IntoIter<Ref<T>> {
+            let snapshot: Vec<_> = self
+                .inner
+                .read()
+                .iter()
+                .map(|value| Ref { value: *value })
+                .collect();
+            snapshot.into_iter()
+        }
+    }
+
+    /// a map like `dashmap::DashMap`, see `Set`
+    pub(crate) struct Map<K, V> {
+        inner: RwLock<BTreeMap<K, V>>,
+    }
+
+    impl<K: Ord + Copy, V: Copy> Default for Map<K, V> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<K: Ord + Copy, V: Copy> Map<K, V> {
+        pub(crate) const fn new() -> Self {
+            Self {
+                inner: RwLock::new(BTreeMap::new()),
+            }
+        }
+        pub(crate) fn insert(&self, key: K, value: V) -> Option<V> {
+            self.inner.write().insert(key, value)
+        }
+        pub(crate) fn get(&self, key: &K) -> Option<MapRef<K, V>> {
+            self.inner.read().get(key).map(|value| MapRef {
+                key: *key,
+                value: *value,
+            })
+        }
+        pub(crate) fn entry(&self, key: K) -> Entry<'_, K, V> {
+            Entry {
+                guard: self.inner.write(),
+                key,
+            }
+        }
+        pub(crate) fn get_mut(&self, key: &K) -> Option<RefMut<'_, K, V>> {
+            let guard = self.inner.write();
+            if guard.contains_key(key) {
+                Some(RefMut { guard, key: *key })
+            } else {
+                None
+            }
+        }
+        pub(crate) fn remove(&self, key: &K) -> Option<(K, V)> {
+            self.inner.write().remove_entry(key)
+        }
+        pub(crate) fn remove_if(&self, key: &K, f: impl FnOnce(&K, &V) -> bool) -> Option<(K, V)> {
+            let mut guard = self.inner.write();
+            match guard.get(key) {
+                Some(value) if f(key, value) => guard.remove_entry(key),
+                _ => None,
+            }
+        }
+        pub(crate) fn iter(&self) -> alloc::vec::IntoIter<MapRef<K, V>> {
+            let snapshot: Vec<_> = self
+                .inner
+                .read()
+                .iter()
+                .map(|(key, value)| MapRef {
+                    key: *key,
+                    value: *value,
+                })
+                .collect();
+            snapshot.into_iter()
+        }
+    }
+
+    pub(crate) struct Entry<'a, K, V> {
+        guard: WriteGuard<'a, BTreeMap<K, V>>,
+        key: K,
+    }
+
+    impl<'a, K: Ord + Copy, V> Entry<'a, K, V> {
+        pub(crate) fn or_insert(mut self, value: V) -> RefMut<'a, K, V> {
+            self.guard.entry(self.key).or_insert(value);
+            RefMut {
+                guard: self.guard,
+                key: self.key,
+            }
+        }
+    }
+
+    /// a value locked in a `Map`
+    pub(crate) struct RefMut<'a, K, V> {
+        guard: WriteGuard<'a, BTreeMap<K, V>>,
+        key: K,
+    }
+
+    impl<K: Ord, V> Deref for RefMut<'_, K, V> {
+        type Target = V;
+        fn deref(&self) -> &V {
+            &self.guard[&self.key]
+        }
+    }
+
+    impl<K: Ord, V> DerefMut for RefMut<'_, K, V> {
+        fn deref_mut(&mut self) -> &mut V {
+            self.guard.get_mut(&self.key).unwrap()
+        }
+    }
+}
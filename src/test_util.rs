@@ -0,0 +1,60 @@
+//! helpers shared by the unit tests.
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::frame::GCFrame;
+use crate::gc_box::{GCBox, GCHeader, GCRef};
+use crate::state::{GCConfig, State};
+use crate::trace::{Trace, Tracer};
+
+/// an object without references
+pub(crate) struct Leaf(pub(crate) u64);
+
+impl Trace for Leaf {
+    fn trace(&self, _: &mut dyn Tracer) {}
+}
+
+/// an object referencing other ones
+pub(crate) struct Node(pub(crate) u64, pub(crate) Vec<GCRef<Node>>);
+
+impl Trace for Node {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        self.1.trace(tracer);
+    }
+}
+
+/// a leaked state of the default config and a frame of it
+pub(crate) fn env() -> (&'static State, &'static GCFrame) {
+    env_with(GCConfig::default())
+}
+
+pub(crate) fn env_with(config: GCConfig) -> (&'static State, &'static GCFrame) {
+    env_of(State::new(config))
+}
+
+/// leak a state set up by the test, like one with a monitoring or an oom handler
+pub(crate) fn env_of(state: State) -> (&'static State, &'static GCFrame) {
+    let state: &'static State = Box::leak(Box::new(state));
+    let frame: &'static GCFrame = Box::leak(Box::new(GCFrame::new(state)));
+    (state, frame)
+}
+
+/// the object of a box, rooted objects are never moved
+pub(crate) fn get<T: Trace>(gc_box: &GCBox<T>) -> &T {
+    unsafe { &*(GCHeader::data(gc_box.header()) as *const T) }
+}
+
+/// whether the object is still tracked by any generation, so it's not freed
+pub(crate) fn is_tracked(state: &State, header: *mut GCHeader) -> bool {
+    state.minor_heap_gen.contains(&header)
+        || state.major_heap_gen.contains(&header)
+        || state.imm_gen.contains(&header)
+}
+
+/// run enough minor gcs to promote every survivor, then a major gc
+pub(crate) fn full_gc(state: &State) {
+    for _ in 0..8 {
+        state.minor_heap_gen_gc();
+    }
+    state.collect();
+}
@@ -1,4 +1,324 @@
-use super::gc_box::GCBox;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::gc_array::GCArray;
+use super::gc_box::{AnyGCRef, Ephemeron, GCBox, GCHeader, GCRef, GCRoot, GCSlot, GCWeak};
+
+#[cfg(feature = "derive")]
+pub use cgc_derive::Trace;
+
+/// receives every gc object referenced by the traced one
+pub trait Tracer {
+    /// objects only reported by `visit` are never moved by the collector
+    fn visit(&mut self, header: *mut GCHeader);
+    /// report a reference that the collector could redirect after moving the object
+    fn visit_slot(&mut self, slot: &mut dyn GCSlot) {
+        self.visit(slot.header());
+    }
+}
+
+/// report every gc object referenced by `self` to the tracer,
+/// forgetting one of them makes it a dangling pointer after collection.
+/// it's called from the workers of gc thread pool during marking.
+/// objects of any type could be reported, and cycles need no special care,
+/// objects only reachable from each other are collected together by both minor and major gc.
+/// enable the `derive` feature for `#[derive(Trace)]`.
+///
+/// with `std` a panicking `trace` is caught and reported by `Monitoring::trace_panicked`.
+/// since the references it didn't report are unknown, that gc frees nothing, and moves nothing,
+/// so a `trace` which always panics keeps the whole heap alive.
 pub trait Trace where Self: 'static {
-    fn trace(&self) -> Vec<GCBox<Self>> where Self: Sized;
-}
\ No newline at end of file
+    fn trace(&self, tracer: &mut dyn Tracer);
+}
+
+/// cleanup of resources out of gc heap, like file handles or sockets,
+/// it's opted in by `GCBox::register_finalizer`.
+///
+/// the finalizers of one collection run before any dead object is dropped or freed,
+/// in no particular order, and possibly in parallel on the workers of gc thread pool,
+/// so for cyclic objects the gc objects referenced by a finalizing one may be finalized already.
+/// every finalizer runs exactly once, a panicking finalizer is caught and ignored with `std`.
+/// the object must not be resurrected by its finalizer.
+pub trait Finalize: Trace {
+    fn finalize(&mut self);
+}
+
+impl Tracer for Vec<*mut GCHeader> {
+    fn visit(&mut self, header: *mut GCHeader) {
+        self.push(header);
+    }
+}
+
+impl<T: Trace> Trace for GCBox<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        tracer.visit(self.header());
+    }
+}
+
+impl<T: Trace> Trace for GCRoot<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        tracer.visit(self.header());
+    }
+}
+
+impl<T: Trace> Trace for GCArray<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        tracer.visit(self.header());
+    }
+}
+
+impl<T: Trace> Trace for GCRef<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        // only the collector writes the cell, while the mutators are stopped
+        tracer.visit_slot(unsafe { &mut *self.value.get() });
+    }
+}
+
+impl Trace for AnyGCRef {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        tracer.visit_slot(unsafe { &mut *self.header.get() });
+    }
+}
+
+/// weak references are not traced, so they never keep anything alive
+impl<T: Trace> Trace for GCWeak<T> {
+    fn trace(&self, _tracer: &mut dyn Tracer) {}
+}
+
+/// ephemerons are traced by the collector, after finding their keys live
+impl<K: Trace, V: Trace> Trace for Ephemeron<K, V> {
+    fn trace(&self, _tracer: &mut dyn Tracer) {}
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        for element in self.iter() {
+            element.trace(tracer);
+        }
+    }
+}
+
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        if let Some(value) = self {
+            value.trace(tracer);
+        }
+    }
+}
+
+impl<T: Trace> Trace for Box<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        (**self).trace(tracer);
+    }
+}
+
+/// implement `Trace` for types which never hold any gc object
+/// ```ignore
+/// struct Name(String);
+/// empty_trace!(Name);
+/// ```
+#[macro_export]
+macro_rules! empty_trace {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl $crate::trace::Trace for $t {
+                #[inline]
+                fn trace(&self, _tracer: &mut dyn $crate::trace::Tracer) {}
+            }
+        )*
+    };
+}
+
+empty_trace!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    &'static str,
+    String,
+);
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::{Trace, Tracer};
+    use crate::gc_box::{AnyGCRef, GCHeader, GCRef, Generation};
+    use crate::state::State;
+    #[cfg(feature = "derive")]
+    use crate::test_util::get;
+    use crate::test_util::{env, is_tracked, Node};
+
+    fn gc(state: &State, generation: Generation) {
+        match generation {
+            Generation::Minor => state.minor_heap_gen_gc(),
+            _ => state.collect(),
+        }
+    }
+
+    /// each node references the next one, the last one references the first one
+    fn ring_is_collected(len: usize) {
+        for generation in [Generation::Minor, Generation::Major] {
+            let (state, frame) = env();
+            let mut nodes: Vec<_> = (0..len as u64)
+                .map(|i| frame.alloc_in(Node(i, vec![]), generation))
+                .collect();
+            for i in 0..len {
+                let next = nodes[(i + 1) % len].as_ref();
+                nodes[i].as_mut().1.push(next);
+            }
+            let headers: Vec<*mut GCHeader> = nodes.iter().map(|node| node.header()).collect();
+            gc(state, generation);
+            assert!(headers.iter().all(|header| is_tracked(state, *header)));
+            drop(nodes);
+            gc(state, generation);
+            assert!(!headers.iter().any(|header| is_tracked(state, *header)));
+        }
+    }
+
+    #[test]
+    fn self_cycle_is_collected() {
+        ring_is_collected(1);
+    }
+
+    #[test]
+    fn two_node_cycle_is_collected() {
+        ring_is_collected(2);
+    }
+
+    #[test]
+    fn three_node_cycle_is_collected() {
+        ring_is_collected(3);
+    }
+
+    #[test]
+    fn cycle_across_types_is_collected() {
+        struct Erased(Option<AnyGCRef>);
+        impl Trace for Erased {
+            fn trace(&self, tracer: &mut dyn Tracer) {
+                self.0.trace(tracer);
+            }
+        }
+        struct Typed(Option<GCRef<Erased>>);
+        impl Trace for Typed {
+            fn trace(&self, tracer: &mut dyn Tracer) {
+                self.0.trace(tracer);
+            }
+        }
+        for generation in [Generation::Minor, Generation::Major] {
+            let (state, frame) = env();
+            let mut typed = frame.alloc_in(Typed(None), generation);
+            let erased = frame.alloc_in(Erased(Some(typed.as_ref().erase())), generation);
+            typed.as_mut().0 = Some(erased.as_ref());
+            let headers = [typed.header(), erased.header()];
+            drop(typed);
+            drop(erased);
+            gc(state, generation);
+            assert!(!headers.iter().any(|header| is_tracked(state, *header)));
+        }
+    }
+
+    /// allocate the value built from two children, and check that only the `traced` ones
+    /// are reported and kept alive by it
+    #[cfg(feature = "derive")]
+    fn assert_traces<T: Trace>(
+        build: impl FnOnce(GCRef<Node>, GCRef<Node>) -> T,
+        traced: [bool; 2],
+    ) {
+        let (state, frame) = env();
+        let children = [
+            frame.alloc_in(Node(1, vec![]), Generation::Major),
+            frame.alloc_in(Node(2, vec![]), Generation::Major),
+        ];
+        let headers = [children[0].header(), children[1].header()];
+        let holder = frame.alloc_in(
+            build(children[0].as_ref(), children[1].as_ref()),
+            Generation::Major,
+        );
+        let mut visits: Vec<*mut GCHeader> = Vec::new();
+        get(&holder).trace(&mut visits);
+        let expected: Vec<_> = (0..2).filter(|i| traced[*i]).map(|i| headers[i]).collect();
+        assert_eq!(visits, expected);
+        drop(children);
+        state.collect();
+        for i in 0..2 {
+            assert_eq!(is_tracked(state, headers[i]), traced[i]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn derive_named_struct() {
+        #[derive(Trace)]
+        struct Named {
+            a: GCRef<Node>,
+            b: GCRef<Node>,
+        }
+        assert_traces(|a, b| Named { a, b }, [true, true]);
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn derive_tuple_struct() {
+        #[derive(Trace)]
+        struct Tuple(GCRef<Node>, GCRef<Node>);
+        assert_traces(Tuple, [true, true]);
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn derive_enum() {
+        #[derive(Trace)]
+        enum Enum {
+            #[allow(dead_code)]
+            Empty,
+            #[allow(dead_code)]
+            One(GCRef<Node>),
+            Two {
+                a: GCRef<Node>,
+                b: GCRef<Node>,
+            },
+        }
+        #[derive(Trace)]
+        enum Never {}
+        assert_traces(|a, b| Enum::Two { a, b }, [true, true]);
+        let mut visits: Vec<*mut GCHeader> = Vec::new();
+        None::<Never>.trace(&mut visits);
+        assert!(visits.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn derive_ignored_field() {
+        #[derive(Trace)]
+        struct Ignoring {
+            a: GCRef<Node>,
+            #[trace(ignore)]
+            b: GCRef<Node>,
+        }
+        assert_traces(|a, b| Ignoring { a, b }, [true, false]);
+    }
+
+    #[test]
+    #[cfg(feature = "derive")]
+    fn derive_generic() {
+        #[derive(Trace)]
+        struct Generic<T>(T, Vec<T>);
+        assert_traces(|a, b| Generic(a, vec![b]), [true, true]);
+    }
+}
@@ -0,0 +1,270 @@
+//! per type defaults of the objects, applied by the collector to every object of a registered type.
+use core::any::TypeId;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::gc_box::{cell_fns, CellFns, GCHeader, Generation};
+use super::state::State;
+use super::sync::Map;
+use super::trace::{Trace, Tracer};
+
+/// how the objects of `T` are allocated, see `State::register_type`
+pub struct TypeMeta<T: Trace> {
+    /// pin every new object like `GCBox::pin`, so it could be handed to C right away.
+    /// a pinned object is a root, so every object of the type is kept alive
+    /// until its box calls `GCBox::unpin`, dropping the box doesn't free it
+    pub pinned: bool,
+    /// the generation allocated into by `GCFrame::alloc`, falling back like `GCFrame::alloc_in`.
+    /// none for the default one, `alloc_in` always uses the given one
+    pub generation: Option<Generation>,
+    /// run before the object is freed, like `Finalize::finalize` registered by `GCBox::register_finalizer`
+    pub finalizer: Option<fn(&mut T)>,
+}
+
+impl<T: Trace> Default for TypeMeta<T> {
+    fn default() -> Self {
+        Self {
+            pinned: false,
+            generation: None,
+            finalizer: None,
+        }
+    }
+}
+
+/// `TypeMeta` without the type
+#[derive(Clone, Copy)]
+pub(crate) struct ErasedTypeMeta {
+    pub(crate) pinned: bool,
+    pub(crate) generation: Option<Generation>,
+    /// the `fn(&mut T)` of the finalizer
+    finalizer: Option<*const ()>,
+}
+
+/// the registered types of a state
+pub(crate) struct TypeRegistry {
+    types: Map<TypeId, ErasedTypeMeta>,
+    /// byte offsets into the data of the objects of a type, see `State::register_type_offsets`
+    offsets: Map<TypeId, &'static [usize]>,
+    /// no meta is registered, so the allocations skip the lookup
+    empty: AtomicBool,
+    /// no offsets are registered, so tracing skips the lookup
+    no_offsets: AtomicBool,
+}
+
+impl TypeRegistry {
+    pub(crate) fn new() -> Self {
+        Self {
+            types: Map::default(),
+            offsets: Map::default(),
+            empty: AtomicBool::new(true),
+            no_offsets: AtomicBool::new(true),
+        }
+    }
+
+    pub(crate) fn get(&self, type_id: TypeId) -> Option<ErasedTypeMeta> {
+        if self.empty.load(Ordering::Acquire) {
+            return None;
+        }
+        self.types.get(&type_id).map(|meta| *meta.value())
+    }
+
+    pub(crate) fn offsets(&self, type_id: TypeId) -> Option<&'static [usize]> {
+        if self.no_offsets.load(Ordering::Acquire) {
+            return None;
+        }
+        self.offsets.get(&type_id).map(|offsets| *offsets.value())
+    }
+
+    /// the table of `T` calling the registered finalizer, if any
+    pub(crate) fn finalizer<T: Trace>(meta: &ErasedTypeMeta) -> Option<&'static CellFns> {
+        meta.finalizer.map(|_| registered_fns::<T>())
+    }
+}
+
+/// visit the non null headers stored at `offsets` from `data`
+/// # Safety
+/// `data` should point to an object whose type is registered with `offsets`
+pub(crate) unsafe fn trace_offsets(offsets: &[usize], data: *const u8, tracer: &mut dyn Tracer) {
+    for offset in offsets {
+        let child = (data.add(*offset) as *const *mut GCHeader).read();
+        if !child.is_null() {
+            tracer.visit(child);
+        }
+    }
+}
+
+const fn registered_fns<T: Trace>() -> &'static CellFns {
+    const {
+        &CellFns {
+            finalize: finalize_registered::<T>,
+            ..*cell_fns::<T>()
+        }
+    }
+}
+
+/// looks the finalizer up again, so a type registered again later runs the new one
+unsafe fn finalize_registered<T: Trace>(header: *mut GCHeader) {
+    let state = (*header).frame().state();
+    let Some(finalizer) = state
+        .type_registry
+        .get(TypeId::of::<T>())
+        .and_then(|meta| meta.finalizer)
+    else {
+        return;
+    };
+    let finalizer: fn(&mut T) = core::mem::transmute(finalizer);
+    finalizer(&mut *(GCHeader::data(header) as *mut T));
+}
+
+impl State {
+    /// apply `meta` to every object of `T` allocated from now on, replacing the previous one.
+    /// the objects allocated before are not changed, the ones pinned by `TypeMeta::pinned` stay rooted
+    pub fn register_type<T: Trace>(&self, meta: TypeMeta<T>) {
+        let erased = ErasedTypeMeta {
+            pinned: meta.pinned,
+            generation: meta.generation,
+            finalizer: meta.finalizer.map(|finalizer| finalizer as *const ()),
+        };
+        self.type_registry.types.insert(TypeId::of::<T>(), erased);
+        self.type_registry.empty.store(false, Ordering::Release);
+    }
+
+    /// trace the objects of `T` through the gc header pointers stored at `offsets`,
+    /// as byte offsets from the start of `T`, besides its `Trace::trace`.
+    /// it's for objects with a manual layout, like `#[repr(C)]` structs shared with C or an interpreter,
+    /// whose references are not typed rust fields, the offsets could be taken by `core::mem::offset_of!`.
+    /// null pointers are skipped, and the referenced objects are never moved, like the ones of `Tracer::visit`.
+    /// the elements of a `GCArray<T>` are traced through them too.
+    /// registering a type again replaces its offsets.
+    /// # Safety
+    /// every offset should be within `T` and aligned for a pointer, it's checked by debug assertions,
+    /// and the word there should always be null or the header of a live gc object,
+    /// every store into it should go through the write barrier, like `GCMut`
+    pub unsafe fn register_type_offsets<T: Trace>(&self, offsets: &'static [usize]) {
+        let word = core::mem::size_of::<*mut GCHeader>();
+        for offset in offsets {
+            debug_assert!(
+                offset + word <= core::mem::size_of::<T>(),
+                "offset {} out of {}",
+                offset,
+                core::any::type_name::<T>()
+            );
+            debug_assert!(
+                offset % core::mem::align_of::<*mut GCHeader>() == 0,
+                "offset {} is not aligned for a pointer",
+                offset
+            );
+        }
+        self.type_registry
+            .offsets
+            .insert(TypeId::of::<T>(), offsets);
+        self.type_registry
+            .no_offsets
+            .store(false, Ordering::Release);
+    }
+
+    /// allocate the objects of `T` like any other type again,
+    /// the finalizers of the ones already allocated are not run anymore
+    pub fn unregister_type<T: Trace>(&self) {
+        self.type_registry.types.remove(&TypeId::of::<T>());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::TypeMeta;
+    use crate::gc_box::{GCHeader, Generation};
+    use crate::state::GCConfig;
+    use crate::test_util::{env, env_with, full_gc, get, is_tracked, Leaf, Node};
+    use crate::trace::{Trace, Tracer};
+
+    /// a reference the `Trace` impl doesn't know about
+    #[repr(C)]
+    struct Raw {
+        id: u64,
+        child: *mut GCHeader,
+    }
+
+    impl Trace for Raw {
+        fn trace(&self, _: &mut dyn Tracer) {}
+    }
+
+    const RAW_OFFSETS: &[usize] = &[core::mem::offset_of!(Raw, child)];
+
+    #[test]
+    fn registered_offsets_are_traced() {
+        let (state, frame) = env();
+        unsafe { state.register_type_offsets::<Raw>(RAW_OFFSETS) };
+        let child = frame.alloc(Leaf(1));
+        let child_header = child.header();
+        let _parent = frame.alloc(Raw {
+            id: 0,
+            child: child_header,
+        });
+        drop(child);
+        state.minor_heap_gen_gc();
+        assert!(is_tracked(state, child_header));
+        full_gc(state);
+        assert!(is_tracked(state, child_header));
+    }
+
+    #[test]
+    fn registered_offsets_of_array_elements_are_traced() {
+        let (state, frame) = env();
+        unsafe { state.register_type_offsets::<Raw>(RAW_OFFSETS) };
+        let children = vec![frame.alloc(Leaf(1)), frame.alloc(Leaf(2))];
+        let headers: vec::Vec<_> = children.iter().map(|child| child.header()).collect();
+        let _array = frame.allocate_array(3, |i| Raw {
+            id: i as u64,
+            child: headers.get(i).copied().unwrap_or(core::ptr::null_mut()),
+        });
+        drop(children);
+        full_gc(state);
+        assert!(headers.iter().all(|header| is_tracked(state, *header)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not aligned")]
+    fn misaligned_offset_is_rejected() {
+        let (state, _) = env();
+        unsafe { state.register_type_offsets::<Raw>(&[1]) };
+    }
+
+    #[test]
+    fn registered_generation_is_allocated_into() {
+        let config = GCConfig::builder().enable_imm_gen(true).build().unwrap();
+        let (state, frame) = env_with(config);
+        state.register_type(TypeMeta::<Leaf> {
+            generation: Some(Generation::Imm),
+            ..TypeMeta::default()
+        });
+        let leaf = frame.alloc(Leaf(1));
+        let node = frame.alloc(Node(2, vec![]));
+        assert!(state.imm_gen.contains(&leaf.header()));
+        assert_eq!(get(&leaf).0, 1);
+        assert!(state.minor_heap_gen.contains(&node.header()));
+        // `alloc_in` always uses the given one
+        let minor = frame.alloc_in(Leaf(3), Generation::Minor);
+        assert!(state.minor_heap_gen.contains(&minor.header()));
+        state.unregister_type::<Leaf>();
+        let unregistered = frame.alloc(Leaf(4));
+        assert!(state.minor_heap_gen.contains(&unregistered.header()));
+    }
+
+    #[test]
+    fn registered_pinned_type_stays_rooted() {
+        let (state, frame) = env();
+        state.register_type(TypeMeta::<Leaf> {
+            pinned: true,
+            ..TypeMeta::default()
+        });
+        let leaf = frame.alloc(Leaf(1));
+        let header = leaf.header();
+        assert!(leaf.is_pinned());
+        drop(leaf);
+        full_gc(state);
+        assert!(is_tracked(state, header));
+    }
+}
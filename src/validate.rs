@@ -0,0 +1,242 @@
+//! consistency checks of the collector itself, for catching regressions while developing it.
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use super::gc_box::{GCHeader, Generation};
+use super::state::{GCStage, State};
+use super::sync::HashMap;
+
+/// an invariant broken by the collector, the headers are addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// the object is stored in the sets of more than one generation
+    MultipleGenerations { header: usize },
+    /// the generation of the object is not the one of the set it's stored in
+    WrongGeneration {
+        header: usize,
+        generation: Generation,
+        set: Generation,
+    },
+    /// a rooted or pinned object is not stored in the set of its generation
+    UntrackedRoot { header: usize },
+    /// a marked object is also in a dead set
+    MarkedDead { header: usize },
+    /// an object references a header not stored in any generation, like a freed one
+    DanglingReference { from: usize, to: usize },
+    /// the size counter of a generation disagrees with the objects in it
+    HeapSizeMismatch {
+        generation: Generation,
+        counter: usize,
+        objects: usize,
+    },
+    /// a cumulative counter of `GcStats` decreased since the last validation
+    CounterDecreased {
+        counter: &'static str,
+        last: usize,
+        current: usize,
+    },
+    /// the survived collections of an object decreased since the last validation,
+    /// the liveness of an object only grows, even when it's moved or promoted
+    LivenessDecreased {
+        header: usize,
+        last: usize,
+        current: usize,
+    },
+    /// a major gc is running, so the sets are in the middle of changing
+    MajorGcRunning,
+}
+
+impl State {
+    /// check the invariants of the collector, with the world stopped and minor gc held off.
+    /// it walks and traces every object, so it's only meant for debugging the collector.
+    pub fn validate(&self) -> Result<(), Vec<InvariantViolation>> {
+        while self
+            .start_minor_gc_flag
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            self.wait_for_collector();
+        }
+        self.stop_world(false);
+        let violations = if self.stage() == GCStage::Ready {
+            self.collect_violations()
+        } else {
+            alloc::vec![InvariantViolation::MajorGcRunning]
+        };
+        self.ctw()
+            .expect("[FALTAL ERROR] world is continued while validating");
+        self.start_minor_gc_flag.store(false, Ordering::Release);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    fn collect_violations(&self) -> Vec<InvariantViolation> {
+        let mut violations = Vec::new();
+        let generations = [
+            (
+                &self.minor_heap_gen,
+                Generation::Minor,
+                &self.minor_heap_size,
+            ),
+            (
+                &self.major_heap_gen,
+                Generation::Major,
+                &self.major_heap_size,
+            ),
+            (&self.imm_gen, Generation::Imm, &self.imm_size),
+        ];
+        let tracked = |header: *mut GCHeader| {
+            generations.iter().any(|(set, _, _)| set.contains(&header))
+                || self.pinned_region_objects.contains(&header)
+        };
+        let last_liveness = core::mem::take(&mut *self.validated_liveness.lock().unwrap());
+        let mut liveness = HashMap::new();
+
+        for (i, (set, generation, heap_size)) in generations.iter().enumerate() {
+            let mut size = 0;
+            for header in set.iter() {
+                let header = *header;
+                let gc_header = unsafe { &*header };
+                size += gc_header.size();
+                if let Some(last) = last_liveness.get(&gc_header.id()) {
+                    if gc_header.liveness() < *last {
+                        violations.push(InvariantViolation::LivenessDecreased {
+                            header: header as usize,
+                            last: *last,
+                            current: gc_header.liveness(),
+                        });
+                    }
+                }
+                liveness.insert(gc_header.id(), gc_header.liveness());
+                if gc_header.generation() != *generation {
+                    violations.push(InvariantViolation::WrongGeneration {
+                        header: header as usize,
+                        generation: gc_header.generation(),
+                        set: *generation,
+                    });
+                }
+                // every shared object is reported once, by the first set storing it
+                if generations[i + 1..]
+                    .iter()
+                    .any(|(other, _, _)| other.contains(&header))
+                {
+                    violations.push(InvariantViolation::MultipleGenerations {
+                        header: header as usize,
+                    });
+                }
+                let mut children: Vec<*mut GCHeader> = Vec::new();
+                unsafe { GCHeader::trace(header, &mut children) };
+                for child in children {
+                    if !tracked(child) {
+                        violations.push(InvariantViolation::DanglingReference {
+                            from: header as usize,
+                            to: child as usize,
+                        });
+                    }
+                }
+            }
+            let counter = heap_size.load(Ordering::Acquire);
+            if counter != size {
+                violations.push(InvariantViolation::HeapSizeMismatch {
+                    generation: *generation,
+                    counter,
+                    objects: size,
+                });
+            }
+        }
+
+        // objects freed since are forgotten
+        *self.validated_liveness.lock().unwrap() = liveness;
+
+        let roots = self
+            .minor_heap_roots
+            .iter()
+            .chain(self.major_heap_roots.iter())
+            .chain(self.pinned_objects.iter())
+            .chain(self.conservative_roots.iter())
+            .map(|header| *header)
+            .chain(self.manual_roots.iter().map(|root| *root.key()));
+        for root in roots {
+            let in_generation = generations.iter().any(|(set, generation, _)| {
+                set.contains(&root) && unsafe { (*root).generation() } == *generation
+            });
+            if !in_generation && !self.pinned_region_objects.contains(&root) {
+                violations.push(InvariantViolation::UntrackedRoot {
+                    header: root as usize,
+                });
+            }
+        }
+
+        for (marked, dead) in [
+            (&self.minor_heap_marked, &self.minor_heap_dead),
+            (&self.major_heap_marked, &self.major_heap_dead),
+        ] {
+            for header in dead.iter() {
+                if marked.contains(&*header) {
+                    violations.push(InvariantViolation::MarkedDead {
+                        header: *header as usize,
+                    });
+                }
+            }
+        }
+
+        let stats = self.stats();
+        let current = [
+            ("total_allocated_bytes", stats.total_allocated_bytes),
+            ("total_allocated_objects", stats.total_allocated_objects),
+            ("total_freed_bytes", stats.total_freed_bytes),
+            ("minor_gc_count", stats.minor_gc_count),
+            ("major_gc_count", stats.major_gc_count),
+        ];
+        let mut last = self.validated_counters.lock().unwrap();
+        for ((counter, current), last) in current.iter().zip(last.iter_mut()) {
+            if current < last {
+                violations.push(InvariantViolation::CounterDecreased {
+                    counter,
+                    last: *last,
+                    current: *current,
+                });
+            }
+            *last = *current;
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::InvariantViolation;
+    use crate::test_util::{env, Node};
+
+    #[test]
+    fn liveness_of_every_object_never_decreases() {
+        let (state, frame) = env();
+        let old = frame.alloc(Node(0, vec![]));
+        for i in 0..8 {
+            let _young = frame.alloc(Node(i, vec![old.as_ref()]));
+            state.minor_heap_gen_gc();
+            assert_eq!(state.validate(), Ok(()));
+        }
+        state.collect();
+        assert_eq!(state.validate(), Ok(()));
+        let (id, liveness) = unsafe { ((*old.header()).id(), (*old.header()).liveness()) };
+        state
+            .validated_liveness
+            .lock()
+            .unwrap()
+            .insert(id, liveness + 1);
+        assert_eq!(
+            state.validate(),
+            Err(vec![InvariantViolation::LivenessDecreased {
+                header: old.header() as usize,
+                last: liveness + 1,
+                current: liveness,
+            }])
+        );
+    }
+}
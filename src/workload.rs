@@ -0,0 +1,212 @@
+//! reproducible synthetic workloads, for comparing collector changes against each other.
+//! the same builder always allocates the same object graph, in the same order.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::frame::GCFrame;
+use crate::gc_box::{GCBox, GCRef};
+use crate::stats::GcStats;
+use crate::trace::{Trace, Tracer};
+
+/// an object of the workload, referencing the ones picked by fan-out and cycles
+pub struct WorkloadNode {
+    pub payload: u64,
+    pub children: Vec<GCRef<WorkloadNode>>,
+}
+
+impl Trace for WorkloadNode {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        self.children.trace(tracer);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorkloadBuilder {
+    objects: usize,
+    seed: u64,
+    /// relative weights of the lifetimes
+    short: u32,
+    medium: u32,
+    long: u32,
+    medium_span: usize,
+    fan_out: usize,
+    cycle_probability: f64,
+    collect_every: usize,
+}
+
+impl Default for WorkloadBuilder {
+    fn default() -> Self {
+        Self {
+            objects: 100_000,
+            seed: 0x5eed,
+            short: 80,
+            medium: 15,
+            long: 5,
+            medium_span: 1024,
+            fan_out: 2,
+            cycle_probability: 0.01,
+            collect_every: 0,
+        }
+    }
+}
+
+impl WorkloadBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// objects allocated by one run
+    /// default is 100000
+    pub fn objects(mut self, objects: usize) -> Self {
+        self.objects = objects;
+        self
+    }
+    /// default is 0x5eed
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+    /// relative weights of the objects dropped right away, the ones kept for `medium_span` allocations,
+    /// and the ones kept until the run ends
+    /// default is 80, 15, 5
+    pub fn lifetimes(mut self, short: u32, medium: u32, long: u32) -> Self {
+        self.short = short;
+        self.medium = medium;
+        self.long = long;
+        self
+    }
+    /// default is 1024
+    pub fn medium_span(mut self, allocations: usize) -> Self {
+        self.medium_span = allocations;
+        self
+    }
+    /// every object references at most this many kept objects
+    /// default is 2
+    pub fn fan_out(mut self, fan_out: usize) -> Self {
+        self.fan_out = fan_out;
+        self
+    }
+    /// a kept object is mutated to reference the new one through the write barrier,
+    /// closing a cycle if the new one references it
+    /// default is 0.01
+    pub fn cycle_probability(mut self, probability: f64) -> Self {
+        self.cycle_probability = probability;
+        self
+    }
+    /// run a major gc every this many allocations, besides the ones triggered by the pacer
+    /// default is 0 for never
+    pub fn collect_every(mut self, allocations: usize) -> Self {
+        self.collect_every = allocations;
+        self
+    }
+
+    /// allocate the workload in `frame`, then drop every object and collect them
+    pub fn run(&self, frame: &'static GCFrame) -> WorkloadReport {
+        let state = frame.state();
+        let mut rng = SplitMix64(self.seed);
+        let weights = self.short as u64 + self.medium as u64 + self.long as u64;
+        let mut medium: VecDeque<GCBox<WorkloadNode>> = VecDeque::new();
+        let mut long: Vec<GCBox<WorkloadNode>> = Vec::new();
+        let start = Instant::now();
+        for i in 0..self.objects {
+            let kept = medium.len() + long.len();
+            let children = if kept == 0 {
+                Vec::new()
+            } else {
+                (0..rng.below(self.fan_out as u64 + 1))
+                    .map(|_| pick(&medium, &long, rng.below(kept as u64) as usize).as_ref())
+                    .collect()
+            };
+            let node = frame.alloc(WorkloadNode {
+                payload: rng.next(),
+                children,
+            });
+            if kept != 0 && rng.chance(self.cycle_probability) {
+                let index = rng.below(kept as u64) as usize;
+                let target = if index < medium.len() {
+                    &mut medium[index]
+                } else {
+                    &mut long[index - medium.len()]
+                };
+                // bounded, so long lived objects don't grow forever
+                if target.as_ref().children.len() < self.fan_out.max(1) * 2 {
+                    target.as_mut().children.push(node.as_ref());
+                }
+            }
+            let lifetime = rng.below(weights.max(1));
+            if lifetime < self.short as u64 {
+                drop(node);
+            } else if lifetime < self.short as u64 + self.medium as u64 {
+                medium.push_back(node);
+                if medium.len() > self.medium_span {
+                    medium.pop_front();
+                }
+            } else {
+                long.push(node);
+            }
+            if self.collect_every != 0 && (i + 1) % self.collect_every == 0 {
+                state.collect();
+            }
+        }
+        let allocating = start.elapsed();
+        let peak = state.stats();
+        drop(medium);
+        drop(long);
+        state.minor_heap_gen_gc();
+        state.collect();
+        WorkloadReport {
+            allocating,
+            total: start.elapsed(),
+            peak,
+            end: state.stats(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorkloadReport {
+    /// allocating every object, with the collections triggered meanwhile
+    pub allocating: Duration,
+    /// including the final collection of every object
+    pub total: Duration,
+    /// stats right after the last allocation
+    pub peak: GcStats,
+    /// stats after the final collection, the cumulative counters cover the whole run
+    pub end: GcStats,
+}
+
+fn pick<'a>(
+    medium: &'a VecDeque<GCBox<WorkloadNode>>,
+    long: &'a [GCBox<WorkloadNode>],
+    index: usize,
+) -> &'a GCBox<WorkloadNode> {
+    if index < medium.len() {
+        &medium[index]
+    } else {
+        &long[index - medium.len()]
+    }
+}
+
+/// small and stable across platforms, so a seed always means the same workload
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// `0..bound`, 0 if bound is 0
+    fn below(&mut self, bound: u64) -> u64 {
+        if bound == 0 {
+            return 0;
+        }
+        self.next() % bound
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}